@@ -1,9 +1,13 @@
 use anyhow::{anyhow, Result};
+use std::cell::RefCell;
 
 use js_sys::ArrayBuffer;
 use wasm_bindgen::{closure::WasmClosure, closure::WasmClosureFnOnce, prelude::Closure, JsCast, JsValue};
 use wasm_bindgen_futures::JsFuture;
-use web_sys::{CanvasRenderingContext2d, Document, HtmlCanvasElement, HtmlElement, Response, Window};
+use web_sys::{
+  CanvasRenderingContext2d, Document, Element, Gamepad, GamepadButton, GamepadMappingType, HtmlCanvasElement, HtmlElement, OrientationLockType,
+  Response, Window,
+};
 
 macro_rules! log {
     ($($t:tt)*) => {
@@ -26,10 +30,20 @@ pub fn document() -> Result<Document> {
     .ok_or_else(|| anyhow!("No Document Founc"))
 }
 
+thread_local! {
+  // 描画先の canvas の id。GameLoop::start で DisplayConfig の値に差し替える
+  static CANVAS_ID: RefCell<String> = RefCell::new(String::from("canvas"));
+}
+
+pub fn set_canvas_id(id: &str) {
+  CANVAS_ID.with(|canvas_id| *canvas_id.borrow_mut() = id.to_string());
+}
+
 pub fn canvas() -> Result<HtmlCanvasElement> {
+  let id = CANVAS_ID.with(|canvas_id| canvas_id.borrow().clone());
   document()?
-    .get_element_by_id("canvas") // "canvas"がハードコーディングされているが必要になるまで再設定可能にするのはやめる
-    .ok_or_else(|| anyhow!("No Canvas Element Found with ID 'canvas'"))?
+    .get_element_by_id(&id)
+    .ok_or_else(|| anyhow!("No Canvas Element Found with ID '{}'", id))?
     .dyn_into::<web_sys::HtmlCanvasElement>()
     .map_err(|element| anyhow!("Error convrting {:#?} to HtmlCanvasElement", element))
 }
@@ -47,6 +61,61 @@ pub fn context() -> Result<CanvasRenderingContext2d> {
     })
 }
 
+// CSS ピクセル単位のウィンドウの大きさ
+pub fn window_size() -> Result<(f64, f64)> {
+  let window = window()?;
+  let width = window
+    .inner_width()
+    .map_err(|err| anyhow!("Failed to get window width: {:#?}", err))?
+    .as_f64()
+    .ok_or_else(|| anyhow!("Window width is not a number"))?;
+  let height = window
+    .inner_height()
+    .map_err(|err| anyhow!("Failed to get window height: {:#?}", err))?
+    .as_f64()
+    .ok_or_else(|| anyhow!("Window height is not a number"))?;
+  Ok((width, height))
+}
+
+pub fn device_pixel_ratio() -> Result<f64> {
+  Ok(window()?.device_pixel_ratio())
+}
+
+pub fn set_style(element: &HtmlElement, property: &str, value: &str) -> Result<()> {
+  element
+    .style()
+    .set_property(property, value)
+    .map_err(|err| anyhow!("Failed to set style {}: {:#?}", property, err))
+}
+
+pub fn is_fullscreen() -> Result<bool> {
+  Ok(document()?.fullscreen_element().is_some())
+}
+
+pub fn request_fullscreen(element: &Element) -> Result<()> {
+  element
+    .request_fullscreen()
+    .map_err(|err| anyhow!("Failed to request fullscreen: {:#?}", err))
+}
+
+pub fn exit_fullscreen() -> Result<()> {
+  document()?.exit_fullscreen();
+  Ok(())
+}
+
+pub async fn lock_orientation(orientation: OrientationLockType) -> Result<()> {
+  let promise = window()?
+    .screen()
+    .map_err(|err| anyhow!("Failed to get screen: {:#?}", err))?
+    .orientation()
+    .lock(orientation)
+    .map_err(|err| anyhow!("Failed to lock orientation: {:#?}", err))?;
+  JsFuture::from(promise)
+    .await
+    .map_err(|err| anyhow!("Orientation lock was rejected: {:#?}", err))?;
+  Ok(())
+}
+
 pub fn spawn_local<F>(future: F)
 where
   F: std::future::Future<Output = ()> + 'static,
@@ -124,28 +193,53 @@ pub fn now() -> Result<f64> {
   )
 }
 
-pub fn draw_ui(html: &str) -> Result<()> {
-  find_ui().and_then(|ui| {
-    ui.insert_adjacent_html("afterbegin", html)
-      .map_err(|err| anyhow!("Failed to insert HTML: {:#?}", err))
-  })
+pub fn create_element(tag: &str) -> Result<Element> {
+  document()?
+    .create_element(tag)
+    .map_err(|err| anyhow!("Failed to create <{}>: {:#?}", tag, err))
 }
 
-pub fn hide_ui() -> Result<()> {
-  let ui = find_ui()?;
-  if let Some(child) = ui.first_child() {
-    ui.remove_child(&child)
-      .map(|_removed_child| ())
-      .map_err(|err| anyhow!("Failed to remofve child: {:#?}", err))
-      .and_then(|_unit| {
-        canvas()?
-          .focus()
-          .map_err(|err| anyhow!("Failed to focus canvas: {:#?}", err))
+pub fn append_ui(element: &Element) -> Result<()> {
+  find_ui()?
+    .append_child(element)
+    .map(|_appended| ())
+    .map_err(|err| anyhow!("Failed to append UI element: {:#?}", err))
+}
+
+// UI のボタンを押したあともキー入力を受け取れるよう、フォーカスを canvas に戻す
+pub fn focus_canvas() -> Result<()> {
+  canvas()?
+    .focus()
+    .map_err(|err| anyhow!("Failed to focus canvas: {:#?}", err))
+}
+
+// 標準マッピングのゲームパッドごとに、ボタンが押されているかを返す
+pub fn gamepad_buttons() -> Result<Vec<Vec<bool>>> {
+  let gamepads = window()?
+    .navigator()
+    .get_gamepads()
+    .map_err(|err| anyhow!("Failed to get gamepads: {:#?}", err))?;
+  Ok(
+    gamepads
+      .iter()
+      .filter_map(|gamepad| gamepad.dyn_into::<Gamepad>().ok())
+      .filter(|gamepad| gamepad.connected() && gamepad.mapping() == GamepadMappingType::Standard)
+      .map(|gamepad| {
+        gamepad
+          .buttons()
+          .iter()
+          .map(|button| {
+            button
+              .dyn_into::<GamepadButton>()
+              .map(|button| button.pressed())
+              .unwrap_or(false)
+          })
+          .collect()
       })
-  } else {
-    Ok(())
-  }
+      .collect(),
+  )
 }
+
 fn find_ui() -> Result<web_sys::Element> {
   document()?
     .get_element_by_id("ui")
@@ -164,3 +258,37 @@ pub fn find_html_element_by_id(id: &str) -> Result<HtmlElement> {
       })
   })
 }
+
+fn local_storage() -> Result<web_sys::Storage> {
+  window()?
+    .local_storage()
+    .map_err(|err| anyhow!("Failed to access localStorage: {:#?}", err))?
+    .ok_or_else(|| anyhow!("No localStorage Found"))
+}
+
+pub fn load_item(key: &str) -> Result<Option<String>> {
+  local_storage()?
+    .get_item(key)
+    .map_err(|err| anyhow!("Failed to read '{}' from localStorage: {:#?}", key, err))
+}
+
+pub fn save_item(key: &str, value: &str) -> Result<()> {
+  local_storage()?
+    .set_item(key, value)
+    .map_err(|err| anyhow!("Failed to write '{}' to localStorage: {:#?}", key, err))
+}
+
+pub fn remove_item(key: &str) -> Result<()> {
+  local_storage()?
+    .remove_item(key)
+    .map_err(|err| anyhow!("Failed to remove '{}' from localStorage: {:#?}", key, err))
+}
+
+pub fn prefers_reduced_motion() -> Result<bool> {
+  Ok(
+    window()?
+      .match_media("(prefers-reduced-motion: reduce)")
+      .map_err(|err| anyhow!("Failed to match media query: {:#?}", err))?
+      .is_some_and(|query| query.matches()),
+  )
+}
@@ -5,22 +5,28 @@ use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use futures::channel::mpsc::{unbounded, UnboundedReceiver};
 use futures::channel::oneshot::channel;
+use rand::{thread_rng, Rng};
 use serde::Deserialize;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
 use std::sync::Mutex;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
-use web_sys::HtmlImageElement;
-use web_sys::{CanvasRenderingContext2d, HtmlElement};
+use web_sys::CanvasRenderingContext2d;
+use web_sys::{Element, HtmlImageElement, OrientationLockType};
 // use wasm_bindgen_test::__rt::browser;
 
-#[derive(Clone, Copy, Default)]
-pub struct Point {
-  pub x: i16,
-  pub y: i16,
-}
+mod camera;
+mod ecs;
+mod math;
+mod particles;
+mod ui;
+pub use camera::{Camera, CameraConfig};
+pub use ecs::{ComponentSet, Components, Entity, Sprite, World};
+pub use math::{Color, Vec2};
+pub use particles::{Emitter, ParticleLook, ParticleSystem};
+pub use ui::{Screen, Widget};
 
 #[derive(Deserialize, Clone)]
 pub struct SheetRect {
@@ -30,6 +36,19 @@ pub struct SheetRect {
   pub h: i16,
 }
 
+// スプライトシートの JSON はピクセル単位の整数なので、描画や当たり判定に使うときは Rect に変換する
+impl SheetRect {
+  pub fn position(&self) -> Vec2 {
+    Vec2::new(self.x.into(), self.y.into())
+  }
+}
+
+impl From<&SheetRect> for Rect {
+  fn from(rect: &SheetRect) -> Self {
+    Rect::new(rect.position(), rect.w.into(), rect.h.into())
+  }
+}
+
 #[derive(Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Cell {
@@ -42,15 +61,15 @@ pub struct Sheet {
   pub frames: HashMap<String, Cell>,
 }
 
-#[derive(Default)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub struct Rect {
-  pub position: Point,
-  pub width: i16,
-  pub height: i16,
+  pub position: Vec2,
+  pub width: f32,
+  pub height: f32,
 }
 
 impl Rect {
-  pub const fn new(position: Point, width: i16, height: i16) -> Self {
+  pub const fn new(position: Vec2, width: f32, height: f32) -> Self {
     Rect {
       position,
       width,
@@ -58,31 +77,31 @@ impl Rect {
     }
   }
 
-  pub const fn new_from_x_y(x: i16, y: i16, width: i16, height: i16) -> Self {
-    Rect::new(Point { x, y }, width, height)
+  pub const fn new_from_x_y(x: f32, y: f32, width: f32, height: f32) -> Self {
+    Rect::new(Vec2::new(x, y), width, height)
   }
 
   pub fn intersects(&self, rect: &Rect) -> bool {
     self.x() < rect.right() && self.right() > rect.x() && self.y() < rect.bottom() && self.bottom() > rect.y()
   }
 
-  pub fn right(&self) -> i16 {
+  pub fn right(&self) -> f32 {
     self.x() + self.width
   }
 
-  pub fn bottom(&self) -> i16 {
+  pub fn bottom(&self) -> f32 {
     self.y() + self.height
   }
 
-  pub fn set_x(&mut self, x: i16) {
+  pub fn set_x(&mut self, x: f32) {
     self.position.x = x
   }
 
-  pub fn x(&self) -> i16 {
+  pub fn x(&self) -> f32 {
     self.position.x
   }
 
-  pub fn y(&self) -> i16 {
+  pub fn y(&self) -> f32 {
     self.position.y
   }
 }
@@ -91,19 +110,20 @@ impl Rect {
 mod tests {
   // 他のコードから隔離するため mod キーワードでモジュール化する
   use super::*;
+  use proptest::prelude::*;
 
   #[test]
   fn two_rects_that_intersect_on_the_left() {
     let rect1 = Rect {
-      position: Point { x: 10, y: 10 },
-      height: 100,
-      width: 100,
+      position: Vec2 { x: 10.0, y: 10.0 },
+      height: 100.0,
+      width: 100.0,
     };
 
     let rect2 = Rect {
-      position: Point { x: 0, y: 10 },
-      height: 100,
-      width: 100,
+      position: Vec2 { x: 0.0, y: 10.0 },
+      height: 100.0,
+      width: 100.0,
     };
 
     assert_eq!(rect2.intersects(&rect1), true);
@@ -113,15 +133,15 @@ mod tests {
   #[test]
   fn two_rects_that_intersect_on_the_top() {
     let rect1 = Rect {
-      position: Point { x: 10, y: 10 },
-      height: 100,
-      width: 100,
+      position: Vec2 { x: 10.0, y: 10.0 },
+      height: 100.0,
+      width: 100.0,
     };
 
     let rect2 = Rect {
-      position: Point { x: 10, y: 0 },
-      height: 100,
-      width: 100,
+      position: Vec2 { x: 10.0, y: 0.0 },
+      height: 100.0,
+      width: 100.0,
     };
 
     assert_eq!(rect2.intersects(&rect1), true);
@@ -130,15 +150,15 @@ mod tests {
   #[test]
   fn two_rects_that_intersect_on_the_right() {
     let rect1 = Rect {
-      position: Point { x: 10, y: 10 },
-      height: 100,
-      width: 100,
+      position: Vec2 { x: 10.0, y: 10.0 },
+      height: 100.0,
+      width: 100.0,
     };
 
     let rect2 = Rect {
-      position: Point { x: 10, y: 10 },
-      height: 100,
-      width: 100,
+      position: Vec2 { x: 50.0, y: 10.0 },
+      height: 100.0,
+      width: 100.0,
     };
 
     assert_eq!(rect2.intersects(&rect1), true);
@@ -147,19 +167,220 @@ mod tests {
   #[test]
   fn two_rects_that_do_not_intersect() {
     let rect1 = Rect {
-      position: Point { x: 10, y: 10 },
-      height: 100,
-      width: 100,
+      position: Vec2 { x: 10.0, y: 10.0 },
+      height: 100.0,
+      width: 100.0,
     };
 
     let rect2 = Rect {
-      position: Point { x: 200, y: 200 },
-      height: 100,
-      width: 100,
+      position: Vec2 { x: 200.0, y: 200.0 },
+      height: 100.0,
+      width: 100.0,
     };
 
     assert_eq!(rect2.intersects(&rect1), false);
   }
+  // 辺が接しているだけなら重なっていない
+  #[test]
+  fn two_rects_that_touch_on_the_right_do_not_intersect() {
+    let rect1 = Rect::new_from_x_y(10.0, 10.0, 100.0, 100.0);
+    let rect2 = Rect::new_from_x_y(110.0, 10.0, 100.0, 100.0);
+
+    assert!(!rect2.intersects(&rect1));
+    assert!(!rect1.intersects(&rect2));
+  }
+
+  // i16 の頃は i16::MAX 付近で溢れていた
+  #[test]
+  fn right_goes_past_the_old_i16_limit() {
+    let rect = Rect::new_from_x_y(i16::MAX as f32, 0.0, 100.0, 100.0);
+
+    assert_eq!(rect.right(), i16::MAX as f32 + 100.0);
+  }
+
+  #[test]
+  fn rects_can_overlap_by_less_than_a_pixel() {
+    let rect1 = Rect::new_from_x_y(0.0, 0.0, 10.0, 10.0);
+    let rect2 = Rect::new_from_x_y(9.5, 0.0, 10.0, 10.0);
+
+    assert!(rect1.intersects(&rect2));
+  }
+
+  #[test]
+  fn colors_blend_between_two_palettes() {
+    let night = Color::new(70, 80, 150);
+
+    assert_eq!(Color::WHITE.lerp(night, 0.0), Color::WHITE);
+    assert_eq!(Color::WHITE.lerp(night, 1.0), night);
+    assert_eq!(Color::WHITE.lerp(night, 0.5), Color::new(163, 168, 203));
+    assert_eq!(Color::WHITE.lerp(night, 2.0), night);
+    assert_eq!(night.css(), "rgb(70, 80, 150)");
+  }
+
+  #[test]
+  fn sheet_rect_converts_to_a_rect() {
+    let sheet_rect = SheetRect {
+      x: 1,
+      y: 2,
+      w: 3,
+      h: 4,
+    };
+
+    assert_eq!(
+      Rect::from(&sheet_rect),
+      Rect::new_from_x_y(1.0, 2.0, 3.0, 4.0)
+    );
+  }
+
+  const DISPLAY: DisplayConfig = DisplayConfig {
+    width: 600.0,
+    height: 600.0,
+    canvas_id: "canvas",
+    container_id: "game",
+    fullscreen_buttons: &[],
+    fullscreen_key: None,
+  };
+
+  #[test]
+  fn wide_window_is_pillarboxed() {
+    let viewport = Viewport::letterbox(&DISPLAY, 1600.0, 900.0, 1.0);
+
+    assert_eq!(viewport.scale, 1.5);
+    assert_eq!(viewport.offset, Vec2::new(350.0, 0.0));
+    assert_eq!(
+      (viewport.backing_width, viewport.backing_height),
+      (900, 900)
+    );
+  }
+
+  #[test]
+  fn tall_window_is_letterboxed() {
+    let viewport = Viewport::letterbox(&DISPLAY, 300.0, 500.0, 1.0);
+
+    assert_eq!(viewport.scale, 0.5);
+    assert_eq!(viewport.offset, Vec2::new(0.0, 100.0));
+    assert_eq!(
+      (viewport.backing_width, viewport.backing_height),
+      (300, 300)
+    );
+  }
+
+  // HiDPI ではバッファだけを大きくし、CSS 上の大きさは変えない
+  #[test]
+  fn backing_store_follows_device_pixel_ratio() {
+    let viewport = Viewport::letterbox(&DISPLAY, 600.0, 600.0, 2.0);
+
+    assert_eq!(viewport.scale, 1.0);
+    assert_eq!(
+      (viewport.backing_width, viewport.backing_height),
+      (1200, 1200)
+    );
+    assert_eq!(viewport.backing_scale(&DISPLAY), (2.0, 2.0));
+  }
+
+  #[test]
+  fn fps_is_averaged_over_about_a_second() {
+    let mut fps = FpsCounter::new(0.0);
+    (1..=59).for_each(|frame| fps.tick(f64::from(frame) * 1000.0 / 60.0));
+    assert_eq!(fps.fps, 0);
+
+    fps.tick(1000.0);
+
+    assert_eq!(fps.fps, 60);
+    assert_eq!(fps.frames, 0);
+  }
+
+  // ボイスが次の音に使い回されたら、前の再生は古いものとして扱う
+  #[test]
+  fn a_reused_voice_leaves_the_earlier_playback_stale() {
+    let playbacks = PlaybackCounter::default();
+    let first = playbacks.next();
+    assert!(first.is_current());
+
+    let second = playbacks.next();
+
+    assert!(!first.is_current());
+    assert!(second.is_current());
+  }
+
+  #[test]
+  fn gamepad_buttons_are_read_as_keys_from_any_pad() {
+    let first = vec![true, false];
+    let mut second = vec![false; 16];
+    second[12] = true;
+
+    let codes = gamepad_codes(&[first, second]);
+
+    assert_eq!(codes, HashSet::from([GAMEPAD_A, GAMEPAD_UP]));
+  }
+
+  #[test]
+  fn holding_a_gamepad_button_is_just_pressed_only_once() {
+    let mut keystate = KeyState::new();
+
+    keystate.set_gamepad_pressed(HashSet::from([GAMEPAD_A]));
+    assert!(keystate.is_just_pressed(GAMEPAD_A));
+    keystate.clear_just_pressed();
+    keystate.set_gamepad_pressed(HashSet::from([GAMEPAD_A]));
+
+    assert!(keystate.is_pressed(GAMEPAD_A));
+    assert!(!keystate.is_just_pressed(GAMEPAD_A));
+    assert_eq!(keystate.just_pressed_key(), None);
+  }
+
+  // 整数の座標だけを使うと、f32 でも丸め誤差なしに辺を一致させられる
+  fn any_rect() -> impl Strategy<Value = Rect> {
+    (any::<i16>(), any::<i16>(), 0..=i16::MAX, 0..=i16::MAX)
+      .prop_map(|(x, y, width, height)| Rect::new_from_x_y(x.into(), y.into(), width.into(), height.into()))
+  }
+
+  fn rect_and_rect_inside_it() -> impl Strategy<Value = (Rect, Rect)> {
+    (any::<i16>(), any::<i16>(), 1..=i16::MAX, 1..=i16::MAX)
+      .prop_flat_map(|(x, y, width, height)| (Just((x, y, width, height)), 0..width, 0..height))
+      .prop_flat_map(|(outer, dx, dy)| {
+        (
+          Just(outer),
+          Just(dx),
+          Just(dy),
+          1..=outer.2 - dx,
+          1..=outer.3 - dy,
+        )
+      })
+      .prop_map(
+        |((x, y, width, height), dx, dy, inner_width, inner_height)| {
+          let outer = Rect::new_from_x_y(x.into(), y.into(), width.into(), height.into());
+          let inner = Rect::new_from_x_y(
+            f32::from(x) + f32::from(dx),
+            f32::from(y) + f32::from(dy),
+            inner_width.into(),
+            inner_height.into(),
+          );
+          (outer, inner)
+        },
+      )
+  }
+
+  proptest! {
+    #[test]
+    fn intersects_is_symmetric(a in any_rect(), b in any_rect()) {
+      prop_assert_eq!(a.intersects(&b), b.intersects(&a));
+    }
+
+    // 面積のある四角形は自分の内側にある四角形と必ず重なる
+    #[test]
+    fn rect_intersects_anything_inside_it((outer, inner) in rect_and_rect_inside_it()) {
+      prop_assert!(outer.intersects(&inner));
+      prop_assert!(inner.intersects(&outer));
+    }
+
+    #[test]
+    fn rects_that_share_only_an_edge_do_not_intersect(a in any_rect(), y in any::<i16>(), width in 0..=i16::MAX, height in 0..=i16::MAX) {
+      let b = Rect::new_from_x_y(a.right(), y.into(), width.into(), height.into());
+
+      prop_assert!(!a.intersects(&b));
+      prop_assert!(!b.intersects(&a));
+    }
+  }
 }
 
 pub async fn load_image(source: &str) -> Result<HtmlImageElement> {
@@ -195,9 +416,166 @@ pub async fn load_image(source: &str) -> Result<HtmlImageElement> {
 
 #[async_trait(?Send)]
 pub trait Game {
+  fn display(&self) -> DisplayConfig;
   async fn initialize(&self) -> Result<Box<dyn Game>>;
   fn update(&mut self, keystate: &KeyState);
   fn draw(&self, renderer: &Renderer);
+  fn show_fps(&self) -> bool {
+    false
+  }
+}
+
+// ゲームが描画する論理解像度と、描画先の canvas の id
+// container_id は canvas と UI をまとめた要素で、全画面表示にはこれを使う
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DisplayConfig {
+  pub width: f32,
+  pub height: f32,
+  pub canvas_id: &'static str,
+  pub container_id: &'static str,
+  // 全画面を切り替えるボタンの id
+  pub fullscreen_buttons: &'static [&'static str],
+  pub fullscreen_key: Option<&'static str>,
+}
+
+// 論理解像度の画面を縦横比を保ったままウィンドウに収めたときの配置
+// scale と offset は CSS ピクセル、backing_* は devicePixelRatio を掛けた実際の描画バッファの大きさ
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Viewport {
+  pub scale: f32,
+  pub offset: Vec2,
+  pub backing_width: u32,
+  pub backing_height: u32,
+}
+
+impl Viewport {
+  pub fn letterbox(config: &DisplayConfig, window_width: f32, window_height: f32, device_pixel_ratio: f32) -> Self {
+    let scale = (window_width / config.width).min(window_height / config.height);
+    let css_width = config.width * scale;
+    let css_height = config.height * scale;
+    Viewport {
+      scale,
+      offset: Vec2::new(
+        (window_width - css_width) / 2.0,
+        (window_height - css_height) / 2.0,
+      ),
+      backing_width: ((css_width * device_pixel_ratio).round() as u32).max(1),
+      backing_height: ((css_height * device_pixel_ratio).round() as u32).max(1),
+    }
+  }
+
+  // 論理座標 1 に対する描画バッファのピクセル数
+  fn backing_scale(&self, config: &DisplayConfig) -> (f32, f32) {
+    (
+      self.backing_width as f32 / config.width,
+      self.backing_height as f32 / config.height,
+    )
+  }
+}
+
+fn current_viewport(config: &DisplayConfig) -> Result<Viewport> {
+  let (width, height) = browser::window_size()?;
+  Ok(Viewport::letterbox(
+    config,
+    width as f32,
+    height as f32,
+    browser::device_pixel_ratio()? as f32,
+  ))
+}
+
+// canvas の大きさを変えると描画の変換が初期化されるので、毎回設定し直す
+fn apply_viewport(config: &DisplayConfig, viewport: &Viewport, context: &CanvasRenderingContext2d) -> Result<()> {
+  let canvas = browser::canvas()?;
+  canvas.set_width(viewport.backing_width);
+  canvas.set_height(viewport.backing_height);
+  let css_width = format!("{}px", config.width * viewport.scale);
+  let css_height = format!("{}px", config.height * viewport.scale);
+  let left = format!("{}px", viewport.offset.x);
+  let top = format!("{}px", viewport.offset.y);
+  browser::set_style(&canvas, "width", &css_width)?;
+  browser::set_style(&canvas, "height", &css_height)?;
+  browser::set_style(&canvas, "left", &left)?;
+  browser::set_style(&canvas, "top", &top)?;
+
+  let (scale_x, scale_y) = viewport.backing_scale(config);
+  context
+    .set_transform(scale_x.into(), 0.0, 0.0, scale_y.into(), 0.0, 0.0)
+    .map_err(|err| anyhow!("Failed to set canvas transform: {:#?}", err))?;
+
+  // UI も論理解像度で組んであるので、canvas と同じ位置と倍率に合わせる
+  let ui = browser::find_html_element_by_id("ui")?;
+  browser::set_style(&ui, "left", &left)?;
+  browser::set_style(&ui, "top", &top)?;
+  browser::set_style(&ui, "transform", &format!("scale({})", viewport.scale))
+}
+
+// 全画面の出入りでウィンドウの大きさが変わるので、拡大率は resize の処理で合わせ直される
+fn toggle_fullscreen(config: &DisplayConfig) -> Result<()> {
+  if browser::is_fullscreen()? {
+    browser::exit_fullscreen()?;
+  } else {
+    let container = browser::find_html_element_by_id(config.container_id)?;
+    browser::request_fullscreen(&container)?;
+    // 横長の画面の方が見やすいので固定を試みる。デスクトップなど対応していない環境では失敗するのでログだけ残す
+    browser::spawn_local(async {
+      if let Err(err) = browser::lock_orientation(OrientationLockType::Landscape).await {
+        log!("Could not lock orientation {:#?}", err);
+      }
+    });
+  }
+  // ボタンを押すとフォーカスが移ってキー入力が届かなくなるので canvas に戻す
+  browser::focus_canvas()
+}
+
+// Safari はユーザー操作のイベントの中でしか全画面にさせてくれないので、update を待たずにその場で切り替える。
+// メニューのボタンは画面ごとに作り直されるので、document でまとめて受け取る
+fn prepare_fullscreen(config: DisplayConfig) -> Result<()> {
+  let is_fullscreen_button = move |id: &str| config.fullscreen_buttons.contains(&id);
+  let toggle = move || {
+    if let Err(err) = toggle_fullscreen(&config) {
+      error!("Error toggling fullscreen {:#?}", err);
+    }
+  };
+  let onclick = browser::closure_wrap(Box::new(move |event: web_sys::Event| {
+    let button = event
+      .target()
+      .and_then(|target| target.dyn_into::<Element>().ok())
+      .and_then(|target| target.closest("button").ok().flatten());
+    if button.is_some_and(|button| is_fullscreen_button(&button.id())) {
+      toggle();
+    }
+  }) as Box<dyn FnMut(web_sys::Event)>);
+  let onkeydown = browser::closure_wrap(Box::new(move |event: web_sys::KeyboardEvent| {
+    let code = event.code();
+    // メニューでフォーカスしているボタンをキーで決定したときも、ここで切り替える
+    let activated = ui::is_activate_key(&code) && ui::focused_button().is_some_and(|id| is_fullscreen_button(&id));
+    if config.fullscreen_key == Some(code.as_str()) || activated {
+      toggle();
+    }
+  }) as Box<dyn FnMut(web_sys::KeyboardEvent)>);
+  let document = browser::document()?;
+  document
+    .add_event_listener_with_callback("click", onclick.as_ref().unchecked_ref())
+    .map_err(|err| anyhow!("Failed to listen for fullscreen clicks: {:#?}", err))?;
+  document
+    .add_event_listener_with_callback("keydown", onkeydown.as_ref().unchecked_ref())
+    .map_err(|err| anyhow!("Failed to listen for the fullscreen key: {:#?}", err))?;
+  onclick.forget();
+  onkeydown.forget();
+  Ok(())
+}
+
+fn prepare_display(config: DisplayConfig, context: CanvasRenderingContext2d) -> Result<()> {
+  prepare_fullscreen(config)?;
+  apply_viewport(&config, &current_viewport(&config)?, &context)?;
+  let onresize = browser::closure_wrap(Box::new(move || {
+    if let Err(err) = current_viewport(&config).and_then(|viewport| apply_viewport(&config, &viewport, &context)) {
+      error!("Error resizing canvas {:#?}", err);
+    }
+  }) as Box<dyn FnMut()>);
+  browser::window()?.set_onresize(Some(onresize.as_ref().unchecked_ref()));
+  onresize.forget();
+  Ok(())
 }
 
 const FRAME_SIZE: f32 = 1.0 / 60.0 * 1000.0;
@@ -209,6 +587,8 @@ type SharedLoopClosure = Rc<RefCell<Option<LoopClosure>>>;
 
 impl GameLoop {
   pub async fn start(game: impl Game + 'static) -> Result<()> {
+    let display = game.display();
+    browser::set_canvas_id(display.canvas_id);
     let mut keyevent_receiver = prepare_input()?;
     let mut game = game.initialize().await?;
     let mut game_loop = GameLoop {
@@ -219,22 +599,30 @@ impl GameLoop {
     let renderer = Renderer {
       context: browser::context()?,
     };
+    prepare_display(display, renderer.context.clone())?;
 
     let f: SharedLoopClosure = Rc::new(RefCell::new(None));
     let g = f.clone();
 
     let mut keystate = KeyState::new();
+    let mut fps = FpsCounter::new(game_loop.last_frame);
 
     *g.borrow_mut() = Some(browser::create_raf_closure(move |perf: f64| {
       process_input(&mut keystate, &mut keyevent_receiver);
+      process_gamepads(&mut keystate);
       game_loop.accumulated_delta += (perf - game_loop.last_frame) as f32;
       // game.drawに時間がかかると、updateが呼ばれる回数が減るため、その分を補填。描画を犠牲にして内部処理は確実に行うようにする。(drawを行わないupdateを行う)
       while game_loop.accumulated_delta > FRAME_SIZE {
         game.update(&keystate);
+        keystate.clear_just_pressed();
         game_loop.accumulated_delta -= FRAME_SIZE;
       }
       game_loop.last_frame = perf;
       game.draw(&renderer);
+      fps.tick(perf);
+      if game.show_fps() {
+        renderer.draw_text(&format!("FPS: {}", fps.fps), Vec2::new(10.0, 24.0));
+      }
 
       let _ = browser::request_animation_frame(f.borrow().as_ref().unwrap());
     }));
@@ -247,10 +635,53 @@ impl GameLoop {
   }
 }
 
+// 直近 1 秒ほどのあいだに描画した回数から FPS を求める
+struct FpsCounter {
+  frames: u32,
+  window_start: f64,
+  fps: u32,
+}
+impl FpsCounter {
+  fn new(now: f64) -> Self {
+    FpsCounter {
+      frames: 0,
+      window_start: now,
+      fps: 0,
+    }
+  }
+  fn tick(&mut self, now: f64) {
+    self.frames += 1;
+    let elapsed = now - self.window_start;
+    if elapsed >= 1000.0 {
+      self.fps = (f64::from(self.frames) * 1000.0 / elapsed).round() as u32;
+      self.frames = 0;
+      self.window_start = now;
+    }
+  }
+}
+
 pub struct Renderer {
   context: CanvasRenderingContext2d,
 }
 
+// 描くものを下の絵にどう重ねるか。Canvas の globalCompositeOperation に対応する
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Blend {
+  // 色を掛け合わせて暗くする。白は何も変えない
+  Multiply,
+  // 色を足して明るくする。同じ絵を重ねると、絵のある所だけが光る
+  Lighter,
+}
+
+impl Blend {
+  fn operation(self) -> &'static str {
+    match self {
+      Blend::Multiply => "multiply",
+      Blend::Lighter => "lighter",
+    }
+  }
+}
+
 impl Renderer {
   pub fn clear(&self, rect: &Rect) {
     self.context.clear_rect(
@@ -276,7 +707,17 @@ impl Renderer {
       )
       .expect("Drawing is thrown exceptions! Unrecoverable error.");
   }
-  pub fn draw_entire_image(&self, image: &HtmlImageElement, position: Point) {
+  pub fn draw_text(&self, text: &str, position: Vec2) {
+    self.context.set_font("16px 'Ken Future'");
+    self.context.set_fill_style(&JsValue::from_str("white"));
+    if let Err(err) = self
+      .context
+      .fill_text(text, position.x.into(), position.y.into())
+    {
+      error!("Error drawing text {:#?}", err);
+    }
+  }
+  pub fn draw_entire_image(&self, image: &HtmlImageElement, position: Vec2) {
     self
       .context
       .draw_image_with_html_image_element_and_dw_and_dh(
@@ -288,74 +729,212 @@ impl Renderer {
       )
       .expect("Drawing is thrown exceptions! Unrecoverable error.");
   }
+  pub fn fill_rect(&self, rect: &Rect, color: &str) {
+    self.context.set_fill_style(&JsValue::from_str(color));
+    self.context.fill_rect(
+      rect.position.x.into(),
+      rect.position.y.into(),
+      rect.width.into(),
+      rect.height.into(),
+    );
+  }
+  // draw の中で描いたものだけを offset だけずらす
+  pub fn with_offset(&self, offset: Vec2, draw: impl FnOnce(&Renderer)) {
+    self.context.save();
+    if let Err(err) = self.context.translate(offset.x.into(), offset.y.into()) {
+      error!("Error moving the camera {:#?}", err);
+    }
+    draw(self);
+    self.context.restore();
+  }
+  // draw の中で描いたものだけを blend で重ねる
+  // 元の重ね方は save / restore で戻すので、入れ子にしても外側の重ね方が残る
+  pub fn with_blend(&self, blend: Blend, draw: impl FnOnce(&Renderer)) {
+    self.context.save();
+    if let Err(err) = self
+      .context
+      .set_global_composite_operation(blend.operation())
+    {
+      error!("Error setting the blend {:#?}", err);
+    }
+    draw(self);
+    self.context.restore();
+  }
+  // draw の中で描いたものだけを半透明にする
+  // 入れ子にしたら外側の透明度に掛け合わせ、抜けたら save / restore で外側の透明度に戻す
+  pub fn with_alpha(&self, alpha: f32, draw: impl FnOnce(&Renderer)) {
+    self.context.save();
+    let outer = self.context.global_alpha();
+    self
+      .context
+      .set_global_alpha(outer * f64::from(alpha.clamp(0.0, 1.0)));
+    draw(self);
+    self.context.restore();
+  }
 }
-enum KeyPress {
+enum InputEvent {
   KeyUp(web_sys::KeyboardEvent),
   KeyDown(web_sys::KeyboardEvent),
+  PointerDown(web_sys::PointerEvent),
 }
 
 pub struct KeyState {
   pressed_keys: HashMap<String, web_sys::KeyboardEvent>,
+  just_pressed_keys: HashSet<String>,
+  gamepad_keys: HashSet<&'static str>,
+  user_gesture: bool,
 }
 impl KeyState {
   pub fn new() -> Self {
     KeyState {
       pressed_keys: HashMap::new(),
+      just_pressed_keys: HashSet::new(),
+      gamepad_keys: HashSet::new(),
+      user_gesture: false,
     }
   }
   pub fn is_pressed(&self, code: &str) -> bool {
-    self.pressed_keys.contains_key(code)
+    self.pressed_keys.contains_key(code) || self.gamepad_keys.contains(code)
+  }
+  // 押された直後の update でだけ true になる。トグル操作など押しっぱなしで繰り返したくない入力に使う
+  pub fn is_just_pressed(&self, code: &str) -> bool {
+    self.just_pressed_keys.contains(code)
+  }
+  // 押された直後のキーボードのキーを 1 つ返す。キー割り当ての変更で使う
+  pub fn just_pressed_key(&self) -> Option<&str> {
+    self
+      .just_pressed_keys
+      .iter()
+      .map(String::as_str)
+      .find(|code| !self.gamepad_keys.contains(code))
   }
   pub fn set_pressed(&mut self, code: &str, event: web_sys::KeyboardEvent) {
+    if !self.is_pressed(code) {
+      self.just_pressed_keys.insert(code.into());
+    }
     self.pressed_keys.insert(code.into(), event);
   }
   pub fn set_released(&mut self, code: &str) {
     self.pressed_keys.remove(code);
   }
+  // ゲームパッドはイベントではなく毎フレームの状態で届くので、前回になかったものを押された直後として扱う
+  fn set_gamepad_pressed(&mut self, codes: HashSet<&'static str>) {
+    for code in &codes {
+      if !self.is_pressed(code) {
+        self.just_pressed_keys.insert((*code).into());
+      }
+    }
+    self.gamepad_keys = codes;
+  }
+  // キー入力やクリックがあった直後の update でだけ true になる。
+  // ブラウザの自動再生制限により、音声の再開はユーザー操作をきっかけにする必要がある
+  pub fn has_user_gesture(&self) -> bool {
+    self.user_gesture
+  }
+  fn set_user_gesture(&mut self) {
+    self.user_gesture = true;
+  }
+  fn clear_just_pressed(&mut self) {
+    self.just_pressed_keys.clear();
+    self.user_gesture = false;
+  }
 }
-fn process_input(state: &mut KeyState, keyevent_receiver: &mut UnboundedReceiver<KeyPress>) {
+fn process_input(state: &mut KeyState, keyevent_receiver: &mut UnboundedReceiver<InputEvent>) {
   loop {
     match keyevent_receiver.try_next() {
       Ok(None) => break,
       Err(_) => break,
       Ok(Some(evt)) => match evt {
-        KeyPress::KeyUp(evt) => state.set_released(&evt.code()),
-        KeyPress::KeyDown(evt) => state.set_pressed(&evt.code(), evt),
+        InputEvent::KeyUp(evt) => state.set_released(&evt.code()),
+        InputEvent::KeyDown(evt) => {
+          state.set_user_gesture();
+          state.set_pressed(&evt.code(), evt)
+        }
+        InputEvent::PointerDown(_evt) => state.set_user_gesture(),
       },
     }
   }
 }
 
+// 標準マッピングのボタン番号に、キーボードのコードと重ならない名前を付ける。
+// キーボードの割り当てを変えてもゲームパッドの操作は変わらないようにするため
+pub const GAMEPAD_A: &str = "GamepadA";
+pub const GAMEPAD_B: &str = "GamepadB";
+pub const GAMEPAD_START: &str = "GamepadStart";
+pub const GAMEPAD_UP: &str = "GamepadUp";
+pub const GAMEPAD_DOWN: &str = "GamepadDown";
+pub const GAMEPAD_LEFT: &str = "GamepadLeft";
+pub const GAMEPAD_RIGHT: &str = "GamepadRight";
+const GAMEPAD_KEYS: [(usize, &str); 7] = [
+  (0, GAMEPAD_A),
+  (1, GAMEPAD_B),
+  (9, GAMEPAD_START),
+  (12, GAMEPAD_UP),
+  (13, GAMEPAD_DOWN),
+  (14, GAMEPAD_LEFT),
+  (15, GAMEPAD_RIGHT),
+];
+fn gamepad_codes(gamepads: &[Vec<bool>]) -> HashSet<&'static str> {
+  GAMEPAD_KEYS
+    .iter()
+    .filter(|(button, _code)| {
+      gamepads
+        .iter()
+        .any(|pressed| pressed.get(*button).copied().unwrap_or(false))
+    })
+    .map(|(_button, code)| *code)
+    .collect()
+}
+fn process_gamepads(state: &mut KeyState) {
+  match browser::gamepad_buttons() {
+    Ok(gamepads) => state.set_gamepad_pressed(gamepad_codes(&gamepads)),
+    Err(err) => {
+      error!("Error reading gamepads {:#?}", err);
+    }
+  }
+}
+
 /**
  * Prepare input events
  * ※canvas要素にはtabIndex属性がついておりキーボードイベントを取得できる前提とする。
  */
-fn prepare_input() -> Result<UnboundedReceiver<KeyPress>> {
+fn prepare_input() -> Result<UnboundedReceiver<InputEvent>> {
   let (keydown_sender, keyevent_receiver) = unbounded();
   let keydown_sender = Rc::new(RefCell::new(keydown_sender));
   let keyup_sender = Rc::clone(&keydown_sender);
+  let pointerdown_sender = Rc::clone(&keydown_sender);
 
   let onkeydown = browser::closure_wrap(Box::new(move |keycode: web_sys::KeyboardEvent| {
     log!("{}", &format!("Key Down: {}", keycode.key()));
     let _ = keydown_sender
       .borrow_mut()
-      .start_send(KeyPress::KeyDown(keycode));
+      .start_send(InputEvent::KeyDown(keycode));
   }) as Box<dyn FnMut(web_sys::KeyboardEvent)>);
 
   let onkeyup = browser::closure_wrap(Box::new(move |keycode: web_sys::KeyboardEvent| {
     let _ = keyup_sender
       .borrow_mut()
-      .start_send(KeyPress::KeyUp(keycode));
+      .start_send(InputEvent::KeyUp(keycode));
   }) as Box<dyn FnMut(web_sys::KeyboardEvent)>);
 
+  let onpointerdown = browser::closure_wrap(Box::new(move |event: web_sys::PointerEvent| {
+    let _ = pointerdown_sender
+      .borrow_mut()
+      .start_send(InputEvent::PointerDown(event));
+  }) as Box<dyn FnMut(web_sys::PointerEvent)>);
+
   browser::canvas()
     .unwrap()
     .set_onkeydown(Some(onkeydown.as_ref().unchecked_ref()));
   browser::canvas()
     .unwrap()
     .set_onkeyup(Some(onkeyup.as_ref().unchecked_ref()));
+  browser::canvas()
+    .unwrap()
+    .set_onpointerdown(Some(onpointerdown.as_ref().unchecked_ref()));
   onkeydown.forget();
   onkeyup.forget();
+  onpointerdown.forget();
   Ok(keyevent_receiver)
 }
 
@@ -365,13 +944,8 @@ pub struct Image {
 }
 
 impl Image {
-  pub fn new(element: HtmlImageElement, position: Point) -> Self {
-    let bounding_box = Rect::new_from_x_y(
-      position.x,
-      position.y,
-      element.width() as i16,
-      element.height() as i16,
-    );
+  pub fn new(element: HtmlImageElement, position: Vec2) -> Self {
+    let bounding_box = Rect::new(position, element.width() as f32, element.height() as f32);
     Self {
       element,
       bounding_box,
@@ -380,19 +954,16 @@ impl Image {
   pub fn draw(&self, renderer: &Renderer) {
     renderer.draw_entire_image(&self.element, self.bounding_box.position);
   }
-  pub fn bounding_box(&self) -> &Rect {
-    &self.bounding_box
-  }
-  pub fn move_horizontally(&mut self, distance: i16) {
+  pub fn move_horizontally(&mut self, distance: f32) {
     self.set_x(self.bounding_box.position.x + distance);
   }
 
-  pub fn set_x(&mut self, x: i16) {
+  pub fn set_x(&mut self, x: f32) {
     self.bounding_box.set_x(x);
     self.bounding_box.position.x = x;
   }
-  pub fn right(&self) -> i16 {
-    (self.bounding_box.x() + self.bounding_box.width) as i16
+  pub fn right(&self) -> f32 {
+    self.bounding_box.right()
   }
 }
 
@@ -414,40 +985,532 @@ impl SpriteSheet {
   }
 }
 
+// 絵の一部を切り出して描くもの。ブラウザ無しでもテストできるよう HtmlImageElement を直接持たせない
+pub trait ImageSource {
+  fn draw(&self, renderer: &Renderer, frame: &Rect, destination: &Rect);
+}
+
+impl ImageSource for HtmlImageElement {
+  fn draw(&self, renderer: &Renderer, frame: &Rect, destination: &Rect) {
+    renderer.draw_image(self, frame, destination);
+  }
+}
+
+const DEFAULT_MAX_VOICES: usize = 4;
+// 音声の再生を始めてよいユーザー操作として、ブラウザが認めるイベント
+const GESTURE_EVENTS: [&str; 3] = ["keydown", "click", "touchend"];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Bus {
+  Master,
+  Music,
+  Sfx,
+}
+
+// ゲームロジックから見た音声の出力先。ブラウザ無しでもテストできるよう Web Audio を直接触らせない
+pub trait AudioBackend {
+  // pan は -1.0(左) から 1.0(右)
+  fn play_sound(&self, sound: &Sound, pan: f32) -> Result<()>;
+}
+
+// music / sfx の GainNode を master に繋ぎ、各音源はいずれかのバスへ接続する
+struct Mixer {
+  master: web_sys::GainNode,
+  music: web_sys::GainNode,
+  sfx: web_sys::GainNode,
+  muted: std::cell::Cell<bool>,
+  // ミュート中も覚えておき、解除したときにこの音量へ戻す
+  master_volume: std::cell::Cell<f32>,
+}
+
+impl Mixer {
+  fn new(context: &web_sys::AudioContext) -> Result<Self> {
+    let master = sound::create_gain(context)?;
+    let music = sound::create_gain(context)?;
+    let sfx = sound::create_gain(context)?;
+    sound::connect_with_audio_node(&music, &master)?;
+    sound::connect_with_audio_node(&sfx, &master)?;
+    sound::connect_with_audio_node(&master, &context.destination())?;
+    Ok(Mixer {
+      master,
+      music,
+      sfx,
+      muted: std::cell::Cell::new(false),
+      master_volume: std::cell::Cell::new(1.0),
+    })
+  }
+
+  fn node(&self, bus: Bus) -> &web_sys::GainNode {
+    match bus {
+      Bus::Master => &self.master,
+      Bus::Music => &self.music,
+      Bus::Sfx => &self.sfx,
+    }
+  }
+}
+
+// デコード済みの音声データと、その音を鳴らすためのボイス
+struct LoadedSound {
+  buffer: web_sys::AudioBuffer,
+  voices: Vec<Voice>,
+}
+
 #[derive(Clone)]
 pub struct Audio {
   context: web_sys::AudioContext,
+  mixer: Rc<Mixer>,
+  sounds: Rc<RefCell<Vec<LoadedSound>>>,
 }
 impl Audio {
   pub fn new() -> Result<Self> {
     let context = sound::create_audo_context()?;
-    Ok(Audio { context })
+    let mixer = Rc::new(Mixer::new(&context)?);
+    let audio = Audio {
+      context,
+      mixer,
+      sounds: Rc::new(RefCell::new(vec![])),
+    };
+    audio.resume_on_gesture()?;
+    Ok(audio)
+  }
+  // 止まっている AudioContext は、キー入力やクリックを受けたイベントの中で再開する。update まで待つと Safari では再開できない
+  fn resume_on_gesture(&self) -> Result<()> {
+    let context = self.context.clone();
+    let on_gesture = browser::closure_wrap(Box::new(move || {
+      if !sound::is_suspended(&context) {
+        return;
+      }
+      match sound::resume(&context) {
+        Ok(resumed) => browser::spawn_local(async move {
+          if let Err(err) = resumed.await {
+            error!("Could not resume audio context {:#?}", err);
+          }
+        }),
+        Err(err) => {
+          error!("{:#?}", err);
+        }
+      }
+    }) as Box<dyn FnMut()>);
+    let window = browser::window()?;
+    for event in GESTURE_EVENTS {
+      window
+        .add_event_listener_with_callback(event, on_gesture.as_ref().unchecked_ref())
+        .map_err(|err| anyhow!("Failed to listen for {}: {:#?}", event, err))?;
+    }
+    on_gesture.forget();
+    Ok(())
   }
   pub async fn load_sound(&self, path: &str) -> Result<Sound> {
     let array_buffer = browser::fetch_array_buffer(path).await?;
     let buffer = sound::decode_audio_data(&self.context, &array_buffer).await?;
-    Ok(Sound { buffer })
+    let mut sounds = self.sounds.borrow_mut();
+    sounds.push(LoadedSound {
+      buffer,
+      voices: vec![],
+    });
+    Ok(Sound::new(sounds.len() - 1, path))
+  }
+  pub async fn load_music(&self, path: &str) -> Result<Sound> {
+    Ok(self.load_sound(path).await?.on_bus(Bus::Music))
+  }
+  // 同時発音数を超えたときは最も古いボイスを止めて使い回す
+  pub fn play_sound_panned(&self, sound: &Sound, pan: f32) -> Result<SoundHandle> {
+    let now = self.current_time();
+    let mut sounds = self.sounds.borrow_mut();
+    let loaded = sounds
+      .get_mut(sound.id)
+      .ok_or_else(|| anyhow!("Sound '{}' is not loaded", sound.name))?;
+    let index = match loaded
+      .voices
+      .iter()
+      .position(|voice| voice.is_finished(now))
+    {
+      Some(index) => index,
+      None if loaded.voices.len() < sound.max_voices => {
+        loaded
+          .voices
+          .push(Voice::new(&self.context, self.mixer.node(sound.bus))?);
+        loaded.voices.len() - 1
+      }
+      None => loaded
+        .voices
+        .iter()
+        .enumerate()
+        .min_by(|(_, a), (_, b)| a.started_at.total_cmp(&b.started_at))
+        .map(|(index, _)| index)
+        .ok_or_else(|| anyhow!("Sound '{}' has no voices", sound.name))?,
+    };
+    let detune = if sound.pitch_variation > 0.0 {
+      thread_rng().gen_range(-sound.pitch_variation..=sound.pitch_variation)
+    } else {
+      0.0
+    };
+    let LoadedSound { buffer, voices } = loaded;
+    voices[index].play(&self.context, buffer, sound.volume, pan, detune)
+  }
+  fn play_track(&self, sound: &Sound, offset: f64, initial_volume: f32) -> Result<SoundHandle> {
+    // 個別の音量調整やフェードができるよう、音源ごとに GainNode を挟む
+    let gain = sound::create_gain(&self.context)?;
+    sound::set_gain(&self.context, &gain, initial_volume)?;
+    sound::connect_with_audio_node(&gain, self.mixer.node(sound.bus))?;
+    let source = sound::play_looping_sound(&self.context, &self.buffer(sound)?, &gain, offset)?;
+    Ok(SoundHandle {
+      context: self.context.clone(),
+      source,
+      gain,
+      volume: sound.volume,
+      playback: None,
+    })
+  }
+  fn buffer(&self, sound: &Sound) -> Result<web_sys::AudioBuffer> {
+    self
+      .sounds
+      .borrow()
+      .get(sound.id)
+      .map(|loaded| loaded.buffer.clone())
+      .ok_or_else(|| anyhow!("Sound '{}' is not loaded", sound.name))
+  }
+  fn current_time(&self) -> f64 {
+    self.context.current_time()
+  }
+  // 自動再生制限により、ユーザー操作の前に作った AudioContext は停止状態で始まる
+  pub fn is_suspended(&self) -> bool {
+    sound::is_suspended(&self.context)
+  }
+  pub fn set_volume(&self, bus: Bus, volume: f32) -> Result<()> {
+    let volume = volume.clamp(0.0, 1.0);
+    if let Bus::Master = bus {
+      self.mixer.master_volume.set(volume);
+      if self.is_muted() {
+        return Ok(());
+      }
+    }
+    sound::set_gain(&self.context, self.mixer.node(bus), volume)
   }
-  pub fn play_sound(&self, sound: &Sound) -> Result<()> {
-    sound::play_sound(&self.context, &sound.buffer, sound::LOOPING::NO)
+  pub fn is_muted(&self) -> bool {
+    self.mixer.muted.get()
   }
-  pub fn play_looping_sound(&self, sound: &Sound) -> Result<()> {
-    sound::play_sound(&self.context, &sound.buffer, sound::LOOPING::YES)
+  pub fn toggle_mute(&self) -> Result<bool> {
+    let muted = !self.is_muted();
+    self.set_muted(muted)?;
+    Ok(muted)
+  }
+  pub fn set_muted(&self, muted: bool) -> Result<()> {
+    self.mixer.muted.set(muted);
+    let volume = if muted {
+      0.0
+    } else {
+      self.mixer.master_volume.get()
+    };
+    sound::set_gain(&self.context, &self.mixer.master, volume)
   }
 }
-#[derive(Clone)]
+impl AudioBackend for Audio {
+  fn play_sound(&self, sound: &Sound, pan: f32) -> Result<()> {
+    self.play_sound_panned(sound, pan).map(|_handle| ())
+  }
+}
+
+// 読み込んだ音声を指す軽量なハンドル。音声データ自体はバックエンド側が持つ
+#[derive(Clone, Debug)]
 pub struct Sound {
-  pub buffer: web_sys::AudioBuffer,
+  id: usize,
+  name: String,
+  bus: Bus,
+  volume: f32,
+  max_voices: usize,
+  // 再生ごとにランダムにずらす音程の幅(セント)
+  pitch_variation: f32,
+}
+impl Sound {
+  fn new(id: usize, name: &str) -> Self {
+    Sound {
+      id,
+      name: name.into(),
+      bus: Bus::Sfx,
+      volume: 1.0,
+      max_voices: DEFAULT_MAX_VOICES,
+      pitch_variation: 0.0,
+    }
+  }
+  pub fn with_max_voices(mut self, max_voices: usize) -> Self {
+    self.max_voices = max_voices.max(1);
+    self
+  }
+  pub fn with_pitch_variation(mut self, cents: f32) -> Self {
+    self.pitch_variation = cents.abs();
+    self
+  }
+  pub fn on_bus(mut self, bus: Bus) -> Self {
+    self.bus = bus;
+    self
+  }
+  pub fn with_volume(mut self, volume: f32) -> Self {
+    self.volume = volume.clamp(0.0, 1.0);
+    self
+  }
 }
 
-pub fn add_click_handler(element: HtmlElement) -> UnboundedReceiver<()> {
-  let (mut click_sender, click_receiver) = unbounded();
-  let on_click = browser::closure_wrap(Box::new(move || {
-    if let Err(err) = click_sender.start_send(()) {
-      error!("Could not send click message {:#?}", err);
+// 使い回すボイスで、何回目の再生かを数える
+#[derive(Clone, Default)]
+struct PlaybackCounter(Rc<std::cell::Cell<u32>>);
+
+impl PlaybackCounter {
+  fn next(&self) -> Playback {
+    let id = self.0.get().wrapping_add(1);
+    self.0.set(id);
+    Playback {
+      counter: self.clone(),
+      id,
     }
-  }) as Box<dyn FnMut()>);
-  element.set_onclick(Some(on_click.as_ref().unchecked_ref()));
-  on_click.forget();
-  click_receiver
+  }
+}
+
+// ボイスが次の音に取られた後は、前の再生のハンドルから GainNode を触らせない
+struct Playback {
+  counter: PlaybackCounter,
+  id: u32,
+}
+
+impl Playback {
+  fn is_current(&self) -> bool {
+    self.counter.0.get() == self.id
+  }
+}
+
+// 効果音の発音単位。GainNode と StereoPannerNode は使い回し、AudioBufferSourceNode だけを再生ごとに作り直す
+struct Voice {
+  gain: web_sys::GainNode,
+  panner: web_sys::StereoPannerNode,
+  source: Option<web_sys::AudioBufferSourceNode>,
+  started_at: f64,
+  ends_at: f64,
+  playbacks: PlaybackCounter,
+}
+
+impl Voice {
+  fn new(context: &web_sys::AudioContext, bus: &web_sys::GainNode) -> Result<Self> {
+    let gain = sound::create_gain(context)?;
+    let panner = sound::create_stereo_panner(context)?;
+    sound::connect_with_audio_node(&gain, &panner)?;
+    sound::connect_with_audio_node(&panner, bus)?;
+    Ok(Voice {
+      gain,
+      panner,
+      source: None,
+      started_at: 0.0,
+      ends_at: 0.0,
+      playbacks: PlaybackCounter::default(),
+    })
+  }
+
+  fn is_finished(&self, now: f64) -> bool {
+    self.ends_at <= now
+  }
+
+  fn play(&mut self, context: &web_sys::AudioContext, buffer: &web_sys::AudioBuffer, volume: f32, pan: f32, detune: f32) -> Result<SoundHandle> {
+    if let Some(source) = self.source.take() {
+      sound::stop_sound(&source, 0.0)?;
+    }
+    sound::set_gain(context, &self.gain, volume)?;
+    self.panner.pan().set_value(pan.clamp(-1.0, 1.0));
+    let source = sound::play_sound_detuned(context, buffer, &self.gain, detune)?;
+    let playback_rate = 2.0_f64.powf(detune as f64 / 1200.0);
+    self.started_at = context.current_time();
+    self.ends_at = self.started_at + buffer.duration() / playback_rate;
+    self.source = Some(source.clone());
+    Ok(SoundHandle {
+      context: context.clone(),
+      source,
+      gain: self.gain.clone(),
+      volume,
+      playback: Some(self.playbacks.next()),
+    })
+  }
+}
+
+#[cfg(test)]
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlayedSound {
+  pub name: String,
+  pub pan: f32,
+}
+
+// 音を鳴らさずに再生要求を記録するだけのバックエンド。ネイティブのテストで使う
+#[cfg(test)]
+#[derive(Default)]
+pub struct RecordingAudio {
+  played: RefCell<Vec<PlayedSound>>,
+  loaded: std::cell::Cell<usize>,
+}
+
+#[cfg(test)]
+impl RecordingAudio {
+  pub fn sound(&self, name: &str) -> Sound {
+    let id = self.loaded.get();
+    self.loaded.set(id + 1);
+    Sound::new(id, name)
+  }
+  pub fn played(&self) -> Vec<PlayedSound> {
+    self.played.borrow().clone()
+  }
+  pub fn play_count(&self, name: &str) -> usize {
+    self
+      .played
+      .borrow()
+      .iter()
+      .filter(|played| played.name == name)
+      .count()
+  }
+}
+
+// 何も描かない絵。ネイティブのテストで使う
+#[cfg(test)]
+pub struct NoImage;
+
+#[cfg(test)]
+impl ImageSource for NoImage {
+  fn draw(&self, _renderer: &Renderer, _frame: &Rect, _destination: &Rect) {}
+}
+
+#[cfg(test)]
+impl AudioBackend for RecordingAudio {
+  fn play_sound(&self, sound: &Sound, pan: f32) -> Result<()> {
+    self.played.borrow_mut().push(PlayedSound {
+      name: sound.name.clone(),
+      pan,
+    });
+    Ok(())
+  }
+}
+
+pub struct SoundHandle {
+  context: web_sys::AudioContext,
+  source: web_sys::AudioBufferSourceNode,
+  gain: web_sys::GainNode,
+  volume: f32,
+  // 効果音のボイスから鳴らしたときだけ持つ。曲は再生ごとに GainNode を作るので要らない
+  playback: Option<Playback>,
+}
+impl SoundHandle {
+  // ボイスが使い回された後の古いハンドルは、何もしない
+  fn is_current(&self) -> bool {
+    self.playback.as_ref().map_or(true, Playback::is_current)
+  }
+  pub fn stop(&self) -> Result<()> {
+    if !self.is_current() {
+      return Ok(());
+    }
+    sound::stop_sound(&self.source, 0.0)
+  }
+  pub fn fade_out(&self, seconds: f64) -> Result<()> {
+    if !self.is_current() {
+      return Ok(());
+    }
+    sound::ramp_gain(&self.context, &self.gain, 0.0, seconds)?;
+    sound::stop_sound(&self.source, self.context.current_time() + seconds)
+  }
+  fn fade_in(&self, seconds: f64) -> Result<()> {
+    if !self.is_current() {
+      return Ok(());
+    }
+    sound::ramp_gain(&self.context, &self.gain, self.volume, seconds)
+  }
+}
+
+enum MusicState {
+  Stopped,
+  Playing {
+    track: Sound,
+    handle: SoundHandle,
+    // 曲の先頭(offset 0)を再生した時点の AudioContext の時刻
+    started_at: f64,
+  },
+  Paused {
+    track: Sound,
+    offset: f64,
+  },
+}
+
+// BGM を1曲だけ管理し、一時停止・再開や曲の切り替え(クロスフェード)を行う
+pub struct MusicPlayer {
+  audio: Audio,
+  state: MusicState,
+}
+
+impl MusicPlayer {
+  pub fn new(audio: Audio) -> Self {
+    MusicPlayer {
+      audio,
+      state: MusicState::Stopped,
+    }
+  }
+  // AudioContext が停止中の再生要求は cue しておき、resume で再生する
+  pub fn play(&mut self, track: &Sound) -> Result<()> {
+    if self.audio.is_suspended() {
+      return self.cue(track);
+    }
+    self.stop(0.0)?;
+    self.start(track, 0.0, 0.0)
+  }
+  pub fn crossfade_to(&mut self, track: &Sound, seconds: f64) -> Result<()> {
+    if self.audio.is_suspended() {
+      return self.cue(track);
+    }
+    self.stop(seconds)?;
+    self.start(track, 0.0, seconds)
+  }
+  // 再生はせず、次の resume で先頭から鳴るように曲をセットしておく
+  pub fn cue(&mut self, track: &Sound) -> Result<()> {
+    self.stop(0.0)?;
+    self.state = MusicState::Paused {
+      track: track.clone(),
+      offset: 0.0,
+    };
+    Ok(())
+  }
+  pub fn pause(&mut self) -> Result<()> {
+    if let MusicState::Playing {
+      track,
+      handle,
+      started_at,
+    } = std::mem::replace(&mut self.state, MusicState::Stopped)
+    {
+      handle.stop()?;
+      let offset = (self.audio.current_time() - started_at) % self.audio.buffer(&track)?.duration();
+      self.state = MusicState::Paused { track, offset };
+    }
+    Ok(())
+  }
+  pub fn resume(&mut self) -> Result<()> {
+    match std::mem::replace(&mut self.state, MusicState::Stopped) {
+      MusicState::Paused { track, offset } => self.start(&track, offset, 0.0),
+      state => {
+        self.state = state;
+        Ok(())
+      }
+    }
+  }
+  pub fn stop(&mut self, fade_seconds: f64) -> Result<()> {
+    match std::mem::replace(&mut self.state, MusicState::Stopped) {
+      MusicState::Playing { handle, .. } if fade_seconds > 0.0 => handle.fade_out(fade_seconds),
+      MusicState::Playing { handle, .. } => handle.stop(),
+      _ => Ok(()),
+    }
+  }
+  fn start(&mut self, track: &Sound, offset: f64, fade_seconds: f64) -> Result<()> {
+    let handle = if fade_seconds > 0.0 {
+      let handle = self.audio.play_track(track, offset, 0.0)?;
+      handle.fade_in(fade_seconds)?;
+      handle
+    } else {
+      self.audio.play_track(track, offset, track.volume)?
+    };
+    self.state = MusicState::Playing {
+      track: track.clone(),
+      handle,
+      started_at: self.audio.current_time() - offset,
+    };
+    Ok(())
+  }
 }
@@ -0,0 +1,136 @@
+use rand::Rng;
+
+use super::{Renderer, Vec2};
+
+// 画面の揺れと一時停止、少し先を見せるための横のずれ
+#[derive(Clone, Copy, Debug)]
+pub struct CameraConfig {
+  // 揺れの強さが 1 のときに動く最大のピクセル数
+  pub max_shake: f32,
+  // 1 回の update で減る揺れの強さ
+  pub shake_decay: f32,
+  // 先読みのずれが、1 回の update で目標に近づく割合
+  pub easing: f32,
+}
+
+#[derive(Debug)]
+pub struct Camera {
+  config: CameraConfig,
+  trauma: f32,
+  hit_stop: u8,
+  lead: f32,
+  shake_offset: Vec2,
+}
+
+impl Camera {
+  pub fn new(config: CameraConfig) -> Self {
+    Camera {
+      config,
+      trauma: 0.0,
+      hit_stop: 0,
+      lead: 0.0,
+      shake_offset: Vec2::ZERO,
+    }
+  }
+
+  // 強さは 0 から 1。揺れている間に足すと強くなる
+  pub fn shake(&mut self, trauma: f32) {
+    self.trauma = (self.trauma + trauma).clamp(0.0, 1.0);
+  }
+
+  pub fn hit_stop(&mut self, frames: u8) {
+    self.hit_stop = self.hit_stop.max(frames);
+  }
+
+  // 止めている間は true を返し、残りのフレームを 1 つ減らす
+  pub fn frozen(&mut self) -> bool {
+    let frozen = self.hit_stop > 0;
+    self.hit_stop = self.hit_stop.saturating_sub(1);
+    frozen
+  }
+
+  // 揺れは強さの 2 乗で効かせて、弱まるときに早く収まるようにする
+  pub fn update(&mut self, lead_target: f32, rng: &mut impl Rng) {
+    self.lead += (lead_target - self.lead) * self.config.easing;
+    let amount = self.trauma * self.trauma * self.config.max_shake;
+    self.shake_offset = if amount > 0.0 {
+      Vec2::new(
+        rng.gen_range(-amount..=amount),
+        rng.gen_range(-amount..=amount),
+      )
+    } else {
+      Vec2::ZERO
+    };
+    self.trauma = (self.trauma - self.config.shake_decay).max(0.0);
+  }
+
+  // 揺れも先読みも無い状態に戻す
+  pub fn settle(&mut self) {
+    self.trauma = 0.0;
+    self.hit_stop = 0;
+    self.lead = 0.0;
+    self.shake_offset = Vec2::ZERO;
+  }
+
+  // 先読みの分だけ景色を左へずらす
+  pub fn offset(&self) -> Vec2 {
+    Vec2::new(self.shake_offset.x - self.lead, self.shake_offset.y)
+  }
+
+  pub fn draw(&self, renderer: &Renderer, draw: impl FnOnce(&Renderer)) {
+    renderer.with_offset(self.offset(), draw);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rand::{rngs::StdRng, SeedableRng};
+
+  const CONFIG: CameraConfig = CameraConfig {
+    max_shake: 10.0,
+    shake_decay: 0.25,
+    easing: 0.5,
+  };
+
+  #[test]
+  fn shaking_stays_within_its_strength_and_dies_down() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut camera = Camera::new(CONFIG);
+    camera.shake(0.6);
+    camera.shake(0.6);
+
+    camera.update(0.0, &mut rng);
+    let offset = camera.offset();
+    assert!(offset.x.abs() <= CONFIG.max_shake && offset.y.abs() <= CONFIG.max_shake);
+    assert_ne!(offset, Vec2::ZERO);
+
+    (0..4).for_each(|_| camera.update(0.0, &mut rng));
+    assert_eq!(camera.offset(), Vec2::ZERO);
+  }
+
+  #[test]
+  fn hit_stop_freezes_for_the_given_frames() {
+    let mut camera = Camera::new(CONFIG);
+    camera.hit_stop(2);
+    camera.hit_stop(1);
+
+    assert!(camera.frozen());
+    assert!(camera.frozen());
+    assert!(!camera.frozen());
+  }
+
+  #[test]
+  fn the_lead_eases_towards_its_target_without_overshooting() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut camera = Camera::new(CONFIG);
+
+    camera.update(20.0, &mut rng);
+    assert_eq!(camera.offset(), Vec2::new(-10.0, 0.0));
+    camera.update(20.0, &mut rng);
+    assert_eq!(camera.offset(), Vec2::new(-15.0, 0.0));
+
+    camera.settle();
+    assert_eq!(camera.offset(), Vec2::ZERO);
+  }
+}
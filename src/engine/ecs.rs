@@ -0,0 +1,484 @@
+use std::rc::Rc;
+
+use web_sys::HtmlImageElement;
+
+use super::{Rect, Renderer, SpriteSheet, Vec2};
+
+// ゲームの物をまとめて扱うための小さなエンティティ・コンポーネント・システム
+// エンティティは番号だけで、中身は World のコンポーネントごとの配列に入っている
+
+// 番号は使い回すので、消えたエンティティを指したままのハンドルは世代で見分ける
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Entity {
+  index: usize,
+  generation: u32,
+}
+
+// エンティティの番号で引ける 1 種類のコンポーネントの入れ物
+pub struct Components<T> {
+  slots: Vec<Option<(u32, T)>>,
+}
+
+impl<T> Default for Components<T> {
+  fn default() -> Self {
+    Components { slots: vec![] }
+  }
+}
+
+impl<T> Components<T> {
+  pub fn insert(&mut self, entity: Entity, value: T) {
+    if self.slots.len() <= entity.index {
+      self.slots.resize_with(entity.index + 1, || None);
+    }
+    self.slots[entity.index] = Some((entity.generation, value));
+  }
+
+  pub fn remove(&mut self, entity: Entity) -> Option<T> {
+    let slot = self.slots.get_mut(entity.index)?;
+    match slot {
+      Some((generation, _)) if *generation == entity.generation => slot.take().map(|(_, value)| value),
+      _ => None,
+    }
+  }
+
+  pub fn get(&self, entity: Entity) -> Option<&T> {
+    match self.slots.get(entity.index)? {
+      Some((generation, value)) if *generation == entity.generation => Some(value),
+      _ => None,
+    }
+  }
+
+  pub fn get_mut(&mut self, entity: Entity) -> Option<&mut T> {
+    match self.slots.get_mut(entity.index)? {
+      Some((generation, value)) if *generation == entity.generation => Some(value),
+      _ => None,
+    }
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = (Entity, &T)> {
+    self.slots.iter().enumerate().filter_map(|(index, slot)| {
+      slot.as_ref().map(|(generation, value)| {
+        (
+          Entity {
+            index,
+            generation: *generation,
+          },
+          value,
+        )
+      })
+    })
+  }
+
+  pub fn iter_mut(&mut self) -> impl Iterator<Item = (Entity, &mut T)> {
+    self
+      .slots
+      .iter_mut()
+      .enumerate()
+      .filter_map(|(index, slot)| {
+        slot.as_mut().map(|(generation, value)| {
+          (
+            Entity {
+              index,
+              generation: *generation,
+            },
+            value,
+          )
+        })
+      })
+  }
+}
+
+// ゲームが足すコンポーネントの組。エンジンは中身を知らず、エンティティが消えたときに外してもらうだけ
+pub trait ComponentSet: Default {
+  fn despawn(&mut self, entity: Entity);
+}
+
+impl<T> ComponentSet for Components<T> {
+  fn despawn(&mut self, entity: Entity) {
+    self.remove(entity);
+  }
+}
+
+// 画像をそのままの大きさで描くか、シートのセルを順番に切り替えて決まった大きさに描く。
+// Row はシートのセルを左から並べて、それぞれの大きさで描く
+pub enum Sprite {
+  Image(HtmlImageElement),
+  Row {
+    sheet: Rc<SpriteSheet>,
+    cells: Vec<String>,
+  },
+  Animation {
+    sheet: Rc<SpriteSheet>,
+    cells: Vec<String>,
+    frame_ticks: u8,
+    frame: u16,
+    width: f32,
+    height: f32,
+  },
+}
+
+impl Sprite {
+  pub fn animation(sheet: Rc<SpriteSheet>, cells: Vec<String>, frame_ticks: u8, width: f32, height: f32) -> Self {
+    Sprite::Animation {
+      sheet,
+      cells,
+      frame_ticks,
+      frame: 0,
+      width,
+      height,
+    }
+  }
+
+  fn width(&self) -> f32 {
+    match self {
+      Sprite::Image(element) => element.width() as f32,
+      Sprite::Row { sheet, cells } => cells
+        .iter()
+        .filter_map(|name| sheet.cell(name))
+        .map(|cell| f32::from(cell.frame.w))
+        .sum(),
+      Sprite::Animation { width, .. } => *width,
+    }
+  }
+
+  // 今の絵のまま止めて、高さだけを変える。足元をそろえるのは呼ぶ側が位置でする
+  pub fn freeze(&mut self, new_height: f32) {
+    if let Sprite::Animation {
+      cells,
+      frame_ticks,
+      frame,
+      height,
+      ..
+    } = self
+    {
+      let current = usize::from(*frame / u16::from(*frame_ticks));
+      *cells = cells.get(current).cloned().into_iter().collect();
+      *frame = 0;
+      *height = new_height;
+    }
+  }
+
+  fn advance(&mut self) {
+    if let Sprite::Animation {
+      cells,
+      frame_ticks,
+      frame,
+      ..
+    } = self
+    {
+      let length = cells.len() as u16 * u16::from(*frame_ticks);
+      *frame = (*frame + 1) % length.max(1);
+    }
+  }
+
+  fn draw(&self, renderer: &Renderer, position: Vec2) {
+    match self {
+      Sprite::Image(element) => renderer.draw_entire_image(element, position),
+      Sprite::Row { sheet, cells } => {
+        let mut x = position.x;
+        for name in cells {
+          let Some(cell) = sheet.cell(name) else {
+            error!("Cell {} not found", name);
+            continue;
+          };
+          let frame = Rect::from(&cell.frame);
+          sheet.draw(
+            renderer,
+            &frame,
+            &Rect::new_from_x_y(x, position.y, frame.width, frame.height),
+          );
+          x += frame.width;
+        }
+      }
+      Sprite::Animation {
+        sheet,
+        cells,
+        frame_ticks,
+        frame,
+        width,
+        height,
+      } => {
+        let Some(name) = cells.get(usize::from(*frame / u16::from(*frame_ticks))) else {
+          return;
+        };
+        match sheet.cell(name) {
+          Some(cell) => sheet.draw(
+            renderer,
+            &Rect::from(&cell.frame),
+            &Rect::new(position, *width, *height),
+          ),
+          None => {
+            error!("Cell {} not found", name);
+          }
+        }
+      }
+    }
+  }
+}
+
+// G はゲームが決めるコンポーネントの組。エンジンのシステムは触らない
+pub struct World<G> {
+  generations: Vec<u32>,
+  alive: Vec<bool>,
+  free: Vec<usize>,
+  pub positions: Components<Vec2>,
+  pub velocities: Components<Vec2>,
+  pub sprites: Components<Sprite>,
+  // 絵だけをずらす量。当たりの範囲は動かさない
+  pub draw_offsets: Components<Vec2>,
+  // 位置からの相対的な当たりの範囲。動くものは動く範囲全体を覆う
+  pub colliders: Components<Rect>,
+  // 残りの update 回数。0 になったら消える
+  pub lifetimes: Components<u16>,
+  pub game: G,
+}
+
+impl<G: ComponentSet> Default for World<G> {
+  fn default() -> Self {
+    World {
+      generations: vec![],
+      alive: vec![],
+      free: vec![],
+      positions: Components::default(),
+      velocities: Components::default(),
+      sprites: Components::default(),
+      draw_offsets: Components::default(),
+      colliders: Components::default(),
+      lifetimes: Components::default(),
+      game: G::default(),
+    }
+  }
+}
+
+impl<G: ComponentSet> World<G> {
+  pub fn new() -> Self {
+    World::default()
+  }
+
+  pub fn spawn(&mut self) -> EntityBuilder<'_, G> {
+    let entity = match self.free.pop() {
+      Some(index) => {
+        self.alive[index] = true;
+        Entity {
+          index,
+          generation: self.generations[index],
+        }
+      }
+      None => {
+        self.generations.push(0);
+        self.alive.push(true);
+        Entity {
+          index: self.alive.len() - 1,
+          generation: 0,
+        }
+      }
+    };
+    EntityBuilder {
+      world: self,
+      entity,
+    }
+  }
+
+  pub fn despawn(&mut self, entity: Entity) {
+    if !self.contains(entity) {
+      return;
+    }
+    self.positions.remove(entity);
+    self.velocities.remove(entity);
+    self.sprites.remove(entity);
+    self.draw_offsets.remove(entity);
+    self.colliders.remove(entity);
+    self.lifetimes.remove(entity);
+    self.game.despawn(entity);
+    self.alive[entity.index] = false;
+    self.generations[entity.index] += 1;
+    self.free.push(entity.index);
+  }
+
+  pub fn contains(&self, entity: Entity) -> bool {
+    self.alive.get(entity.index) == Some(&true) && self.generations[entity.index] == entity.generation
+  }
+
+  // 当たりの範囲を画面上の位置に直したもの
+  pub fn bounding_box(&self, entity: Entity) -> Option<Rect> {
+    let position = self.positions.get(entity)?;
+    let collider = self.colliders.get(entity)?;
+    Some(Rect::new(
+      *position + collider.position,
+      collider.width,
+      collider.height,
+    ))
+  }
+
+  // 当たりの範囲か絵の右端。どちらも無ければ位置そのもの
+  pub fn right(&self, entity: Entity) -> Option<f32> {
+    let position = self.positions.get(entity)?;
+    let right = match (self.colliders.get(entity), self.sprites.get(entity)) {
+      (Some(collider), _) => position.x + collider.right(),
+      (None, Some(sprite)) => position.x + sprite.width(),
+      (None, None) => position.x,
+    };
+    Some(right)
+  }
+
+  // 画面が流れた分だけ、位置を持つものをすべて動かす
+  pub fn scroll(&mut self, x: f32) {
+    self
+      .positions
+      .iter_mut()
+      .for_each(|(_, position)| position.x += x);
+  }
+
+  pub fn integrate(&mut self) {
+    for (entity, velocity) in self.velocities.iter() {
+      if let Some(position) = self.positions.get_mut(entity) {
+        *position = *position + *velocity;
+      }
+    }
+  }
+
+  pub fn expire(&mut self) {
+    let expired: Vec<Entity> = self
+      .lifetimes
+      .iter_mut()
+      .filter_map(|(entity, remaining)| {
+        *remaining = remaining.saturating_sub(1);
+        (*remaining == 0).then_some(entity)
+      })
+      .collect();
+    expired.into_iter().for_each(|entity| self.despawn(entity));
+  }
+
+  pub fn animate(&mut self) {
+    self
+      .sprites
+      .iter_mut()
+      .for_each(|(_, sprite)| sprite.advance());
+  }
+
+  // 右端が left より左に出ていったものを消す
+  pub fn cull(&mut self, left: f32) {
+    let gone: Vec<Entity> = self
+      .positions
+      .iter()
+      .map(|(entity, _)| entity)
+      .filter(|entity| self.right(*entity).is_some_and(|right| right <= left))
+      .collect();
+    gone.into_iter().for_each(|entity| self.despawn(entity));
+  }
+
+  pub fn draw(&self, renderer: &Renderer) {
+    for (entity, sprite) in self.sprites.iter() {
+      if let Some(position) = self.positions.get(entity) {
+        let offset = self.draw_offsets.get(entity).copied().unwrap_or(Vec2::ZERO);
+        sprite.draw(renderer, *position + offset);
+      }
+    }
+  }
+}
+
+pub struct EntityBuilder<'a, G> {
+  world: &'a mut World<G>,
+  entity: Entity,
+}
+
+impl<'a, G> EntityBuilder<'a, G> {
+  pub fn position(self, position: Vec2) -> Self {
+    self.world.positions.insert(self.entity, position);
+    self
+  }
+  pub fn sprite(self, sprite: Sprite) -> Self {
+    self.world.sprites.insert(self.entity, sprite);
+    self
+  }
+  pub fn collider(self, collider: Rect) -> Self {
+    self.world.colliders.insert(self.entity, collider);
+    self
+  }
+  // ゲームのコンポーネントを足す
+  pub fn with(self, add: impl FnOnce(&mut G, Entity)) -> Self {
+    add(&mut self.world.game, self.entity);
+    self
+  }
+  pub fn entity(self) -> Entity {
+    self.entity
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn world() -> World<Components<&'static str>> {
+    World::new()
+  }
+
+  #[test]
+  fn despawned_entities_are_not_seen_through_old_handles() {
+    let mut world = world();
+    let first = world
+      .spawn()
+      .position(Vec2::new(1.0, 2.0))
+      .with(|names, entity| names.insert(entity, "first"))
+      .entity();
+    world.despawn(first);
+
+    let second = world
+      .spawn()
+      .with(|names, entity| names.insert(entity, "second"))
+      .entity();
+
+    assert!(!world.contains(first));
+    assert!(world.contains(second));
+    assert_eq!(world.game.get(first), None);
+    assert_eq!(world.game.get(second), Some(&"second"));
+    // 番号は使い回しても、前のエンティティの位置は引き継がない
+    assert_eq!(world.positions.get(second), None);
+  }
+
+  #[test]
+  fn systems_move_expire_and_cull_entities() {
+    let mut world = world();
+    let moving = world.spawn().position(Vec2::new(100.0, 0.0)).entity();
+    world.velocities.insert(moving, Vec2::new(0.0, 2.0));
+    let short_lived = world.spawn().position(Vec2::new(100.0, 0.0)).entity();
+    world.lifetimes.insert(short_lived, 2);
+    let wide = world
+      .spawn()
+      .position(Vec2::new(0.0, 0.0))
+      .collider(Rect::new_from_x_y(0.0, 0.0, 50.0, 10.0))
+      .entity();
+
+    world.scroll(-10.0);
+    world.integrate();
+    world.expire();
+    assert_eq!(world.positions.get(moving), Some(&Vec2::new(90.0, 2.0)));
+    assert!(world.contains(short_lived));
+
+    world.expire();
+    assert!(!world.contains(short_lived));
+
+    world.cull(0.0);
+    assert!(world.contains(wide));
+    world.scroll(-40.0);
+    world.cull(0.0);
+    assert!(!world.contains(wide));
+    assert!(world.contains(moving));
+  }
+
+  #[test]
+  fn bounding_boxes_are_relative_to_the_position() {
+    let mut world = world();
+    let entity = world
+      .spawn()
+      .position(Vec2::new(100.0, 200.0))
+      .collider(Rect::new_from_x_y(-20.0, 5.0, 60.0, 10.0))
+      .entity();
+
+    assert_eq!(
+      world.bounding_box(entity),
+      Some(Rect::new_from_x_y(80.0, 205.0, 60.0, 10.0))
+    );
+    assert_eq!(world.right(entity), Some(140.0));
+  }
+}
@@ -0,0 +1,63 @@
+use std::ops::{Add, Sub};
+
+// 位置と速度に使う 2 次元ベクトル。1 ピクセルより細かい動きや、1 未満の加速度を扱えるように f32 にしている
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct Vec2 {
+  pub x: f32,
+  pub y: f32,
+}
+
+impl Vec2 {
+  pub const ZERO: Vec2 = Vec2::new(0.0, 0.0);
+
+  pub const fn new(x: f32, y: f32) -> Self {
+    Vec2 { x, y }
+  }
+}
+
+impl Add for Vec2 {
+  type Output = Vec2;
+
+  fn add(self, other: Vec2) -> Vec2 {
+    Vec2::new(self.x + other.x, self.y + other.y)
+  }
+}
+
+impl Sub for Vec2 {
+  type Output = Vec2;
+
+  fn sub(self, other: Vec2) -> Vec2 {
+    Vec2::new(self.x - other.x, self.y - other.y)
+  }
+}
+
+// 0 から 255 の RGB の色。時間帯の色の移り変わりのように、2 色の間を補間して使う
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Color {
+  pub r: u8,
+  pub g: u8,
+  pub b: u8,
+}
+
+impl Color {
+  pub const WHITE: Color = Color::new(255, 255, 255);
+
+  pub const fn new(r: u8, g: u8, b: u8) -> Self {
+    Color { r, g, b }
+  }
+
+  // t が 0 なら self、1 なら other
+  pub fn lerp(self, other: Color, t: f32) -> Color {
+    let t = t.clamp(0.0, 1.0);
+    let mix = |from: u8, to: u8| (f32::from(from) + (f32::from(to) - f32::from(from)) * t).round() as u8;
+    Color::new(
+      mix(self.r, other.r),
+      mix(self.g, other.g),
+      mix(self.b, other.b),
+    )
+  }
+
+  pub fn css(self) -> String {
+    format!("rgb({}, {}, {})", self.r, self.g, self.b)
+  }
+}
@@ -0,0 +1,195 @@
+use std::{ops::RangeInclusive, rc::Rc};
+
+use rand::Rng;
+
+use super::{ComponentSet, Components, Entity, Rect, Renderer, SpriteSheet, Vec2, World};
+
+// 土ぼこりや火花などの小さな粒。1 粒ずつ自前の World のエンティティにして、動きと寿命は World のシステムに任せる。
+// すぐ消えて番号が使い回されるので、入れ物は予算の数より増えない。予算を超えた分は出さない
+
+// 粒の見た目。塗りつぶした四角か、シートのセル 1 枚
+#[derive(Clone)]
+pub enum ParticleLook {
+  Color(&'static str),
+  Cell { sheet: Rc<SpriteSheet>, frame: Rect },
+}
+
+impl ParticleLook {
+  pub fn cell(sheet: Rc<SpriteSheet>, name: &str) -> Option<Self> {
+    let frame = Rect::from(&sheet.cell(name)?.frame);
+    Some(ParticleLook::Cell { sheet, frame })
+  }
+}
+
+// 1 回に出す粒の出し方。向きはラジアンで、0 が右、画面の y は下向き
+#[derive(Clone)]
+pub struct Emitter {
+  pub look: ParticleLook,
+  pub count: usize,
+  pub angle: RangeInclusive<f32>,
+  pub speed: RangeInclusive<f32>,
+  pub size: RangeInclusive<f32>,
+  pub lifetime: RangeInclusive<u16>,
+  pub gravity: f32,
+}
+
+// 粒だけが持つコンポーネント。位置と速度と残りの寿命は World のものを使う
+#[derive(Default)]
+struct ParticleComponents {
+  looks: Components<ParticleLook>,
+  sizes: Components<f32>,
+  gravities: Components<f32>,
+  // 出たときの寿命。残りの寿命と比べて薄くする
+  lifespans: Components<u16>,
+}
+
+impl ComponentSet for ParticleComponents {
+  fn despawn(&mut self, entity: Entity) {
+    self.looks.remove(entity);
+    self.sizes.remove(entity);
+    self.gravities.remove(entity);
+    self.lifespans.remove(entity);
+  }
+}
+
+pub struct ParticleSystem {
+  world: World<ParticleComponents>,
+  budget: usize,
+}
+
+impl ParticleSystem {
+  pub fn new(budget: usize) -> Self {
+    ParticleSystem {
+      world: World::new(),
+      budget,
+    }
+  }
+
+  fn count(&self) -> usize {
+    self.world.lifetimes.iter().count()
+  }
+
+  // 年を取るほど薄くなり、寿命で消える
+  fn alpha(&self, entity: Entity) -> Option<f32> {
+    let remaining = self.world.lifetimes.get(entity)?;
+    let lifespan = self.world.game.lifespans.get(entity)?;
+    Some(f32::from(*remaining) / f32::from(*lifespan))
+  }
+
+  pub fn emit(&mut self, emitter: &Emitter, position: Vec2, rng: &mut impl Rng) {
+    let count = emitter.count.min(self.budget - self.count());
+    for _ in 0..count {
+      let angle = rng.gen_range(emitter.angle.clone());
+      let speed = rng.gen_range(emitter.speed.clone());
+      let size = rng.gen_range(emitter.size.clone());
+      let lifetime = rng.gen_range(emitter.lifetime.clone()).max(1);
+      let entity = self
+        .world
+        .spawn()
+        .position(position)
+        .with(|particles, entity| {
+          particles.looks.insert(entity, emitter.look.clone());
+          particles.sizes.insert(entity, size);
+          particles.gravities.insert(entity, emitter.gravity);
+          particles.lifespans.insert(entity, lifetime);
+        })
+        .entity();
+      self
+        .world
+        .velocities
+        .insert(entity, Vec2::new(angle.cos() * speed, angle.sin() * speed));
+      self.world.lifetimes.insert(entity, lifetime);
+    }
+  }
+
+  // 画面が流れた分だけ、地面に残った粒も一緒に動かす
+  pub fn scroll(&mut self, x: f32) {
+    self.world.scroll(x);
+  }
+
+  pub fn update(&mut self) {
+    for (entity, gravity) in self.world.game.gravities.iter() {
+      if let Some(velocity) = self.world.velocities.get_mut(entity) {
+        velocity.y += gravity;
+      }
+    }
+    self.world.integrate();
+    self.world.expire();
+  }
+
+  pub fn draw(&self, renderer: &Renderer) {
+    for (entity, look) in self.world.game.looks.iter() {
+      let (Some(position), Some(size), Some(alpha)) = (
+        self.world.positions.get(entity),
+        self.world.game.sizes.get(entity),
+        self.alpha(entity),
+      ) else {
+        continue;
+      };
+      let half = size / 2.0;
+      let destination = Rect::new_from_x_y(position.x - half, position.y - half, *size, *size);
+      renderer.with_alpha(alpha, |renderer| match look {
+        ParticleLook::Color(color) => renderer.fill_rect(&destination, color),
+        ParticleLook::Cell { sheet, frame } => sheet.draw(renderer, frame, &destination),
+      });
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rand::{rngs::StdRng, SeedableRng};
+
+  fn dust(count: usize) -> Emitter {
+    Emitter {
+      look: ParticleLook::Color("white"),
+      count,
+      angle: 0.0..=0.0,
+      speed: 2.0..=2.0,
+      size: 4.0..=4.0,
+      lifetime: 3..=3,
+      gravity: 1.0,
+    }
+  }
+
+  #[test]
+  fn emitting_stops_at_the_budget() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut particles = ParticleSystem::new(10);
+
+    particles.emit(&dust(6), Vec2::ZERO, &mut rng);
+    particles.emit(&dust(6), Vec2::ZERO, &mut rng);
+
+    assert_eq!(particles.count(), 10);
+  }
+
+  #[test]
+  fn particles_fall_fade_and_disappear() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut particles = ParticleSystem::new(10);
+    particles.emit(&dust(1), Vec2::ZERO, &mut rng);
+
+    let (particle, _) = particles.world.game.looks.iter().next().unwrap();
+
+    particles.update();
+    particles.scroll(-1.0);
+    assert_eq!(
+      particles.world.positions.get(particle),
+      Some(&Vec2::new(1.0, 1.0))
+    );
+    particles.update();
+    assert_eq!(
+      particles.world.positions.get(particle),
+      Some(&Vec2::new(3.0, 3.0))
+    );
+    assert!((particles.alpha(particle).unwrap() - 1.0 / 3.0).abs() < 1e-6);
+
+    particles.update();
+    assert!(!particles.world.contains(particle));
+    assert_eq!(particles.count(), 0);
+    // 消えた分は予算に戻る
+    particles.emit(&dust(20), Vec2::ZERO, &mut rng);
+    assert_eq!(particles.count(), 10);
+  }
+}
@@ -0,0 +1,297 @@
+use super::{KeyState, GAMEPAD_A, GAMEPAD_DOWN, GAMEPAD_LEFT, GAMEPAD_RIGHT, GAMEPAD_START, GAMEPAD_UP};
+use crate::browser;
+use anyhow::{anyhow, Result};
+use futures::channel::mpsc::{unbounded, UnboundedReceiver};
+use wasm_bindgen::prelude::Closure;
+use wasm_bindgen::JsCast;
+use web_sys::Element;
+
+const FOCUSED_CLASS: &str = "focused";
+const ACTIVATE_KEYS: [&str; 2] = ["Enter", "Space"];
+
+// 画面を組み立てる部品。HTML 文字列ではなく型で組み立て、DOM ノードは Screen が作って管理する
+#[derive(Debug, PartialEq)]
+pub enum Widget {
+  Label {
+    id: &'static str,
+    text: String,
+  },
+  Button {
+    id: &'static str,
+    text: String,
+  },
+  Panel {
+    id: &'static str,
+    children: Vec<Widget>,
+  },
+}
+
+impl Widget {
+  pub fn label(id: &'static str, text: impl Into<String>) -> Self {
+    Widget::Label {
+      id,
+      text: text.into(),
+    }
+  }
+  pub fn button(id: &'static str, text: impl Into<String>) -> Self {
+    Widget::Button {
+      id,
+      text: text.into(),
+    }
+  }
+  pub fn panel(id: &'static str, children: Vec<Widget>) -> Self {
+    Widget::Panel { id, children }
+  }
+  fn id(&self) -> &'static str {
+    match self {
+      Widget::Label { id, .. } | Widget::Button { id, .. } | Widget::Panel { id, .. } => id,
+    }
+  }
+  fn tag(&self) -> &'static str {
+    match self {
+      Widget::Label { .. } => "p",
+      Widget::Button { .. } => "button",
+      Widget::Panel { .. } => "div",
+    }
+  }
+  // フォーカスを移す順番は画面に並んでいる順(木を前から辿った順)にする
+  fn buttons(&self) -> Vec<&'static str> {
+    match self {
+      Widget::Label { .. } => vec![],
+      Widget::Button { id, .. } => vec![id],
+      Widget::Panel { children, .. } => children.iter().flat_map(Widget::buttons).collect(),
+    }
+  }
+  fn text_mut(&mut self, target: &str) -> Option<&mut String> {
+    match self {
+      Widget::Label { id, text } | Widget::Button { id, text } => (*id == target).then_some(text),
+      Widget::Panel { children, .. } => children.iter_mut().find_map(|child| child.text_mut(target)),
+    }
+  }
+  fn build(&self) -> Result<Element> {
+    let element = browser::create_element(self.tag())?;
+    element.set_id(self.id());
+    match self {
+      Widget::Label { text, .. } | Widget::Button { text, .. } => element.set_text_content(Some(text)),
+      Widget::Panel { children, .. } => {
+        for child in children {
+          let child_element = child.build()?;
+          element
+            .append_child(&child_element)
+            .map_err(|err| anyhow!("Failed to append {}: {:#?}", child.id(), err))?;
+        }
+      }
+    }
+    Ok(element)
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Navigation {
+  Previous,
+  Next,
+  Activate,
+}
+
+impl Navigation {
+  // メニューの操作はキー割り当ての設定によらず固定にする
+  fn from_keystate(keystate: &KeyState) -> Option<Self> {
+    let pressed = |codes: &[&str]| codes.iter().any(|code| keystate.is_just_pressed(code));
+    if pressed(&["ArrowUp", "ArrowLeft", GAMEPAD_UP, GAMEPAD_LEFT]) {
+      Some(Navigation::Previous)
+    } else if pressed(&["ArrowDown", "ArrowRight", GAMEPAD_DOWN, GAMEPAD_RIGHT]) {
+      Some(Navigation::Next)
+    } else if pressed(&ACTIVATE_KEYS) || pressed(&[GAMEPAD_A, GAMEPAD_START]) {
+      Some(Navigation::Activate)
+    } else {
+      None
+    }
+  }
+}
+
+#[derive(Debug)]
+struct Focus {
+  buttons: Vec<&'static str>,
+  index: usize,
+}
+
+impl Focus {
+  fn new(buttons: Vec<&'static str>) -> Self {
+    Focus { buttons, index: 0 }
+  }
+  fn current(&self) -> Option<&'static str> {
+    self.buttons.get(self.index).copied()
+  }
+  // 端まで行ったら反対側に回り込む。決定したときだけボタンの id を返す
+  fn navigate(&mut self, navigation: Navigation) -> Option<&'static str> {
+    let count = self.buttons.len();
+    if count == 0 {
+      return None;
+    }
+    match navigation {
+      Navigation::Previous => self.index = (self.index + count - 1) % count,
+      Navigation::Next => self.index = (self.index + 1) % count,
+      Navigation::Activate => return self.current(),
+    }
+    None
+  }
+  fn find(&self, id: &str) -> Option<&'static str> {
+    self.buttons.iter().copied().find(|button| *button == id)
+  }
+}
+
+// #ui の下に表示している 1 画面分のウィジェット。drop すると DOM からも取り除かれる
+pub struct Screen {
+  root: Widget,
+  element: Element,
+  focus: Focus,
+  clicks: UnboundedReceiver<String>,
+  _on_click: Closure<dyn FnMut(web_sys::Event)>,
+}
+
+impl Screen {
+  pub fn show(root: Widget) -> Result<Self> {
+    let element = root.build()?;
+    let (mut click_sender, clicks) = unbounded();
+    // ボタンごとではなく画面の根元でクリックを受け取り、押されたボタンの id を送る
+    let on_click = browser::closure_wrap(Box::new(move |event: web_sys::Event| {
+      let button = event
+        .target()
+        .and_then(|target| target.dyn_into::<Element>().ok())
+        .and_then(|target| target.closest("button").ok().flatten());
+      if let Some(button) = button {
+        if let Err(err) = click_sender.start_send(button.id()) {
+          error!("Could not send click message {:#?}", err);
+        }
+      }
+      if let Err(err) = browser::focus_canvas() {
+        error!("Error focusing canvas {:#?}", err);
+      }
+    }) as Box<dyn FnMut(web_sys::Event)>);
+    element
+      .add_event_listener_with_callback("click", on_click.as_ref().unchecked_ref())
+      .map_err(|err| anyhow!("Failed to listen for clicks: {:#?}", err))?;
+    browser::append_ui(&element)?;
+
+    let screen = Screen {
+      focus: Focus::new(root.buttons()),
+      root,
+      element,
+      clicks,
+      _on_click: on_click,
+    };
+    screen.highlight()?;
+    Ok(screen)
+  }
+  // 表示中の文字と同じなら DOM には触らない
+  pub fn set_text(&mut self, id: &str, text: String) -> Result<()> {
+    let current = self
+      .root
+      .text_mut(id)
+      .ok_or_else(|| anyhow!("No widget with text found with ID '{}'", id))?;
+    if *current == text {
+      return Ok(());
+    }
+    browser::find_html_element_by_id(id)?.set_text_content(Some(&text));
+    *current = text;
+    Ok(())
+  }
+  // クリックされるか、フォーカス中のボタンが決定されたらその id を返す
+  pub fn update(&mut self, keystate: &KeyState) -> Option<&'static str> {
+    if let Ok(Some(id)) = self.clicks.try_next() {
+      return self.focus.find(&id);
+    }
+    let navigation = Navigation::from_keystate(keystate)?;
+    let previous = self.focus.current();
+    let activated = self.focus.navigate(navigation);
+    if self.focus.current() != previous {
+      if let Err(err) = self.highlight() {
+        error!("Error moving focus {:#?}", err);
+      }
+    }
+    activated
+  }
+  // キー入力は canvas で受けるので、DOM のフォーカスは動かさずクラスで見た目だけ切り替える
+  fn highlight(&self) -> Result<()> {
+    let current = self.focus.current();
+    for button in &self.focus.buttons {
+      let class = if Some(*button) == current {
+        FOCUSED_CLASS
+      } else {
+        ""
+      };
+      browser::find_html_element_by_id(button)?.set_class_name(class);
+    }
+    Ok(())
+  }
+}
+
+pub fn is_activate_key(code: &str) -> bool {
+  ACTIVATE_KEYS.contains(&code)
+}
+
+// 表示中の画面でフォーカスしているボタンの id
+pub fn focused_button() -> Option<String> {
+  let document = browser::document().ok()?;
+  let button = document
+    .query_selector(&format!("button.{}", FOCUSED_CLASS))
+    .ok()??;
+  Some(button.id())
+}
+
+impl Drop for Screen {
+  fn drop(&mut self) {
+    self.element.remove();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn menu() -> Widget {
+    Widget::panel(
+      "menu",
+      vec![
+        Widget::label("title", "Paused"),
+        Widget::button("resume", "Resume"),
+        Widget::panel("options", vec![Widget::button("settings", "Settings")]),
+        Widget::button("quit", "Quit"),
+      ],
+    )
+  }
+
+  #[test]
+  fn buttons_are_listed_in_tree_order() {
+    assert_eq!(menu().buttons(), vec!["resume", "settings", "quit"]);
+  }
+
+  #[test]
+  fn text_can_be_found_in_nested_panels() {
+    let mut menu = menu();
+    *menu.text_mut("settings").unwrap() = "Options".into();
+    assert_eq!(
+      menu.text_mut("settings").map(|text| text.as_str()),
+      Some("Options")
+    );
+    assert_eq!(menu.text_mut("options"), None);
+  }
+
+  #[test]
+  fn focus_wraps_around_both_ends() {
+    let mut focus = Focus::new(menu().buttons());
+    assert_eq!(focus.current(), Some("resume"));
+    assert_eq!(focus.navigate(Navigation::Previous), None);
+    assert_eq!(focus.current(), Some("quit"));
+    focus.navigate(Navigation::Next);
+    focus.navigate(Navigation::Next);
+    assert_eq!(focus.navigate(Navigation::Activate), Some("settings"));
+  }
+
+  #[test]
+  fn a_screen_without_buttons_never_activates() {
+    let mut focus = Focus::new(Widget::label("score", "Score: 0").buttons());
+    assert_eq!(focus.navigate(Navigation::Next), None);
+    assert_eq!(focus.navigate(Navigation::Activate), None);
+  }
+}
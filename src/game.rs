@@ -1,21 +1,121 @@
-use std::rc::Rc;
+use std::{
+  f32::consts::{PI, TAU},
+  rc::Rc,
+};
 
 use self::red_hat_boy_states::*;
 use crate::{
   browser,
-  engine::{self, Audio, Cell, Game, Image, KeyState, Point, Rect, Renderer, Sheet, Sound, SpriteSheet},
-  segments::{platform_and_stone, stone_and_platform},
+  engine::{
+    self, Audio, AudioBackend, Blend, Bus, Camera, CameraConfig, Cell, Color, ComponentSet, Components, DisplayConfig, Emitter, Entity, Game, Image,
+    ImageSource, KeyState, MusicPlayer, ParticleLook, ParticleSystem, Rect, Renderer, Screen, Sheet, Sound, Sprite, SpriteSheet, Vec2, Widget, World,
+    GAMEPAD_B,
+  },
+  high_scores::HighScores,
+  segments::{create_collectibles, create_segment, EnemyBehaviour, PlatformMotion, Segment, CRUMBLE_FRAMES, ENEMY_HEIGHT, ENEMY_WIDTH, PICKUP_SIZE},
+  settings::{next_volume, Action, Difficulty, Settings, BACK_KEY, FULLSCREEN_KEY, MUTE_KEY},
 };
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
-use futures::channel::mpsc::UnboundedReceiver;
 use gloo_utils::format::JsValueSerdeExt;
-use rand::{thread_rng, Rng};
+use rand::{seq::SliceRandom, thread_rng, Rng};
 use web_sys::HtmlImageElement;
 
-const HEIGHT: i16 = 600;
-const TIMELINE_MINIMUM: i16 = 1000;
-const OBSTACLE_BUFFER: i16 = 20; // ちょうどよさそうだった値。セグメント同士が近づきすぎないための値
+const HEIGHT: f32 = 600.0;
+const WIDTH: f32 = 600.0;
+const TIMELINE_MINIMUM: f32 = 1000.0;
+const OBSTACLE_BUFFER: f32 = 20.0; // ちょうどよさそうだった値。セグメント同士が近づきすぎないための値
+const EASY_OBSTACLE_BUFFER: f32 = 120.0;
+const MUSIC_VOLUME: f32 = 0.6;
+const MUSIC_FADE_SECONDS: f64 = 1.0;
+const PICKUP_SOUND_VOLUME: f32 = 0.6;
+const JUMP_SOUND_VOLUME: f32 = 0.8;
+const SLIDE_SOUND_VOLUME: f32 = 0.7;
+const COIN_POINTS: u32 = 100;
+const COIN_FRAMES: u8 = 4;
+const COIN_FRAME_TICKS: u8 = 6; // 何回の update ごとにコインの絵を進めるか
+const STOMP_POINTS: u32 = 200;
+const ENEMY_FRAMES: u8 = 4;
+const ENEMY_FRAME_TICKS: u8 = 8;
+const SQUASH_FRAMES: u8 = 20; // 踏まれた敵が潰れた姿で残るフレーム数
+const POWER_UP_CHANCE: f64 = 0.2; // セグメントごとにパワーアップが出る確率
+const MAGNET_RADIUS: f32 = 200.0;
+const MAGNET_SPEED: f32 = 8.0;
+const FRAMES_PER_SECOND: u16 = 60;
+const CRUMBLE_SHAKE: f32 = 2.0;
+const CRUMBLE_GRAVITY: f32 = 0.8;
+const LIVES: u8 = 3;
+const RESPAWN_CLEARANCE: f32 = WIDTH; // 起き上がったときに、画面に入っている障害物は片付ける
+const PARTICLE_BUDGET: usize = 120; // 古い携帯でも重くならないよう、画面に出す粒の数はここまで
+const DUST_INTERVAL: u32 = 6; // 走っている間、何回の update ごとに土ぼこりを立てるか
+const DUST_COLOR: &str = "#c8b08a";
+const SPARK_COLOR: &str = "#ffb030";
+const SPARKLE_CELL: &str = "Sparkle.png";
+const CAMERA: CameraConfig = CameraConfig {
+  max_shake: 12.0,
+  shake_decay: 0.04,
+  easing: 0.05,
+};
+const CAMERA_LEAD: f32 = 16.0; // 走っている間、進む先をこれだけ多く見せる。少年は画面の左端近くにいるので控えめにする
+const VIEW_MARGIN: f32 = 40.0; // カメラがずれても画面の端に隙間が出ないよう、地面は画面より広く描く
+const KNOCK_OUT_SHAKE: f32 = 0.8;
+const STOMP_SHAKE: f32 = 0.3;
+const HIT_STOP_FRAMES: u8 = 6;
+// 走った距離で移り変わる時間帯の色。昼、夕方、夜、明け方の順に DAY_LENGTH で一巡する
+// 画面に掛け合わせて重ねるので、白は何も変えず、暗い色ほど暗くなる
+const DAY_PALETTE: [Color; 4] = [
+  Color::WHITE,
+  Color::new(255, 196, 150),
+  Color::new(80, 90, 160),
+  Color::new(235, 200, 210),
+];
+const DAY_LENGTH: u32 = 7200; // 2 分ほど走ると一巡する
+const GROUND_TILE: &str = "2.png";
+const GROUND_DEPTH: f32 = 16.0; // 画面の下端に見えている地面の厚み。タイルの草の部分だけを切り出す
+
+// 穴の縁は、草の端が丸くなっているタイルから切り出して、地面の切れ目が分かるようにする
+const GROUND_LEFT_EDGE_TILE: &str = "1.png";
+const GROUND_RIGHT_EDGE_TILE: &str = "3.png";
+const GROUND_EDGE_WIDTH: f32 = 24.0;
+const PIT_COLOR: &str = "#2a1a0e"; // 穴の中は背景の緑が透けないよう暗い土の色で塗る
+const SOUND_PROMPT_ID: &str = "sound_prompt";
+const SCORE_ID: &str = "score";
+const POWER_UPS_ID: &str = "power_ups";
+const LIVES_ID: &str = "lives";
+const GAME_OVER_ID: &str = "game_over";
+const NEW_GAME_ID: &str = "new_game";
+const NEW_HIGH_SCORE_ID: &str = "new_high_score";
+const FINAL_DISTANCE_ID: &str = "final_distance";
+const FINAL_PICKUPS_ID: &str = "final_pickups";
+const FINAL_STOMPS_ID: &str = "final_stomps";
+const FINAL_SCORE_ID: &str = "final_score";
+const MAIN_MENU_ID: &str = "main_menu";
+const PLAY_ID: &str = "play";
+const SETTINGS_ID: &str = "settings";
+const HIGH_SCORES_ID: &str = "high_scores";
+const CREDITS_ID: &str = "credits";
+const SOUND_ID: &str = "sound";
+const MENU_FULLSCREEN_ID: &str = "menu_fullscreen";
+const MASTER_VOLUME_ID: &str = "master_volume";
+const MUSIC_VOLUME_ID: &str = "music_volume";
+const SFX_VOLUME_ID: &str = "sfx_volume";
+const RUN_KEY_ID: &str = "run_key";
+const JUMP_KEY_ID: &str = "jump_key";
+const SLIDE_KEY_ID: &str = "slide_key";
+const SHOW_FPS_ID: &str = "show_fps";
+const REDUCED_MOTION_ID: &str = "reduced_motion";
+const DIFFICULTY_ID: &str = "difficulty";
+const LIVES_MODE_ID: &str = "lives_mode";
+const BACK_ID: &str = "back";
+const CREDITS: &str = include_str!("../static/credits.txt");
+const DISPLAY: DisplayConfig = DisplayConfig {
+  width: WIDTH,
+  height: HEIGHT,
+  canvas_id: "canvas",
+  container_id: "game",
+  fullscreen_buttons: &["fullscreen", MENU_FULLSCREEN_ID],
+  fullscreen_key: Some(FULLSCREEN_KEY),
+};
 pub struct WalkTheDog {
   machine: Option<WalkTheDogStateMachine>,
 }
@@ -26,28 +126,53 @@ impl WalkTheDog {
 }
 
 enum WalkTheDogStateMachine {
+  Title(WalkTheDogState<Title>),
   Ready(WalkTheDogState<Ready>),
   Walking(WalkTheDogState<Walking>),
   GameOver(WalkTheDogState<GameOver>),
 }
 impl WalkTheDogStateMachine {
   fn new(walk: Walk) -> Self {
-    WalkTheDogStateMachine::Ready(WalkTheDogState::new(walk))
+    WalkTheDogStateMachine::Title(WalkTheDogState::new(walk))
   }
-  fn update(self, keystate: &KeyState) -> Self {
+  fn update(mut self, keystate: &KeyState) -> Self {
+    if keystate.has_user_gesture() {
+      self.walk_mut().unlock_audio();
+    }
+    if keystate.is_just_pressed(MUTE_KEY) {
+      self.walk_mut().toggle_mute();
+    }
     match self {
+      WalkTheDogStateMachine::Title(state) => state.update(keystate).into(),
       WalkTheDogStateMachine::Ready(state) => state.update(keystate).into(),
       WalkTheDogStateMachine::Walking(state) => state.update(keystate).into(),
-      WalkTheDogStateMachine::GameOver(state) => state.update().into(),
+      WalkTheDogStateMachine::GameOver(state) => state.update(keystate).into(),
     }
   }
   fn draw(&self, renderer: &Renderer) {
     match self {
+      WalkTheDogStateMachine::Title(state) => state.draw(renderer),
       WalkTheDogStateMachine::Ready(state) => state.draw(renderer),
       WalkTheDogStateMachine::Walking(state) => state.draw(renderer),
       WalkTheDogStateMachine::GameOver(state) => state.draw(renderer),
     }
   }
+  fn walk(&self) -> &Walk {
+    match self {
+      WalkTheDogStateMachine::Title(state) => &state.walk,
+      WalkTheDogStateMachine::Ready(state) => &state.walk,
+      WalkTheDogStateMachine::Walking(state) => &state.walk,
+      WalkTheDogStateMachine::GameOver(state) => &state.walk,
+    }
+  }
+  fn walk_mut(&mut self) -> &mut Walk {
+    match self {
+      WalkTheDogStateMachine::Title(state) => &mut state.walk,
+      WalkTheDogStateMachine::Ready(state) => &mut state.walk,
+      WalkTheDogStateMachine::Walking(state) => &mut state.walk,
+      WalkTheDogStateMachine::GameOver(state) => &mut state.walk,
+    }
+  }
 }
 struct WalkTheDogState<T> {
   _state: T,
@@ -58,10 +183,23 @@ impl<T> WalkTheDogState<T> {
     self.walk.draw(renderer);
   }
 }
+struct Title {
+  page: MenuPage,
+  menu: Screen,
+  // キー割り当てを変えている間は、次に押されたキーを待つ
+  rebinding: Option<Action>,
+}
+#[derive(Clone, Copy, PartialEq)]
+enum MenuPage {
+  Main,
+  Settings,
+  HighScores,
+  Credits,
+}
 struct Ready;
 struct Walking;
 struct GameOver {
-  new_game_event: UnboundedReceiver<()>,
+  menu: Screen,
 }
 enum ReadyEndState {
   Complete(WalkTheDogState<Walking>),
@@ -75,17 +213,253 @@ impl From<ReadyEndState> for WalkTheDogStateMachine {
     }
   }
 }
-impl WalkTheDogState<Ready> {
-  fn new(walk: Walk) -> Self {
-    browser::draw_ui("<p id='score'>0</>").unwrap();
+enum TitleEndState {
+  Complete(WalkTheDogState<Ready>),
+  Continue(WalkTheDogState<Title>),
+}
+impl From<TitleEndState> for WalkTheDogStateMachine {
+  fn from(end_state: TitleEndState) -> Self {
+    match end_state {
+      TitleEndState::Complete(ready_state) => ready_state.into(),
+      TitleEndState::Continue(title_state) => title_state.into(),
+    }
+  }
+}
+impl WalkTheDogState<Title> {
+  fn new(mut walk: Walk) -> Self {
+    walk.play_track(Track::Ready);
+    let page = MenuPage::Main;
+    let menu = page.show(&walk).expect("Could not show the title screen");
     WalkTheDogState {
-      _state: Ready,
+      _state: Title {
+        page,
+        menu,
+        rebinding: None,
+      },
       walk,
     }
   }
+  fn update(mut self, keystate: &KeyState) -> TitleEndState {
+    self.walk.boy.update();
+    if let Some(action) = self._state.rebinding {
+      self.rebind(action, keystate);
+      self.refresh_settings();
+      return TitleEndState::Continue(self);
+    }
+    match self._state.menu.update(keystate) {
+      Some(PLAY_ID) => return TitleEndState::Complete(self.play()),
+      Some(SETTINGS_ID) => self.open(MenuPage::Settings),
+      Some(HIGH_SCORES_ID) => self.open(MenuPage::HighScores),
+      Some(CREDITS_ID) => self.open(MenuPage::Credits),
+      Some(BACK_ID) => self.open(MenuPage::Main),
+      Some(SOUND_ID) => self.walk.toggle_mute(),
+      Some(MASTER_VOLUME_ID) => self
+        .walk
+        .change_settings(|settings| settings.master_volume = next_volume(settings.master_volume)),
+      Some(MUSIC_VOLUME_ID) => self
+        .walk
+        .change_settings(|settings| settings.music_volume = next_volume(settings.music_volume)),
+      Some(SFX_VOLUME_ID) => self
+        .walk
+        .change_settings(|settings| settings.sfx_volume = next_volume(settings.sfx_volume)),
+      Some(RUN_KEY_ID) => self._state.rebinding = Some(Action::Run),
+      Some(JUMP_KEY_ID) => self._state.rebinding = Some(Action::Jump),
+      Some(SLIDE_KEY_ID) => self._state.rebinding = Some(Action::Slide),
+      Some(SHOW_FPS_ID) => self
+        .walk
+        .change_settings(|settings| settings.show_fps = !settings.show_fps),
+      Some(REDUCED_MOTION_ID) => self
+        .walk
+        .change_settings(|settings| settings.reduced_motion = !settings.reduced_motion),
+      Some(DIFFICULTY_ID) => self
+        .walk
+        .change_settings(|settings| settings.difficulty = settings.difficulty.next()),
+      Some(LIVES_MODE_ID) => self
+        .walk
+        .change_settings(|settings| settings.lives_mode = !settings.lives_mode),
+      // 全画面はクリックやキーを受けたイベントの中で engine が切り替える
+      Some(MENU_FULLSCREEN_ID) => {}
+      _ => {
+        if is_back_pressed(keystate) && self._state.page != MenuPage::Main {
+          self.open(MenuPage::Main);
+        }
+      }
+    }
+    self.refresh_settings();
+    TitleEndState::Continue(self)
+  }
+  // Escape で取り消す。ゲームパッドのボタンや、ゲーム全体で使うキーには割り当てられない
+  fn rebind(&mut self, action: Action, keystate: &KeyState) {
+    if is_back_pressed(keystate) {
+      self._state.rebinding = None;
+    } else if let Some(code) = keystate.just_pressed_key() {
+      let mut key_bindings = self.walk.settings.key_bindings.clone();
+      match key_bindings.bind(action, code) {
+        Ok(()) => {
+          self
+            .walk
+            .change_settings(|settings| settings.key_bindings = key_bindings);
+          self._state.rebinding = None;
+        }
+        Err(err) => {
+          log!("Could not bind the key {:#?}", err);
+        }
+      }
+    }
+  }
+  // M キーでも音を切り替えられるので、表示は毎回合わせる
+  fn refresh_settings(&mut self) {
+    if self._state.page != MenuPage::Settings {
+      return;
+    }
+    for (id, label) in settings_labels(&self.walk, self._state.rebinding) {
+      if let Err(err) = self._state.menu.set_text(id, label) {
+        error!("Error updating the settings screen {:#?}", err);
+      }
+    }
+  }
+  fn open(&mut self, page: MenuPage) {
+    match page.show(&self.walk) {
+      Ok(menu) => {
+        self._state = Title {
+          page,
+          menu,
+          rebinding: None,
+        }
+      }
+      Err(err) => {
+        error!("Error opening the menu {:#?}", err);
+      }
+    }
+  }
+  // タイトル画面と同じ曲が流れているので、そのまま遊び始める
+  fn play(self) -> WalkTheDogState<Ready> {
+    WalkTheDogState {
+      _state: Ready,
+      walk: self.walk,
+    }
+  }
+}
+impl MenuPage {
+  fn show(self, walk: &Walk) -> Result<Screen> {
+    let root = match self {
+      MenuPage::Main => Widget::panel(
+        "title_menu",
+        vec![
+          Widget::label("title", "Walk the Dog"),
+          Widget::button(PLAY_ID, "Play"),
+          Widget::button(SETTINGS_ID, "Settings"),
+          Widget::button(HIGH_SCORES_ID, "High Scores"),
+          Widget::button(CREDITS_ID, "Credits"),
+        ],
+      ),
+      MenuPage::Settings => {
+        let mut buttons: Vec<Widget> = settings_labels(walk, None)
+          .into_iter()
+          .map(|(id, label)| Widget::button(id, label))
+          .collect();
+        buttons.push(Widget::button(MENU_FULLSCREEN_ID, "Full Screen"));
+        buttons.push(Widget::button(BACK_ID, "Back"));
+        Widget::panel("settings_menu", buttons)
+      }
+      MenuPage::HighScores => Widget::panel(
+        "high_scores_menu",
+        vec![
+          Widget::label(
+            "high_score_list",
+            high_score_list(walk.high_scores.scores()),
+          ),
+          Widget::button(BACK_ID, "Back"),
+        ],
+      ),
+      MenuPage::Credits => Widget::panel(
+        "credits_menu",
+        vec![
+          Widget::label("credits_text", CREDITS.trim()),
+          Widget::button(BACK_ID, "Back"),
+        ],
+      ),
+    };
+    Screen::show(root)
+  }
+}
+fn is_back_pressed(keystate: &KeyState) -> bool {
+  keystate.is_just_pressed(BACK_KEY) || keystate.is_just_pressed(GAMEPAD_B)
+}
+fn settings_labels(walk: &Walk, rebinding: Option<Action>) -> [(&'static str, String); 11] {
+  let settings = &walk.settings;
+  let on_off = |on: bool| if on { "On" } else { "Off" };
+  let percent = |volume: f32| format!("{}%", (volume * 100.0).round());
+  let key = |action: Action| {
+    if rebinding == Some(action) {
+      "Press a key".into()
+    } else {
+      settings.key_bindings.key(action).to_string()
+    }
+  };
+  [
+    (SOUND_ID, format!("Sound: {}", on_off(!settings.muted))),
+    (
+      MASTER_VOLUME_ID,
+      format!("Volume: {}", percent(settings.master_volume)),
+    ),
+    (
+      MUSIC_VOLUME_ID,
+      format!("Music: {}", percent(settings.music_volume)),
+    ),
+    (
+      SFX_VOLUME_ID,
+      format!("Effects: {}", percent(settings.sfx_volume)),
+    ),
+    (RUN_KEY_ID, format!("Run: {}", key(Action::Run))),
+    (JUMP_KEY_ID, format!("Jump: {}", key(Action::Jump))),
+    (SLIDE_KEY_ID, format!("Slide: {}", key(Action::Slide))),
+    (
+      SHOW_FPS_ID,
+      format!("Show FPS: {}", on_off(settings.show_fps)),
+    ),
+    (
+      REDUCED_MOTION_ID,
+      format!("Reduced Motion: {}", on_off(settings.reduced_motion)),
+    ),
+    (
+      DIFFICULTY_ID,
+      format!("Difficulty: {}", settings.difficulty.name()),
+    ),
+    (
+      LIVES_MODE_ID,
+      format!("Lives: {}", on_off(settings.lives_mode)),
+    ),
+  ]
+}
+// 易しいほどセグメントの間を広く空ける。どの組み合わせもこの間隔で抜けられることはテストで確かめる
+fn obstacle_gap(difficulty: Difficulty) -> f32 {
+  match difficulty {
+    Difficulty::Easy => EASY_OBSTACLE_BUFFER,
+    Difficulty::Normal => OBSTACLE_BUFFER,
+    Difficulty::Hard => 0.0,
+  }
+}
+fn high_score_list(scores: &[u32]) -> String {
+  if scores.is_empty() {
+    return "No scores yet".into();
+  }
+  scores
+    .iter()
+    .enumerate()
+    .map(|(rank, score)| format!("{}. {}", rank + 1, score))
+    .collect::<Vec<_>>()
+    .join("\n")
+}
+impl WalkTheDogState<Ready> {
   fn update(mut self, keystate: &KeyState) -> ReadyEndState {
     self.walk.boy.update();
-    if keystate.is_pressed("ArrowRight") {
+    if self
+      .walk
+      .settings
+      .key_bindings
+      .is_pressed(keystate, Action::Run)
+    {
       ReadyEndState::Complete(self.start_running())
     } else {
       ReadyEndState::Continue(self)
@@ -93,6 +467,7 @@ impl WalkTheDogState<Ready> {
   }
   fn start_running(mut self) -> WalkTheDogState<Walking> {
     self.run_right();
+    self.walk.play_track(Track::Walking);
     WalkTheDogState {
       _state: Walking,
       walk: self.walk,
@@ -104,57 +479,117 @@ impl WalkTheDogState<Ready> {
 }
 impl WalkTheDogState<Walking> {
   fn update(mut self, keystate: &KeyState) -> WalkingEndState {
-    if keystate.is_pressed("Space") {
+    // ぶつかった直後は少しだけ止めて、当たったことを伝える。その間も画面の揺れと飛び散る粒は続ける
+    if self.walk.camera.frozen() {
+      self.walk.move_camera(true);
+      self.walk.particles.update();
+      return WalkingEndState::Continue(self);
+    }
+    // 二段ジャンプは押し直したときだけ。押しっぱなしで空中に出た瞬間に跳ばないようにする
+    if self
+      .walk
+      .settings
+      .key_bindings
+      .is_just_pressed(keystate, Action::Jump)
+    {
+      self.walk.boy.air_jump();
+    }
+    if self
+      .walk
+      .settings
+      .key_bindings
+      .is_pressed(keystate, Action::Jump)
+    {
       self.walk.boy.jump();
     }
 
-    if keystate.is_pressed("ArrowDown") {
+    if self
+      .walk
+      .settings
+      .key_bindings
+      .is_pressed(keystate, Action::Slide)
+    {
       self.walk.boy.slide();
     }
 
+    let was_airborne = matches!(
+      self.walk.boy.state_machine,
+      RedHatBoyStateMachine::Jumping(_)
+    );
+    let was_down = self.walk.boy.state_machine.is_down();
     self.walk.boy.update();
 
     let walking_speed = self.walk.velocity();
     let [first_background, second_background] = &mut self.walk.backgrounds;
     first_background.move_horizontally(walking_speed);
     second_background.move_horizontally(walking_speed);
+    self.walk.ground.move_horizontally(walking_speed);
 
-    if first_background.right() < 0 {
+    if first_background.right() < 0.0 {
       first_background.set_x(second_background.right());
     }
-    if second_background.right() < 0 {
+    if second_background.right() < 0.0 {
       second_background.set_x(first_background.right());
     }
 
-    self.walk.obstacles.retain(|obstacle| obstacle.right() > 0);
-
-    self.walk.obstacles.iter_mut().for_each(|obstacle| {
-      obstacle.move_horizontally(walking_speed);
-      obstacle.check_intersection(&mut self.walk.boy);
-    });
+    let world = &mut self.walk.world;
+    world.cull(0.0);
+    world.scroll(walking_speed);
+    let magnet = self
+      .walk
+      .boy
+      .effects()
+      .is_active(PowerUp::Magnet)
+      .then(|| self.walk.boy.bounding_box());
+    attract_coins(world, magnet.as_ref());
+    follow_paths(world);
+    crumble(world);
+    world.integrate();
+    world.animate();
+    world.expire();
+    self.walk.particles.scroll(walking_speed);
+    self.walk.particles.update();
+    self.walk.run_obstacles();
+    self.walk.collect_pickups();
+    self.walk.emit_particles(was_airborne, was_down);
+    self.walk.move_camera(was_down);
 
     if self.walk.timeline < TIMELINE_MINIMUM {
       self.walk.generate_next_segment();
     } else {
       self.walk.timeline += walking_speed;
     }
-    self.walk.score += 1;
+    self.walk.distance += 1;
+    self.walk.show_score();
 
-    if self.walk.knocked_out() {
+    if self.walk.knocked_out() && !self.walk.respawn() {
       WalkingEndState::Complete(self.end_game())
     } else {
       WalkingEndState::Continue(self)
     }
   }
-  fn end_game(self) -> WalkTheDogState<GameOver> {
-    let receiver = browser::draw_ui("<button id='new_game'>New Game</button>")
-      .and_then(|_unit| browser::find_html_element_by_id("new_game"))
-      .map(|element| engine::add_click_handler(element))
-      .unwrap();
+  fn end_game(mut self) -> WalkTheDogState<GameOver> {
+    // ゲームオーバーの間は止まった画面を見せるので、揺れたままにしない
+    self.walk.camera.settle();
+    self.walk.play_track(Track::GameOver);
+    let score = self.walk.score();
+    let mut widgets = vec![
+      Widget::label(
+        FINAL_DISTANCE_ID,
+        format!("Distance: {}", self.walk.distance),
+      ),
+      Widget::label(FINAL_PICKUPS_ID, format!("Pickups: {}", self.walk.pickups)),
+      Widget::label(FINAL_STOMPS_ID, format!("Stomps: {}", self.walk.stomps)),
+      Widget::label(FINAL_SCORE_ID, format!("Score: {}", score)),
+    ];
+    if self.walk.high_scores.record(score) {
+      widgets.push(Widget::label(NEW_HIGH_SCORE_ID, "New High Score!"));
+    }
+    widgets.push(Widget::button(NEW_GAME_ID, "New Game"));
+    widgets.push(Widget::button(MAIN_MENU_ID, "Main Menu"));
+    let menu = Screen::show(Widget::panel(GAME_OVER_ID, widgets)).expect("Could not show the game over screen");
     WalkTheDogState {
-      _state: GameOver {
-        new_game_event: receiver,
-      },
+      _state: GameOver { menu },
       walk: self.walk,
     }
   }
@@ -173,24 +608,31 @@ impl From<WalkingEndState> for WalkTheDogStateMachine {
   }
 }
 impl WalkTheDogState<GameOver> {
-  fn update(mut self) -> GameOverEndState {
-    if self._state.new_game_pressed() {
-      GameOverEndState::Complete(self.new_game())
-    } else {
-      GameOverEndState::Continue(self)
+  fn update(mut self, keystate: &KeyState) -> GameOverEndState {
+    // 倒れたときの火花は、止まった画面の上でも最後まで飛ばして消す
+    self.walk.particles.update();
+    match self._state.menu.update(keystate) {
+      Some(NEW_GAME_ID) => GameOverEndState::Complete(self.new_game()),
+      Some(MAIN_MENU_ID) => GameOverEndState::Menu(self.main_menu()),
+      _ => GameOverEndState::Continue(self),
     }
   }
+  // GameOver を捨てると画面も DOM から取り除かれる
   fn new_game(self) -> WalkTheDogState<Ready> {
-    browser::hide_ui();
+    let mut walk = Walk::reset(self.walk);
+    walk.play_track(Track::Ready);
     WalkTheDogState {
       _state: Ready,
-      walk: Walk::reset(self.walk),
+      walk,
     }
   }
+  fn main_menu(self) -> WalkTheDogState<Title> {
+    WalkTheDogState::<Title>::new(Walk::reset(self.walk))
+  }
 }
-impl GameOver {
-  fn new_game_pressed(&mut self) -> bool {
-    matches!(self.new_game_event.try_next(), Ok(Some(())))
+impl From<WalkTheDogState<Title>> for WalkTheDogStateMachine {
+  fn from(state: WalkTheDogState<Title>) -> Self {
+    WalkTheDogStateMachine::Title(state)
   }
 }
 impl From<WalkTheDogState<Ready>> for WalkTheDogStateMachine {
@@ -211,6 +653,7 @@ impl From<WalkTheDogState<GameOver>> for WalkTheDogStateMachine {
 enum GameOverEndState {
   Continue(WalkTheDogState<GameOver>),
   Complete(WalkTheDogState<Ready>),
+  Menu(WalkTheDogState<Title>),
 }
 
 impl From<GameOverEndState> for WalkTheDogStateMachine {
@@ -218,83 +661,452 @@ impl From<GameOverEndState> for WalkTheDogStateMachine {
     match state {
       GameOverEndState::Continue(game_over) => game_over.into(),
       GameOverEndState::Complete(ready) => ready.into(),
+      GameOverEndState::Menu(title) => title.into(),
     }
   }
 }
 pub struct Walk {
   boy: RedHatBoy,
   backgrounds: [Image; 2],
-  obstacles: Vec<Box<dyn Obstacle>>,
+  ground: Ground,
+  // 障害物と拾えるもの。更新、描画、画面外に出たときの片付けは World のシステムでまとめて行う
+  world: World<WalkComponents>,
+  particles: ParticleSystem,
+  emitters: Emitters,
+  camera: Camera,
   obstacle_sheet: Rc<SpriteSheet>,
+  enemy_sheet: Rc<SpriteSheet>,
   stone: HtmlImageElement,
-  timeline: i16,
-  score: u16,
+  sign: HtmlImageElement,
+  pickup_sheet: Rc<SpriteSheet>,
+  pickup_sound: Sound,
+  timeline: f32,
+  // 前のフレームで乗っていた足場が横に動いた量
+  carry: f32,
+  // 走った距離(update ごとに 1)と拾ったものの点数、敵を踏んだ点数。合計がスコアになる
+  distance: u32,
+  pickups: u32,
+  stomps: u32,
+  // 残機モードのときだけ使う。0 になったらゲームオーバー
+  lives: u8,
+  hud: Screen,
+  power_up_hud: Screen,
+  lives_hud: Screen,
+  high_scores: HighScores,
+  settings: Settings,
+  sound_prompt: Option<Screen>,
+  audio: Audio,
+  music: MusicPlayer,
+  tracks: MusicTracks,
+}
+
+// ゲームの中で出す粒の出し方
+struct Emitters {
+  dust: Emitter,
+  landing: Emitter,
+  knock_out: Emitter,
+  sparkle: Emitter,
+}
+
+impl Emitters {
+  fn new(pickup_sheet: &Rc<SpriteSheet>) -> Self {
+    let sparkle = ParticleLook::cell(pickup_sheet.clone(), SPARKLE_CELL).unwrap_or_else(|| {
+      error!("Cell {} not found", SPARKLE_CELL);
+      ParticleLook::Color("gold")
+    });
+    Emitters {
+      // 後ろ上に少し舞い上がってすぐ消える
+      dust: Emitter {
+        look: ParticleLook::Color(DUST_COLOR),
+        count: 2,
+        angle: PI + 0.2..=PI + 0.8,
+        speed: 0.5..=1.5,
+        size: 3.0..=6.0,
+        lifetime: 15..=25,
+        gravity: 0.03,
+      },
+      landing: Emitter {
+        look: ParticleLook::Color(DUST_COLOR),
+        count: 10,
+        angle: PI + 0.1..=TAU - 0.1,
+        speed: 1.0..=2.5,
+        size: 3.0..=7.0,
+        lifetime: 15..=25,
+        gravity: 0.05,
+      },
+      knock_out: Emitter {
+        look: ParticleLook::Color(SPARK_COLOR),
+        count: 24,
+        angle: 0.0..=TAU,
+        speed: 2.0..=5.0,
+        size: 3.0..=6.0,
+        lifetime: 20..=40,
+        gravity: 0.2,
+      },
+      sparkle: Emitter {
+        look: sparkle,
+        count: 6,
+        angle: 0.0..=TAU,
+        speed: 1.0..=2.5,
+        size: 8.0..=14.0,
+        lifetime: 15..=25,
+        gravity: 0.0,
+      },
+    }
+  }
+}
+
+#[derive(Clone, Copy)]
+enum Track {
+  Ready,
+  Walking,
+  GameOver,
+}
+
+struct MusicTracks {
+  ready: Sound,
+  walking: Sound,
+  game_over: Sound,
+}
+
+impl MusicTracks {
+  fn sound(&self, track: Track) -> &Sound {
+    match track {
+      Track::Ready => &self.ready,
+      Track::Walking => &self.walking,
+      Track::GameOver => &self.game_over,
+    }
+  }
 }
 
 impl Walk {
-  fn velocity(&self) -> i16 {
-    -self.boy.walking_speed()
+  // 動く足場に乗っていれば、その分だけ背景ごと余計に動かす
+  fn velocity(&self) -> f32 {
+    -self.boy.walking_speed() - self.carry
   }
   fn generate_next_segment(&mut self) {
     let mut rng = thread_rng();
-    let next_segmenet = rng.gen_range(0..2);
-    let mut next_obstacles = match next_segmenet {
-      0 => stone_and_platform(
-        self.stone.clone(),
-        self.obstacle_sheet.clone(),
-        self.timeline + OBSTACLE_BUFFER,
-      ),
-      1 => platform_and_stone(
-        self.stone.clone(),
-        self.obstacle_sheet.clone(),
-        self.timeline + OBSTACLE_BUFFER,
-      ),
-      _ => vec![],
-    };
+    let segment = Segment::random(&mut rng);
+    let gap = obstacle_gap(self.settings.difficulty);
+    let next_obstacles = create_segment(
+      &mut self.world,
+      segment,
+      self.stone.clone(),
+      self.sign.clone(),
+      self.obstacle_sheet.clone(),
+      self.enemy_sheet.clone(),
+      self.timeline + gap,
+    );
 
-    self.timeline = rightmost(&next_obstacles);
-    self.obstacles.append(&mut next_obstacles);
+    let power_up = rng
+      .gen_bool(POWER_UP_CHANCE)
+      .then(|| *PowerUp::ALL.choose(&mut rng).unwrap_or(&PowerUp::Shield));
+    create_collectibles(
+      &mut self.world,
+      segment,
+      self.pickup_sheet.clone(),
+      self.timeline + gap,
+      power_up,
+    );
+
+    self.timeline = rightmost(&self.world, &next_obstacles);
   }
   fn draw(&self, renderer: &Renderer) {
     self
       .backgrounds
       .iter()
       .for_each(|background| background.draw(renderer));
-    self.boy.draw(renderer);
-    self
-      .obstacles
+    let pits: Vec<(f32, f32)> = self
+      .world
+      .game
+      .contacts
       .iter()
-      .for_each(|obstacle| obstacle.draw(renderer));
-    browser::find_html_element_by_id("score")
-      .map(|element| element.set_inner_html(&format!("Score: {}", self.score)))
-      .unwrap();
+      .filter(|(_, contact)| **contact == Contact::Pit)
+      .filter_map(|(entity, _)| self.world.bounding_box(entity))
+      .map(|bounding_box| (bounding_box.x(), bounding_box.right()))
+      .collect();
+    // 遠くの背景は揺らさず、走っている場所から手前だけをカメラでずらす
+    self.camera.draw(renderer, |renderer| {
+      self.ground.draw(renderer, &pits);
+      self.boy.draw(renderer);
+      self.world.draw(renderer);
+    });
+    let tint = sky_tint(self.distance);
+    if tint != Color::WHITE {
+      renderer.with_blend(Blend::Multiply, |renderer| {
+        renderer.fill_rect(&Rect::new_from_x_y(0.0, 0.0, WIDTH, HEIGHT), &tint.css());
+      });
+    }
+    // 火花や光は夜でも目立つよう、色を重ねた後に描く
+    self
+      .camera
+      .draw(renderer, |renderer| self.particles.draw(renderer));
   }
   fn knocked_out(&self) -> bool {
     self.boy.knocked_out()
   }
+  fn score(&self) -> u32 {
+    self.distance + self.pickups + self.stomps
+  }
+  // 残機があれば 1 つ減らしてその場で走り直す。スコアと距離はそのまま
+  fn respawn(&mut self) -> bool {
+    if !self.settings.lives_mode {
+      return false;
+    }
+    self.lives = self.lives.saturating_sub(1);
+    if self.lives == 0 {
+      return false;
+    }
+    let nearby: Vec<Entity> = self
+      .world
+      .game
+      .contacts
+      .iter()
+      .map(|(entity, _)| entity)
+      .filter(|entity| {
+        self
+          .world
+          .bounding_box(*entity)
+          .is_some_and(|bounding_box| bounding_box.x() <= RESPAWN_CLEARANCE)
+      })
+      .collect();
+    nearby
+      .into_iter()
+      .for_each(|entity| self.world.despawn(entity));
+    self.boy.respawn();
+    self.show_score();
+    true
+  }
+  // 障害物と少年との当たりを見る。足場に乗って動いた量と、敵を踏んだ点数もここで集める
+  fn run_obstacles(&mut self) {
+    let stomped = touch(&mut self.world, &mut self.boy);
+    self.carry = carry(&self.world);
+    if !stomped.is_empty() && !self.settings.reduced_motion {
+      self.camera.shake(STOMP_SHAKE);
+    }
+    for entity in stomped {
+      self.stomps += self.world.game.points.remove(entity).unwrap_or(0);
+      squash(&mut self.world, entity);
+    }
+  }
+  // 走る、着地する、倒れるといった少年の変化に合わせて粒を出す。動きを減らす設定では出さない
+  fn emit_particles(&mut self, was_airborne: bool, was_down: bool) {
+    if self.settings.reduced_motion {
+      return;
+    }
+    let machine = &self.boy.state_machine;
+    let boy_box = self.boy.bounding_box();
+    let running = matches!(machine, RedHatBoyStateMachine::Running(_));
+    let mut rng = thread_rng();
+    if machine.is_down() && !was_down {
+      self
+        .particles
+        .emit(&self.emitters.knock_out, center(&boy_box), &mut rng);
+    } else if running && was_airborne {
+      let feet = Vec2::new(center(&boy_box).x, boy_box.bottom());
+      self.particles.emit(&self.emitters.landing, feet, &mut rng);
+    } else if running && self.distance % DUST_INTERVAL == 0 {
+      let back_foot = Vec2::new(boy_box.x(), boy_box.bottom());
+      self
+        .particles
+        .emit(&self.emitters.dust, back_foot, &mut rng);
+    }
+  }
+  // 倒れた瞬間は画面を揺らして少しだけ止める。先読みは走る速さに合わせてゆっくり追う
+  fn move_camera(&mut self, was_down: bool) {
+    if self.settings.reduced_motion {
+      self.camera.settle();
+      return;
+    }
+    if self.boy.state_machine.is_down() && !was_down {
+      self.camera.shake(KNOCK_OUT_SHAKE);
+      self.camera.hit_stop(HIT_STOP_FRAMES);
+    }
+    let lead = (CAMERA_LEAD * self.boy.walking_speed() / RUNNING_SPEED).clamp(0.0, CAMERA_LEAD);
+    self.camera.update(lead, &mut thread_rng());
+  }
+  fn collect_pickups(&mut self) {
+    if self.knocked_out() {
+      return;
+    }
+    let boy_box = self.boy.bounding_box();
+    let collected: Vec<(CollectibleKind, Rect)> = self
+      .world
+      .game
+      .pickups
+      .iter()
+      .filter_map(|(entity, kind)| {
+        let bounding_box = self.world.bounding_box(entity)?;
+        bounding_box
+          .intersects(&boy_box)
+          .then_some((entity, *kind, bounding_box))
+      })
+      .collect::<Vec<_>>()
+      .into_iter()
+      .map(|(entity, kind, bounding_box)| {
+        self.world.despawn(entity);
+        (kind, bounding_box)
+      })
+      .collect();
+    if collected.is_empty() {
+      return;
+    }
+    for (kind, bounding_box) in &collected {
+      if !self.settings.reduced_motion {
+        self.particles.emit(
+          &self.emitters.sparkle,
+          center(bounding_box),
+          &mut thread_rng(),
+        );
+      }
+      self.pickups += kind.points();
+      if let CollectibleKind::PowerUp(power_up) = kind {
+        self.boy.power_up(*power_up);
+      }
+      let pan = (bounding_box.x() / WIDTH * 2.0 - 1.0) * STEREO_WIDTH;
+      if let Err(err) = self.audio.play_sound(&self.pickup_sound, pan) {
+        log!("Error playing sound {:#?}", err);
+      }
+    }
+    self.show_score();
+  }
+  fn show_score(&mut self) {
+    if let Err(err) = self
+      .hud
+      .set_text(SCORE_ID, format!("Score: {}", self.score()))
+    {
+      error!("Error updating the score {:#?}", err);
+    }
+    let power_ups = power_up_text(&self.boy.effects());
+    if let Err(err) = self.power_up_hud.set_text(POWER_UPS_ID, power_ups) {
+      error!("Error updating the power-ups {:#?}", err);
+    }
+    let lives = lives_text(self.settings.lives_mode, self.lives);
+    if let Err(err) = self.lives_hud.set_text(LIVES_ID, lives) {
+      error!("Error updating the lives {:#?}", err);
+    }
+  }
+  fn toggle_mute(&mut self) {
+    // ミュート中は BGM を一時停止し、解除したら続きから再生する
+    let result = self.audio.toggle_mute().and_then(|muted| {
+      self.settings.muted = muted;
+      self.settings.save();
+      if muted {
+        self.music.pause()
+      } else {
+        self.music.resume()
+      }
+    });
+    if let Err(err) = result {
+      error!("Error toggling mute {:#?}", err);
+    }
+  }
+  fn change_settings(&mut self, change: impl FnOnce(&mut Settings)) {
+    change(&mut self.settings);
+    self.settings.save();
+    if let Err(err) = apply_volumes(&self.audio, &self.settings) {
+      error!("Error applying the volume settings {:#?}", err);
+    }
+  }
+  // AudioContext の再開は engine がイベントの中で済ませているので、ここでは案内を消して止めておいた曲を鳴らす
+  fn unlock_audio(&mut self) {
+    if self.sound_prompt.take().is_none() {
+      return;
+    }
+    if self.audio.is_muted() {
+      return;
+    }
+    if let Err(err) = self.music.resume() {
+      error!("Error resuming music {:#?}", err);
+    }
+  }
+  fn play_track(&mut self, track: Track) {
+    let sound = self.tracks.sound(track);
+    let result = if self.audio.is_muted() {
+      // ミュート中は鳴らさず、解除時に resume で再生されるようにしておく
+      self.music.cue(sound)
+    } else if matches!(track, Track::GameOver) {
+      // ぶつかった瞬間にフェードせず曲を切り替える
+      self.music.play(sound)
+    } else {
+      self.music.crossfade_to(sound, MUSIC_FADE_SECONDS)
+    };
+    if let Err(err) = result {
+      error!("Error playing music {:#?}", err);
+    }
+  }
   fn reset(walk: Self) -> Self {
-    let starting_obstacles = stone_and_platform(walk.stone.clone(), walk.obstacle_sheet.clone(), 0);
-    let timeline = rightmost(&starting_obstacles);
-    Walk {
+    let mut world = World::new();
+    let starting_obstacles = create_segment(
+      &mut world,
+      Segment::StoneAndPlatform,
+      walk.stone.clone(),
+      walk.sign.clone(),
+      walk.obstacle_sheet.clone(),
+      walk.enemy_sheet.clone(),
+      0.0,
+    );
+    create_collectibles(
+      &mut world,
+      Segment::StoneAndPlatform,
+      walk.pickup_sheet.clone(),
+      0.0,
+      None,
+    );
+    let timeline = rightmost(&world, &starting_obstacles);
+    let mut walk = Walk {
       boy: RedHatBoy::reset(walk.boy),
       backgrounds: walk.backgrounds,
-      obstacles: starting_obstacles,
+      ground: walk.ground,
+      world,
+      particles: ParticleSystem::new(PARTICLE_BUDGET),
+      emitters: walk.emitters,
+      camera: Camera::new(CAMERA),
       obstacle_sheet: walk.obstacle_sheet,
+      enemy_sheet: walk.enemy_sheet,
       stone: walk.stone,
+      sign: walk.sign,
+      pickup_sheet: walk.pickup_sheet,
+      pickup_sound: walk.pickup_sound,
       timeline,
-      score: 0,
-    }
+      carry: 0.0,
+      distance: 0,
+      pickups: 0,
+      stomps: 0,
+      lives: LIVES,
+      hud: walk.hud,
+      power_up_hud: walk.power_up_hud,
+      lives_hud: walk.lives_hud,
+      high_scores: walk.high_scores,
+      settings: walk.settings,
+      sound_prompt: walk.sound_prompt,
+      audio: walk.audio,
+      music: walk.music,
+      tracks: walk.tracks,
+    };
+    walk.show_score();
+    walk
   }
 }
 
 #[async_trait(?Send)]
 impl Game for WalkTheDog {
+  fn display(&self) -> DisplayConfig {
+    DISPLAY
+  }
+  fn show_fps(&self) -> bool {
+    self
+      .machine
+      .as_ref()
+      .is_some_and(|machine| machine.walk().settings.show_fps)
+  }
   async fn initialize(&self) -> Result<Box<dyn Game>> {
     match self.machine {
       None => {
         let json = browser::fetch_json("rhb.json").await?;
         let background = engine::load_image("BG.png").await?;
         let stone = engine::load_image("Stone.png").await?;
+        let sign = engine::load_image("Sign.png").await?;
         let tiles = browser::fetch_json("tiles.json").await?;
         let sprite_sheet: Rc<SpriteSheet> = Rc::new(
           SpriteSheet::new(
@@ -303,38 +1115,139 @@ impl Game for WalkTheDog {
           )
           .await,
         );
+        let pickup_sheet = Rc::new(
+          SpriteSheet::new(
+            browser::fetch_json("pickups.json")
+              .await?
+              .into_serde::<Sheet>()?,
+            engine::load_image("pickups.png").await?,
+          )
+          .await,
+        );
+        let enemy_sheet = Rc::new(
+          SpriteSheet::new(
+            browser::fetch_json("enemies.json")
+              .await?
+              .into_serde::<Sheet>()?,
+            engine::load_image("enemies.png").await?,
+          )
+          .await,
+        );
         let audio = Audio::new()?;
-        let sound = audio.load_sound("SFX_Jump_23.mp3").await?;
-        let background_music = audio.load_sound("background_song.mp3").await?;
-
-        audio.play_looping_sound(&background_music)?;
+        let pickup_sound = audio
+          .load_sound("SFX_Coin.wav")
+          .await?
+          .with_volume(PICKUP_SOUND_VOLUME)
+          .with_max_voices(3)
+          .with_pitch_variation(30.0);
+        let sounds = RedHatBoySounds {
+          jump: audio
+            .load_sound("SFX_Jump_23.mp3")
+            .await?
+            .with_volume(JUMP_SOUND_VOLUME)
+            .with_max_voices(2)
+            .with_pitch_variation(50.0),
+          land: audio
+            .load_sound("SFX_Land.wav")
+            .await?
+            .with_max_voices(2)
+            .with_pitch_variation(150.0),
+          slide: audio
+            .load_sound("SFX_Slide.wav")
+            .await?
+            .with_volume(SLIDE_SOUND_VOLUME)
+            .with_max_voices(1),
+          knock_out: audio
+            .load_sound("SFX_KnockOut.wav")
+            .await?
+            .with_max_voices(1),
+        };
+        let tracks = MusicTracks {
+          ready: audio.load_music("ready_song.wav").await?,
+          walking: audio.load_music("background_song.mp3").await?,
+          game_over: audio.load_music("game_over_song.wav").await?,
+        };
+        let settings = Settings::load();
+        audio.set_muted(settings.muted)?;
+        apply_volumes(&audio, &settings)?;
+        let sound_prompt = if audio.is_suspended() {
+          Some(Screen::show(Widget::label(
+            SOUND_PROMPT_ID,
+            "Click to enable sound",
+          ))?)
+        } else {
+          None
+        };
+        let hud = Screen::show(Widget::label(SCORE_ID, "Score: 0"))?;
+        let power_up_hud = Screen::show(Widget::label(POWER_UPS_ID, ""))?;
+        let lives_hud = Screen::show(Widget::label(
+          LIVES_ID,
+          lives_text(settings.lives_mode, LIVES),
+        ))?;
 
         let rhb = RedHatBoy::new(
           json.into_serde::<Sheet>()?,
-          engine::load_image("rhb.png").await?,
-          audio,
-          sound,
+          Rc::new(engine::load_image("rhb.png").await?),
+          Rc::new(audio.clone()),
+          sounds,
+        );
+        let background_width = background.width() as f32;
+        let mut world = World::new();
+        let starting_obstacles = create_segment(
+          &mut world,
+          Segment::StoneAndPlatform,
+          stone.clone(),
+          sign.clone(),
+          sprite_sheet.clone(),
+          enemy_sheet.clone(),
+          0.0,
+        );
+        create_collectibles(
+          &mut world,
+          Segment::StoneAndPlatform,
+          pickup_sheet.clone(),
+          0.0,
+          None,
         );
-        let background_width = background.width() as i16;
-        let starting_obstacles = stone_and_platform(stone.clone(), sprite_sheet.clone(), 0);
-        let timeline = rightmost(&starting_obstacles);
+        let timeline = rightmost(&world, &starting_obstacles);
         let machine = WalkTheDogStateMachine::new(Walk {
           boy: rhb,
           backgrounds: [
-            Image::new(background.clone(), Point { x: 0, y: 0 }),
+            Image::new(background.clone(), Vec2::ZERO),
             Image::new(
               background,
-              Point {
+              Vec2 {
                 x: background_width,
-                y: 0,
+                y: 0.0,
               },
             ),
           ],
-          obstacles: starting_obstacles,
+          ground: Ground::new(sprite_sheet.clone()),
+          world,
+          particles: ParticleSystem::new(PARTICLE_BUDGET),
+          emitters: Emitters::new(&pickup_sheet),
+          camera: Camera::new(CAMERA),
           obstacle_sheet: sprite_sheet,
+          enemy_sheet,
           stone,
+          sign,
+          pickup_sheet,
+          pickup_sound,
           timeline,
-          score: 0,
+          carry: 0.0,
+          distance: 0,
+          pickups: 0,
+          stomps: 0,
+          lives: LIVES,
+          hud,
+          power_up_hud,
+          lives_hud,
+          high_scores: HighScores::load(),
+          settings,
+          sound_prompt,
+          music: MusicPlayer::new(audio.clone()),
+          audio,
+          tracks,
         });
         Ok(Box::new(WalkTheDog {
           machine: Some(machine),
@@ -351,9 +1264,9 @@ impl Game for WalkTheDog {
   }
   fn draw(&self, renderer: &Renderer) {
     renderer.clear(&Rect {
-      position: Point { x: 0, y: 0 },
-      width: HEIGHT,
-      height: 600,
+      position: Vec2::ZERO,
+      width: WIDTH,
+      height: HEIGHT,
     });
     if let Some(machine) = &self.machine {
       machine.draw(renderer);
@@ -364,60 +1277,35 @@ impl Game for WalkTheDog {
 pub struct RedHatBoy {
   state_machine: RedHatBoyStateMachine,
   sprite_sheet: Sheet,
-  image: HtmlImageElement,
+  image: Rc<dyn ImageSource>,
 }
 impl RedHatBoy {
-  fn new(sheet: Sheet, image: HtmlImageElement, audio: Audio, sound: Sound) -> Self {
+  fn new(sheet: Sheet, image: Rc<dyn ImageSource>, audio: Rc<dyn AudioBackend>, sounds: RedHatBoySounds) -> Self {
     RedHatBoy {
-      state_machine: RedHatBoyStateMachine::Idle(RedHatBoyState::new(audio, sound)),
+      state_machine: RedHatBoyStateMachine::new(audio, sounds),
       sprite_sheet: sheet,
       image,
     }
   }
-  fn frame_name(&self) -> String {
-    format!(
-      "{} ({}).png",
-      self.state_machine.frame_name(),
-      (self.state_machine.context().frame / 3) + 1
-    )
-  }
-  fn current_sprite(&self) -> Option<&Cell> {
-    self.sprite_sheet.frames.get(&self.frame_name())
-  }
-  fn destination_box(&self) -> Rect {
-    let sprite = self.current_sprite().expect("Cell not found");
-    Rect::new_from_x_y(
-      (self.state_machine.context().position.x + sprite.sprite_source_size.x).into(),
-      (self.state_machine.context().position.y + sprite.sprite_source_size.y).into(),
-      sprite.frame.w.into(),
-      sprite.frame.h.into(),
-    )
-  }
   fn bounding_box(&self) -> Rect {
-    const X_OFFSET: i16 = 18;
-    const Y_OFFSET: i16 = 14;
-    const WIDTH_OFFFSET: i16 = 28;
-    let mut bounding_box = self.destination_box();
-    bounding_box.position.x += X_OFFSET;
-    bounding_box.position.y += Y_OFFSET;
-    bounding_box.width -= WIDTH_OFFFSET;
-    bounding_box.height -= Y_OFFSET;
-    bounding_box
+    self.state_machine.bounding_box(&self.sprite_sheet)
   }
 
   fn draw(&self, renderer: &Renderer) {
-    let sprite = self.current_sprite().expect("Cell not found");
-
-    renderer.draw_image(
-      &self.image,
-      &&Rect::new_from_x_y(
-        sprite.frame.x.into(),
-        sprite.frame.y.into(),
-        sprite.frame.w.into(),
-        sprite.frame.h.into(),
-      ),
-      &self.destination_box(),
-    );
+    let sprite = self
+      .state_machine
+      .current_sprite(&self.sprite_sheet)
+      .expect("Cell not found");
+    let frame = Rect::from(&sprite.frame);
+    let destination = self.state_machine.destination_box(&self.sprite_sheet);
+
+    self.image.draw(renderer, &frame, &destination);
+    // 同じ絵を足し合わせて、少年の形のまま明るく光らせる
+    if self.effects().is_flashing() {
+      renderer.with_blend(Blend::Lighter, |renderer| {
+        self.image.draw(renderer, &frame, &destination)
+      });
+    }
   }
   fn update(&mut self) {
     self.state_machine = self.state_machine.clone().update();
@@ -431,19 +1319,27 @@ impl RedHatBoy {
   fn jump(&mut self) {
     self.state_machine = self.state_machine.clone().transition(Event::Jump);
   }
-  fn knock_out(&mut self) {
-    self.state_machine = self.state_machine.clone().transition(Event::KnockOut);
+  fn air_jump(&mut self) {
+    self.state_machine = self.state_machine.clone().transition(Event::AirJump);
+  }
+  fn power_up(&mut self, power_up: PowerUp) {
+    self.state_machine = self
+      .state_machine
+      .clone()
+      .transition(Event::PowerUp(power_up));
   }
-  fn land_on(&mut self, position: i16) {
-    self.state_machine = self.state_machine.clone().transition(Event::Land(position));
+  fn effects(&self) -> Effects {
+    self.state_machine.context().effects
   }
-  fn pos_y(&self) -> i16 {
-    self.state_machine.context().position.y
+  fn respawn(&mut self) {
+    self.state_machine = self.state_machine.clone().transition(Event::Respawn);
   }
-  fn velocity_y(&self) -> i16 {
-    self.state_machine.context().velocity.y
+  fn collide(&mut self, collision: Option<Event>) {
+    if let Some(event) = collision {
+      self.state_machine = self.state_machine.clone().transition(event);
+    }
   }
-  fn walking_speed(&self) -> i16 {
+  fn walking_speed(&self) -> f32 {
     self.state_machine.context().velocity.x
   }
   fn knocked_out(&self) -> bool {
@@ -454,31 +1350,134 @@ impl RedHatBoy {
       boy.sprite_sheet,
       boy.image,
       boy.state_machine.context().audio.clone(),
-      boy.state_machine.context().jump_sound.clone(),
+      boy.state_machine.context().sounds.clone(),
     )
   }
 }
 
-mod red_hat_boy_states {
+pub mod red_hat_boy_states {
   use super::HEIGHT;
-  use crate::engine::{Audio, Point, Sound};
-  const FLOOR: i16 = 479;
-  const PLAYER_HEIGHT: i16 = HEIGHT - FLOOR;
-  const STARTING_POINT: i16 = -20;
+  use crate::engine::{AudioBackend, Sound, Vec2};
+  use std::rc::Rc;
+  pub(super) const FLOOR: f32 = 479.0;
+  pub(super) const PLAYER_HEIGHT: f32 = HEIGHT - FLOOR;
+  pub(super) const STARTING_POINT: f32 = -20.0;
   const IDLE_FRAMES: u8 = 29;
   const IDLE_FRAME_NAME: &str = "Idle";
   const RUN_FRAME_NAME: &str = "Run";
   const RUNNING_FRAMES: u8 = 23;
-  const RUNNING_SPEED: i16 = 4;
+  pub(super) const RUNNING_SPEED: f32 = 4.0;
   const SLIDE_FRAME_NAME: &str = "Slide";
-  const SLIDING_FRAMES: u8 = 14;
+  const SLIDING_FRAMES: u8 = 39; // 5(画像の枚数) * SLIDING_FRAME_TICKS - 1
+  pub(super) const FRAME_TICKS: u8 = 3; // 絵 1 枚を見せるフレーム数
+  pub(super) const SLIDING_FRAME_TICKS: u8 = 8; // 看板の下をくぐり抜けられるよう、スライディングはゆっくり見せる
   const JUMP_FRAME_NAME: &str = "Jump";
   const JUMPING_FRAMES: u8 = 35; // 12(画像の枚数) * 3 - 1
-  const JUMP_SPEED: i16 = -25;
-  const GRAVITY: i16 = 1;
+  pub(super) const JUMP_SPEED: f32 = -25.0;
+  const GRAVITY: f32 = 1.0;
   const FALLING_FRAMES: u8 = 29;
   const FALLING_FRAME_NAME: &str = "Dead";
-  const TERMINAL_VELOCITY: i16 = 20;
+  const TERMINAL_VELOCITY: f32 = 20.0;
+  pub(super) const STEREO_WIDTH: f32 = 0.6; // 画面の端でも完全に左右に振り切らないようにする
+  pub(super) const AIR_JUMP_SPEED: f32 = -20.0;
+  pub(super) const STOMP_BOUNCE_SPEED: f32 = -15.0;
+  pub(super) const POWER_UP_FRAMES: u16 = 600; // 10 秒
+  pub(super) const SHIELD_GRACE_FRAMES: u16 = 45; // 盾が割れたあと、同じ石にもう一度当たらないだけの時間
+  pub(super) const RESPAWN_INVULNERABLE_FRAMES: u16 = 120;
+  const FLASH_FRAMES: u16 = 4; // 光らせるときは、この間隔で点けたり消したりする
+  pub(super) const POWER_UP_WARNING_FRAMES: u16 = 120; // パワーアップが切れる 2 秒前から光らせて知らせる
+
+  #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+  pub enum PowerUp {
+    Shield,
+    DoubleJump,
+    Magnet,
+  }
+
+  impl PowerUp {
+    pub const ALL: [PowerUp; 3] = [PowerUp::Shield, PowerUp::DoubleJump, PowerUp::Magnet];
+
+    pub fn name(self) -> &'static str {
+      match self {
+        PowerUp::Shield => "Shield",
+        PowerUp::DoubleJump => "Double Jump",
+        PowerUp::Magnet => "Magnet",
+      }
+    }
+  }
+
+  // パワーアップの残りフレーム数。どれも取り直すと最初から数え直す
+  #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+  pub struct Effects {
+    shield: u16,
+    double_jump: u16,
+    magnet: u16,
+    invulnerable: u16,
+    air_jumped: bool,
+  }
+
+  impl Effects {
+    pub fn remaining(&self, power_up: PowerUp) -> u16 {
+      match power_up {
+        PowerUp::Shield => self.shield,
+        PowerUp::DoubleJump => self.double_jump,
+        PowerUp::Magnet => self.magnet,
+      }
+    }
+    pub fn is_active(&self, power_up: PowerUp) -> bool {
+      self.remaining(power_up) > 0
+    }
+    // 石にぶつかっても倒れない
+    pub fn is_protected(&self) -> bool {
+      self.shield > 0 || self.is_invulnerable()
+    }
+    // 足場の横にぶつかっても倒れない。盾では防げない
+    pub fn is_invulnerable(&self) -> bool {
+      self.invulnerable > 0
+    }
+    pub fn can_air_jump(&self) -> bool {
+      self.double_jump > 0 && !self.air_jumped
+    }
+    // 無敵の間と、パワーアップが切れそうなときに光る
+    pub fn is_flashing(&self) -> bool {
+      let flashing = |remaining: u16| remaining > 0 && (remaining / FLASH_FRAMES) % 2 == 1;
+      flashing(self.invulnerable)
+        || PowerUp::ALL
+          .iter()
+          .map(|power_up| self.remaining(*power_up))
+          .any(|remaining| remaining <= POWER_UP_WARNING_FRAMES && flashing(remaining))
+    }
+    // 走り直すときはパワーアップを失い、しばらく無敵になる
+    fn respawn() -> Self {
+      Effects {
+        invulnerable: RESPAWN_INVULNERABLE_FRAMES,
+        ..Effects::default()
+      }
+    }
+    fn grant(mut self, power_up: PowerUp) -> Self {
+      match power_up {
+        PowerUp::Shield => self.shield = POWER_UP_FRAMES,
+        PowerUp::DoubleJump => self.double_jump = POWER_UP_FRAMES,
+        PowerUp::Magnet => self.magnet = POWER_UP_FRAMES,
+      }
+      self
+    }
+    // 盾は 1 回で割れる。割れた直後はまだ石に重なっているので少しだけ無敵にする
+    fn absorb_hit(mut self) -> Self {
+      if self.shield > 0 {
+        self.shield = 0;
+        self.invulnerable = SHIELD_GRACE_FRAMES;
+      }
+      self
+    }
+    fn tick(mut self) -> Self {
+      self.shield = self.shield.saturating_sub(1);
+      self.double_jump = self.double_jump.saturating_sub(1);
+      self.magnet = self.magnet.saturating_sub(1);
+      self.invulnerable = self.invulnerable.saturating_sub(1);
+      self
+    }
+  }
 
   #[derive(Clone)]
   pub struct RedHatBoyState<S> {
@@ -493,19 +1492,55 @@ mod red_hat_boy_states {
     fn update_context(&mut self, frames: u8) {
       self.context = self.context.clone().update(frames);
     }
+    pub fn grant(mut self, power_up: PowerUp) -> Self {
+      self.context.effects = self.context.effects.grant(power_up);
+      self
+    }
+    pub fn absorb_hit(mut self) -> Self {
+      self.context.effects = self.context.effects.absorb_hit();
+      self
+    }
+    // 穴に落ちたら前には進まず、真下に落ちていく。盾があっても助からない
+    pub fn plunge(self) -> RedHatBoyState<Plunging> {
+      RedHatBoyState {
+        context: self
+          .context
+          .reset_frame()
+          .stop_running()
+          .play_knock_out_sound(),
+        _state: Plunging {},
+      }
+    }
+  }
+
+  #[derive(Clone)]
+  pub struct RedHatBoySounds {
+    pub jump: Sound,
+    pub land: Sound,
+    pub slide: Sound,
+    pub knock_out: Sound,
   }
 
   #[derive(Clone)]
   pub struct RedHatBoyContext {
     pub frame: u8,
-    pub position: Point,
-    pub velocity: Point,
-    pub audio: Audio,
-    pub jump_sound: Sound,
+    pub position: Vec2,
+    pub velocity: Vec2,
+    pub effects: Effects,
+    pub audio: Rc<dyn AudioBackend>,
+    pub sounds: RedHatBoySounds,
   }
 
   impl RedHatBoyContext {
-    pub fn update(mut self, frame_count: u8) -> Self {
+    pub fn update(self, frame_count: u8) -> Self {
+      let mut context = self.fall(frame_count);
+      if context.position.y > FLOOR {
+        context.position.y = FLOOR;
+      }
+      context
+    }
+    // 床で止まらずに落ち続ける。穴に落ちたときはこちらを使う
+    fn fall(mut self, frame_count: u8) -> Self {
       if self.velocity.y < TERMINAL_VELOCITY {
         self.velocity.y += GRAVITY;
       }
@@ -515,10 +1550,7 @@ mod red_hat_boy_states {
         self.frame = 0;
       }
       self.position.y += self.velocity.y;
-
-      if self.position.y > FLOOR {
-        self.position.y = FLOOR;
-      }
+      self.effects = self.effects.tick();
       self
     }
     fn reset_frame(mut self) -> Self {
@@ -529,24 +1561,44 @@ mod red_hat_boy_states {
       self.velocity.x = RUNNING_SPEED;
       self
     }
-    fn set_vertical_velocity(mut self, speed: i16) -> Self {
+    fn set_vertical_velocity(mut self, speed: f32) -> Self {
       self.velocity.y = speed;
       self
     }
     fn stop(mut self) -> Self {
-      self.velocity.x = 0;
-      self.velocity.y = 0;
+      self.velocity = Vec2::ZERO;
+      self
+    }
+    fn stop_running(mut self) -> Self {
+      self.velocity.x = 0.0;
       self
     }
-    fn set_on(mut self, position: i16) -> Self {
+    fn set_on(mut self, position: f32) -> Self {
       let position = position - PLAYER_HEIGHT;
       self.position.y = position;
+      self.effects.air_jumped = false;
       self
     }
-    fn play_jump_sound(self) -> Self {
-      if let Err(err) = self.audio.play_sound(&self.jump_sound) {
-        log!("Error playing jump sound {:#?}", err);
+    fn play_sound(&self, sound: &Sound) {
+      // 画面が流れても少年は左端から動かないので、自分の音は左右に振らず真ん中で鳴らす
+      if let Err(err) = self.audio.play_sound(sound, 0.0) {
+        log!("Error playing sound {:#?}", err);
       }
+    }
+    fn play_jump_sound(self) -> Self {
+      self.play_sound(&self.sounds.jump);
+      self
+    }
+    fn play_land_sound(self) -> Self {
+      self.play_sound(&self.sounds.land);
+      self
+    }
+    fn play_slide_sound(self) -> Self {
+      self.play_sound(&self.sounds.slide);
+      self
+    }
+    fn play_knock_out_sound(self) -> Self {
+      self.play_sound(&self.sounds.knock_out);
       self
     }
   }
@@ -563,21 +1615,25 @@ mod red_hat_boy_states {
   #[derive(Copy, Clone)]
   pub struct Falling;
 
+  #[derive(Copy, Clone)]
+  pub struct Plunging;
+
   #[derive(Copy, Clone)]
   pub struct KnockedOut;
 
   impl RedHatBoyState<Idle> {
-    pub fn new(audio: Audio, jump_sound: Sound) -> Self {
+    pub fn new(audio: Rc<dyn AudioBackend>, sounds: RedHatBoySounds) -> Self {
       RedHatBoyState {
         context: RedHatBoyContext {
           frame: 0,
-          position: Point {
+          position: Vec2 {
             x: STARTING_POINT,
             y: FLOOR,
           },
-          velocity: Point { x: 0, y: 0 },
+          velocity: Vec2::ZERO,
+          effects: Effects::default(),
           audio,
-          jump_sound,
+          sounds,
         },
         _state: Idle {},
       }
@@ -607,7 +1663,7 @@ mod red_hat_boy_states {
     }
     pub fn slide(self) -> RedHatBoyState<Sliding> {
       RedHatBoyState {
-        context: self.context.reset_frame(),
+        context: self.context.reset_frame().play_slide_sound(),
         _state: Sliding {},
       }
     }
@@ -624,11 +1680,11 @@ mod red_hat_boy_states {
     }
     pub fn knock_out(self) -> RedHatBoyState<Falling> {
       RedHatBoyState {
-        context: self.context.reset_frame().stop(),
+        context: self.context.reset_frame().stop().play_knock_out_sound(),
         _state: Falling {},
       }
     }
-    pub fn land_on(self, position: i16) -> RedHatBoyState<Running> {
+    pub fn land_on(self, position: f32) -> RedHatBoyState<Running> {
       RedHatBoyState {
         context: self.context.set_on(position),
         _state: Running {},
@@ -656,11 +1712,11 @@ mod red_hat_boy_states {
     }
     pub fn knock_out(self) -> RedHatBoyState<Falling> {
       RedHatBoyState {
-        context: self.context.reset_frame().stop(),
+        context: self.context.reset_frame().stop().play_knock_out_sound(),
         _state: Falling {},
       }
     }
-    pub fn land_on(self, position: i16) -> RedHatBoyState<Sliding> {
+    pub fn land_on(self, position: f32) -> RedHatBoyState<Sliding> {
       RedHatBoyState {
         context: self.context.set_on(position),
         _state: Sliding {},
@@ -675,23 +1731,49 @@ mod red_hat_boy_states {
       self.context = self.context.update(JUMPING_FRAMES);
 
       if self.context.position.y >= FLOOR {
-        JumpingEndState::Landing(self.land_on(HEIGHT.into()))
+        JumpingEndState::Landing(self.land_on(HEIGHT))
       } else {
         JumpingEndState::Jumping(self)
       }
     }
-    pub fn land_on(self, position: i16) -> RedHatBoyState<Running> {
+    pub fn land_on(self, position: f32) -> RedHatBoyState<Running> {
       RedHatBoyState {
-        context: self.context.reset_frame().set_on(position),
+        context: self
+          .context
+          .reset_frame()
+          .set_on(position)
+          .play_land_sound(),
         _state: Running {},
       }
     }
-    pub fn knock_out(self) -> RedHatBoyState<Falling> {
+    // 二段ジャンプは着地するまでに 1 回だけ
+    pub fn air_jump(mut self) -> RedHatBoyState<Jumping> {
+      self.context.effects.air_jumped = true;
       RedHatBoyState {
-        context: self.context.reset_frame().stop(),
+        context: self
+          .context
+          .reset_frame()
+          .set_vertical_velocity(AIR_JUMP_SPEED)
+          .play_jump_sound(),
+        _state: Jumping {},
+      }
+    }
+    pub fn knock_out(self) -> RedHatBoyState<Falling> {
+      RedHatBoyState {
+        context: self.context.reset_frame().stop().play_knock_out_sound(),
         _state: Falling {},
       }
     }
+    // 踏んだ敵の上で跳ね返る。跳び直すわけではないのでアニメーションはそのまま続ける
+    pub fn stomp(self) -> RedHatBoyState<Jumping> {
+      RedHatBoyState {
+        context: self
+          .context
+          .set_vertical_velocity(STOMP_BOUNCE_SPEED)
+          .play_jump_sound(),
+        _state: Jumping {},
+      }
+    }
   }
   impl RedHatBoyState<Falling> {
     pub fn frame_name(&self) -> &str {
@@ -714,10 +1796,39 @@ mod red_hat_boy_states {
       }
     }
   }
+  impl RedHatBoyState<Plunging> {
+    pub fn frame_name(&self) -> &str {
+      JUMP_FRAME_NAME
+    }
+    pub fn knock_out(self) -> RedHatBoyState<KnockedOut> {
+      RedHatBoyState {
+        context: self.context,
+        _state: KnockedOut {},
+      }
+    }
+    // 画面の下に消えたら倒れたのと同じ扱いにする
+    pub fn update(mut self) -> PlungingEndState {
+      self.context = self.context.fall(JUMPING_FRAMES);
+      if self.context.position.y > HEIGHT {
+        PlungingEndState::KnockedOut(self.knock_out())
+      } else {
+        PlungingEndState::Plunging(self)
+      }
+    }
+  }
   impl RedHatBoyState<KnockedOut> {
     pub fn frame_name(&self) -> &str {
       FALLING_FRAME_NAME
     }
+    // 足場の上で倒れていても床から走り直す
+    pub fn respawn(mut self) -> RedHatBoyState<Running> {
+      self.context.effects = Effects::respawn();
+      self.context.position.y = FLOOR;
+      RedHatBoyState {
+        context: self.context.reset_frame().stop().run_right(),
+        _state: Running {},
+      }
+    }
   }
   pub enum SlidingEndState {
     Complete(RedHatBoyState<Running>),
@@ -731,28 +1842,49 @@ mod red_hat_boy_states {
     KnockedOut(RedHatBoyState<KnockedOut>),
     Falling(RedHatBoyState<Falling>),
   }
+  pub enum PlungingEndState {
+    KnockedOut(RedHatBoyState<KnockedOut>),
+    Plunging(RedHatBoyState<Plunging>),
+  }
 }
 
+#[derive(Clone, Copy, Debug)]
 pub enum Event {
   Run,
   Slide,
   Jump,
+  // 空中でジャンプを押し直した
+  AirJump,
+  // 石にぶつかった。盾があれば防げる
+  Hit,
   KnockOut,
   Update,
-  Land(i16),
+  Land(f32),
+  PowerUp(PowerUp),
+  // 残機を使って倒れたところから走り直す
+  Respawn,
+  // 敵を上から踏んだ
+  Stomp,
+  // 地面の穴の上に出た
+  Plunge,
 }
 #[derive(Clone)]
-enum RedHatBoyStateMachine {
+pub enum RedHatBoyStateMachine {
   Idle(RedHatBoyState<Idle>),
   Running(RedHatBoyState<Running>),
   Sliding(RedHatBoyState<Sliding>),
   Jumping(RedHatBoyState<Jumping>),
   Falling(RedHatBoyState<Falling>),
+  Plunging(RedHatBoyState<Plunging>),
   KnockedOut(RedHatBoyState<KnockedOut>),
 }
 impl RedHatBoyStateMachine {
-  fn transition(self, event: Event) -> Self {
-    match (self.clone(), event) {
+  // 画像を読み込まなくても物理だけを動かせるように、音は渡された AudioBackend で鳴らす
+  pub fn new(audio: Rc<dyn AudioBackend>, sounds: RedHatBoySounds) -> Self {
+    RedHatBoyStateMachine::Idle(RedHatBoyState::new(audio, sounds))
+  }
+  pub fn transition(self, event: Event) -> Self {
+    match (self, event) {
       (RedHatBoyStateMachine::Idle(state), Event::Run) => state.run().into(),
       (RedHatBoyStateMachine::Running(state), Event::Slide) => state.slide().into(),
       (RedHatBoyStateMachine::Idle(state), Event::Update) => state.update().into(),
@@ -760,6 +1892,20 @@ impl RedHatBoyStateMachine {
       (RedHatBoyStateMachine::Sliding(state), Event::Update) => state.update().into(),
       (RedHatBoyStateMachine::Running(state), Event::Jump) => state.jump().into(),
       (RedHatBoyStateMachine::Jumping(state), Event::Update) => state.update().into(),
+      (RedHatBoyStateMachine::Jumping(state), Event::AirJump) if state.context().effects.can_air_jump() => state.air_jump().into(),
+      (RedHatBoyStateMachine::Running(state), Event::Hit) if state.context().effects.is_protected() => state.absorb_hit().into(),
+      (RedHatBoyStateMachine::Sliding(state), Event::Hit) if state.context().effects.is_protected() => state.absorb_hit().into(),
+      (RedHatBoyStateMachine::Jumping(state), Event::Hit) if state.context().effects.is_protected() => state.absorb_hit().into(),
+      (RedHatBoyStateMachine::Running(state), Event::Hit) => state.knock_out().into(),
+      (RedHatBoyStateMachine::Sliding(state), Event::Hit) => state.knock_out().into(),
+      (RedHatBoyStateMachine::Jumping(state), Event::Hit) => state.knock_out().into(),
+      (RedHatBoyStateMachine::Idle(state), Event::PowerUp(power_up)) => state.grant(power_up).into(),
+      (RedHatBoyStateMachine::Running(state), Event::PowerUp(power_up)) => state.grant(power_up).into(),
+      (RedHatBoyStateMachine::Sliding(state), Event::PowerUp(power_up)) => state.grant(power_up).into(),
+      (RedHatBoyStateMachine::Jumping(state), Event::PowerUp(power_up)) => state.grant(power_up).into(),
+      (RedHatBoyStateMachine::Sliding(state), Event::KnockOut) if state.context().effects.is_invulnerable() => state.into(),
+      (RedHatBoyStateMachine::Jumping(state), Event::KnockOut) if state.context().effects.is_invulnerable() => state.into(),
+      (RedHatBoyStateMachine::Running(state), Event::KnockOut) if state.context().effects.is_invulnerable() => state.into(),
       (RedHatBoyStateMachine::Sliding(state), Event::KnockOut) => state.knock_out().into(),
       (RedHatBoyStateMachine::Jumping(state), Event::KnockOut) => state.knock_out().into(),
       (RedHatBoyStateMachine::Running(state), Event::KnockOut) => state.knock_out().into(),
@@ -767,7 +1913,72 @@ impl RedHatBoyStateMachine {
       (RedHatBoyStateMachine::Jumping(state), Event::Land(position)) => state.land_on(position).into(),
       (RedHatBoyStateMachine::Running(state), Event::Land(position)) => state.land_on(position).into(),
       (RedHatBoyStateMachine::Sliding(state), Event::Land(position)) => state.land_on(position).into(),
-      _ => self,
+      (RedHatBoyStateMachine::KnockedOut(state), Event::Respawn) => state.respawn().into(),
+      (RedHatBoyStateMachine::Jumping(state), Event::Stomp) => state.stomp().into(),
+      (RedHatBoyStateMachine::Running(state), Event::Plunge) => state.plunge().into(),
+      (RedHatBoyStateMachine::Sliding(state), Event::Plunge) => state.plunge().into(),
+      (RedHatBoyStateMachine::Jumping(state), Event::Plunge) => state.plunge().into(),
+      (RedHatBoyStateMachine::Plunging(state), Event::Update) => state.update().into(),
+      // ここから下は状態が変わらない組み合わせ。
+      // イベントや状態を追加したときに対応漏れがコンパイルエラーになるよう、ワイルドカードは使わない
+      // 走り出すまでは Run 以外を受け付けない。障害物も Walking になるまで当たらない
+      (
+        machine @ RedHatBoyStateMachine::Idle(_),
+        Event::Slide | Event::Jump | Event::AirJump | Event::Hit | Event::KnockOut | Event::Land(_) | Event::Respawn | Event::Stomp | Event::Plunge,
+      ) => machine,
+      // 既に走っている。二段ジャンプは地面からは出せない。敵を踏めるのは跳んでいるときだけ
+      (machine @ RedHatBoyStateMachine::Running(_), Event::Run | Event::AirJump | Event::Respawn | Event::Stomp) => machine,
+      // スライディングが終わって立ち上がるまでは操作できない
+      (machine @ RedHatBoyStateMachine::Sliding(_), Event::Run | Event::Slide | Event::Jump | Event::AirJump | Event::Respawn | Event::Stomp) => {
+        machine
+      }
+      // 空中では二段ジャンプ以外の操作はできない
+      (machine @ RedHatBoyStateMachine::Jumping(_), Event::Run | Event::Slide | Event::Jump | Event::AirJump | Event::Respawn) => machine,
+      // 倒れている最中は Dead のアニメーションを進めるだけ。パワーアップも受け取らず、倒れきるまでは走り直せない
+      (
+        machine @ RedHatBoyStateMachine::Falling(_),
+        Event::Run
+        | Event::Slide
+        | Event::Jump
+        | Event::AirJump
+        | Event::Hit
+        | Event::KnockOut
+        | Event::Land(_)
+        | Event::PowerUp(_)
+        | Event::Respawn
+        | Event::Stomp
+        | Event::Plunge,
+      ) => machine,
+      // 穴に落ちたら画面の外に出るまで落ちるだけ。石や敵にも当たらず、足場にも乗れない
+      (
+        machine @ RedHatBoyStateMachine::Plunging(_),
+        Event::Run
+        | Event::Slide
+        | Event::Jump
+        | Event::AirJump
+        | Event::Hit
+        | Event::KnockOut
+        | Event::Land(_)
+        | Event::PowerUp(_)
+        | Event::Respawn
+        | Event::Stomp
+        | Event::Plunge,
+      ) => machine,
+      // 倒れきったら走り直す以外は何も受け付けない
+      (
+        machine @ RedHatBoyStateMachine::KnockedOut(_),
+        Event::Run
+        | Event::Slide
+        | Event::Jump
+        | Event::AirJump
+        | Event::Hit
+        | Event::KnockOut
+        | Event::Update
+        | Event::Land(_)
+        | Event::PowerUp(_)
+        | Event::Stomp
+        | Event::Plunge,
+      ) => machine,
     }
   }
   fn frame_name(&self) -> &str {
@@ -777,27 +1988,103 @@ impl RedHatBoyStateMachine {
       RedHatBoyStateMachine::Sliding(state) => state.frame_name(),
       RedHatBoyStateMachine::Jumping(state) => state.frame_name(),
       RedHatBoyStateMachine::Falling(state) => state.frame_name(),
+      RedHatBoyStateMachine::Plunging(state) => state.frame_name(),
       RedHatBoyStateMachine::KnockedOut(state) => state.frame_name(),
     }
   }
-  fn context(&self) -> &RedHatBoyContext {
+  pub fn context(&self) -> &RedHatBoyContext {
     match self {
-      RedHatBoyStateMachine::Idle(state) => &state.context(),
-      RedHatBoyStateMachine::Running(state) => &state.context(),
-      RedHatBoyStateMachine::Sliding(state) => &state.context(),
-      RedHatBoyStateMachine::Jumping(state) => &state.context(),
-      RedHatBoyStateMachine::Falling(state) => &state.context(),
-      RedHatBoyStateMachine::KnockedOut(state) => &state.context(),
+      RedHatBoyStateMachine::Idle(state) => state.context(),
+      RedHatBoyStateMachine::Running(state) => state.context(),
+      RedHatBoyStateMachine::Sliding(state) => state.context(),
+      RedHatBoyStateMachine::Jumping(state) => state.context(),
+      RedHatBoyStateMachine::Falling(state) => state.context(),
+      RedHatBoyStateMachine::Plunging(state) => state.context(),
+      RedHatBoyStateMachine::KnockedOut(state) => state.context(),
     }
   }
 
-  fn update(self) -> Self {
+  pub fn update(self) -> Self {
     self.transition(Event::Update)
   }
   fn knocked_out(&self) -> bool {
     matches!(self, RedHatBoyStateMachine::KnockedOut(_))
   }
+  // 障害物にぶつかって倒れ始めたら、もう走り続けられない
+  pub fn is_down(&self) -> bool {
+    matches!(
+      self,
+      RedHatBoyStateMachine::Falling(_) | RedHatBoyStateMachine::Plunging(_) | RedHatBoyStateMachine::KnockedOut(_)
+    )
+  }
+  fn frame_ticks(&self) -> u8 {
+    match self {
+      RedHatBoyStateMachine::Sliding(_) => SLIDING_FRAME_TICKS,
+      RedHatBoyStateMachine::Idle(_)
+      | RedHatBoyStateMachine::Running(_)
+      | RedHatBoyStateMachine::Jumping(_)
+      | RedHatBoyStateMachine::Falling(_)
+      | RedHatBoyStateMachine::Plunging(_)
+      | RedHatBoyStateMachine::KnockedOut(_) => FRAME_TICKS,
+    }
+  }
+  // スライディング中は頭を下げているので、帽子と頭の分だけ当たり判定を低くする
+  fn hitbox(&self) -> Hitbox {
+    match self {
+      RedHatBoyStateMachine::Sliding(_) => SLIDING_HITBOX,
+      RedHatBoyStateMachine::Idle(_)
+      | RedHatBoyStateMachine::Running(_)
+      | RedHatBoyStateMachine::Jumping(_)
+      | RedHatBoyStateMachine::Falling(_)
+      | RedHatBoyStateMachine::Plunging(_)
+      | RedHatBoyStateMachine::KnockedOut(_) => STANDING_HITBOX,
+    }
+  }
+  fn sprite_name(&self) -> String {
+    format!(
+      "{} ({}).png",
+      self.frame_name(),
+      (self.context().frame / self.frame_ticks()) + 1
+    )
+  }
+  fn current_sprite<'a>(&self, sheet: &'a Sheet) -> Option<&'a Cell> {
+    sheet.frames.get(&self.sprite_name())
+  }
+  fn destination_box(&self, sheet: &Sheet) -> Rect {
+    let sprite = self.current_sprite(sheet).expect("Cell not found");
+    Rect::new(
+      self.context().position + sprite.sprite_source_size.position(),
+      sprite.frame.w.into(),
+      sprite.frame.h.into(),
+    )
+  }
+  pub fn bounding_box(&self, sheet: &Sheet) -> Rect {
+    let hitbox = self.hitbox();
+    let mut bounding_box = self.destination_box(sheet);
+    bounding_box.position.x += hitbox.left;
+    bounding_box.position.y += hitbox.top;
+    bounding_box.width -= hitbox.left + hitbox.right;
+    bounding_box.height -= hitbox.top;
+    bounding_box
+  }
 }
+
+// 絵の枠から内側に削る量。足元は削らないので、着地の高さは絵と同じになる
+struct Hitbox {
+  left: f32,
+  top: f32,
+  right: f32,
+}
+const STANDING_HITBOX: Hitbox = Hitbox {
+  left: 18.0,
+  top: 14.0,
+  right: 10.0,
+};
+const SLIDING_HITBOX: Hitbox = Hitbox {
+  left: 12.0,
+  top: 30.0,
+  right: 16.0,
+};
 impl From<RedHatBoyState<Running>> for RedHatBoyStateMachine {
   fn from(state: RedHatBoyState<Running>) -> Self {
     RedHatBoyStateMachine::Running(state)
@@ -826,6 +2113,11 @@ impl From<RedHatBoyState<Falling>> for RedHatBoyStateMachine {
     RedHatBoyStateMachine::Falling(state)
   }
 }
+impl From<RedHatBoyState<Plunging>> for RedHatBoyStateMachine {
+  fn from(state: RedHatBoyState<Plunging>) -> Self {
+    RedHatBoyStateMachine::Plunging(state)
+  }
+}
 impl From<RedHatBoyState<KnockedOut>> for RedHatBoyStateMachine {
   fn from(state: RedHatBoyState<KnockedOut>) -> Self {
     RedHatBoyStateMachine::KnockedOut(state)
@@ -849,6 +2141,14 @@ impl From<JumpingEndState> for RedHatBoyStateMachine {
     }
   }
 }
+impl From<PlungingEndState> for RedHatBoyStateMachine {
+  fn from(state: PlungingEndState) -> Self {
+    match state {
+      PlungingEndState::Plunging(plunging) => plunging.into(),
+      PlungingEndState::KnockedOut(knocked_out) => knocked_out.into(),
+    }
+  }
+}
 impl From<FallingEndState> for RedHatBoyStateMachine {
   fn from(state: FallingEndState) -> Self {
     match state {
@@ -858,129 +2158,1374 @@ impl From<FallingEndState> for RedHatBoyStateMachine {
   }
 }
 
-pub struct Platform {
-  sheet: Rc<SpriteSheet>,
-  bounding_boxes: Vec<Rect>,
-  sprites: Vec<Cell>,
-  position: Point,
+// 崩れる足場の状態。乗られると揺れ始め、落ち始めたら速度に重力を足していく
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Footing {
+  Solid,
+  Cracking(u16),
+  Falling,
 }
 
-impl Platform {
-  pub fn new(sheet: Rc<SpriteSheet>, position: Point, sprite_names: &[&str], bounding_boxs: &[Rect]) -> Self {
-    let sprites = sprite_names
-      .iter()
-      .filter_map(|name| sheet.cell(name).cloned())
-      .collect();
-    let bounding_boxes = bounding_boxs
+// 少年が触れたときの当たり方
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Contact {
+  // 触れたら倒れる。石や看板
+  Barrier,
+  // 上から乗れて、横や下から当たったら倒れる
+  Platform,
+  // 上から踏めば潰れて、それ以外で触れたら倒れる
+  Enemy,
+  // 地面を走っていると落ちる
+  Pit,
+}
+
+// 動きが無いときの位置からのずれ方。足場も敵も、画面上の位置でずれが決まる
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Path {
+  Platform(PlatformMotion),
+  Enemy(EnemyBehaviour),
+}
+
+impl Path {
+  fn offset(self, x: f32) -> Vec2 {
+    match self {
+      Path::Platform(motion) => motion.offset(x),
+      Path::Enemy(behaviour) => behaviour.offset(x),
+    }
+  }
+}
+
+// 道筋と、今どれだけずれているか。ずれの変化を速度にして World に動かしてもらう
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Motion {
+  path: Path,
+  offset: Vec2,
+}
+
+// Walk の世界のエンティティに、ゲームが足すコンポーネント
+#[derive(Default)]
+pub struct WalkComponents {
+  // 位置から見た、少年と当たる箱。colliders は動く範囲全体を覆うので、当たりにはこちらを使う
+  pub shapes: Components<Vec<Rect>>,
+  pub contacts: Components<Contact>,
+  pub motions: Components<Motion>,
+  pub footings: Components<Footing>,
+  // 少年を乗せて運べるものと、今乗せているか
+  pub carriers: Components<bool>,
+  // 踏まれたときに入る点数
+  pub points: Components<u32>,
+  pub pickups: Components<CollectibleKind>,
+}
+
+impl ComponentSet for WalkComponents {
+  fn despawn(&mut self, entity: Entity) {
+    self.shapes.remove(entity);
+    self.contacts.remove(entity);
+    self.motions.remove(entity);
+    self.footings.remove(entity);
+    self.carriers.remove(entity);
+    self.points.remove(entity);
+    self.pickups.remove(entity);
+  }
+}
+
+// 動く足場や敵の、このフレームの速度を決める。動く範囲は動きが無いときの位置に留めておく
+fn follow_paths(world: &mut World<WalkComponents>) {
+  for (entity, motion) in world.game.motions.iter_mut() {
+    let Some(position) = world.positions.get(entity) else {
+      continue;
+    };
+    let anchor = *position - motion.offset;
+    let offset = motion.path.offset(anchor.x);
+    let velocity = offset - motion.offset;
+    motion.offset = offset;
+    world.velocities.insert(entity, velocity);
+    if let Some(collider) = world.colliders.get_mut(entity) {
+      collider.position = collider.position - velocity;
+    }
+  }
+}
+
+// 乗られた足場はしばらく小刻みに揺れて知らせてから落ちる
+fn crumble(world: &mut World<WalkComponents>) {
+  for (entity, footing) in world.game.footings.iter_mut() {
+    *footing = match *footing {
+      Footing::Solid => Footing::Solid,
+      Footing::Cracking(remaining) if remaining <= 1 => {
+        world.velocities.insert(entity, Vec2::ZERO);
+        Footing::Falling
+      }
+      Footing::Cracking(remaining) => Footing::Cracking(remaining - 1),
+      Footing::Falling => {
+        if let Some(velocity) = world.velocities.get_mut(entity) {
+          velocity.y += CRUMBLE_GRAVITY;
+        }
+        Footing::Falling
+      }
+    };
+    let shake = match *footing {
+      Footing::Cracking(remaining) if remaining % 4 < 2 => CRUMBLE_SHAKE,
+      Footing::Cracking(_) => -CRUMBLE_SHAKE,
+      Footing::Solid | Footing::Falling => 0.0,
+    };
+    world.draw_offsets.insert(entity, Vec2::new(shake, 0.0));
+  }
+}
+
+// 少年と触れているものを調べて少年の状態を変える。踏まれたものを返す
+fn touch(world: &mut World<WalkComponents>, boy: &mut RedHatBoy) -> Vec<Entity> {
+  let WalkComponents {
+    shapes,
+    contacts,
+    footings,
+    carriers,
+    ..
+  } = &mut world.game;
+  let mut stomped = vec![];
+  for (entity, contact) in contacts.iter() {
+    let (Some(position), Some(shapes)) = (world.positions.get(entity), shapes.get(entity)) else {
+      continue;
+    };
+    let boxes: Vec<Rect> = shapes
       .iter()
-      .map(|bounding_box| {
-        Rect::new_from_x_y(
-          bounding_box.x() + position.x,
-          bounding_box.y() + position.y,
-          bounding_box.width,
-          bounding_box.height,
-        )
-      })
+      .map(|shape| Rect::new(*position + shape.position, shape.width, shape.height))
       .collect();
-    Platform {
-      sheet,
-      position,
-      sprites,
-      bounding_boxes,
+    let Some(first) = boxes.first() else {
+      continue;
+    };
+    let boy_box = boy.bounding_box();
+    let collision = match contact {
+      Contact::Barrier => barrier_collision(&boy_box, first),
+      Contact::Platform => {
+        // 落ち始めた足場にはもう乗れない
+        let collision = if footings.get(entity) == Some(&Footing::Falling) {
+          None
+        } else {
+          platform_collision(&boy.state_machine, &boy_box, position.y, &boxes)
+        };
+        let landed = matches!(collision, Some(Event::Land(_)));
+        if let Some(footing @ Footing::Solid) = footings.get_mut(entity) {
+          if landed {
+            *footing = Footing::Cracking(CRUMBLE_FRAMES);
+          }
+        }
+        if let Some(carrying) = carriers.get_mut(entity) {
+          *carrying = landed;
+        }
+        collision
+      }
+      Contact::Enemy => enemy_collision(&boy.state_machine, &boy_box, first),
+      Contact::Pit => pit_collision(&boy.state_machine, &boy_box, first.x(), first.right()),
+    };
+    if matches!(collision, Some(Event::Stomp)) {
+      stomped.push(entity);
     }
+    boy.collide(collision);
   }
+  stomped
+}
 
-  fn bounding_boxes(&self) -> &Vec<Rect> {
-    &self.bounding_boxes
+// 少年を乗せている足場がこのフレームに横に動いた量。Walk はこの分だけ余計にスクロールさせて、少年を足場と一緒に動かす
+fn carry(world: &World<WalkComponents>) -> f32 {
+  world
+    .game
+    .carriers
+    .iter()
+    .filter(|(_, carrying)| **carrying)
+    .filter_map(|(entity, _)| world.velocities.get(entity))
+    .map(|velocity| velocity.x)
+    .sum()
+}
+
+// 踏まれたものは動きを止め、足元を残して高さが半分の姿でしばらく残ってから消える
+fn squash(world: &mut World<WalkComponents>, entity: Entity) {
+  world.game.contacts.remove(entity);
+  world.game.motions.remove(entity);
+  world.velocities.remove(entity);
+  if let Some(sprite) = world.sprites.get_mut(entity) {
+    sprite.freeze(ENEMY_HEIGHT / 2.0);
+  }
+  world
+    .draw_offsets
+    .insert(entity, Vec2::new(0.0, ENEMY_HEIGHT / 2.0));
+  world.lifetimes.insert(entity, u16::from(SQUASH_FRAMES));
+}
+
+// 触れると点数などが手に入り、その場で消える。障害物と違って少年の状態は変えない
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CollectibleKind {
+  Coin,
+  PowerUp(PowerUp),
+}
+impl CollectibleKind {
+  fn points(self) -> u32 {
+    match self {
+      CollectibleKind::Coin => COIN_POINTS,
+      CollectibleKind::PowerUp(_) => 0,
+    }
   }
+  // コインは回る絵を順に切り替える。パワーアップは 1 枚だけ
+  fn cells(self) -> Vec<String> {
+    match self {
+      CollectibleKind::Coin => (1..=COIN_FRAMES)
+        .map(|frame| format!("Coin ({}).png", frame))
+        .collect(),
+      CollectibleKind::PowerUp(PowerUp::Shield) => vec!["Shield.png".into()],
+      CollectibleKind::PowerUp(PowerUp::DoubleJump) => vec!["DoubleJump.png".into()],
+      CollectibleKind::PowerUp(PowerUp::Magnet) => vec!["Magnet.png".into()],
+    }
+  }
+}
+
+pub fn spawn_collectible(world: &mut World<WalkComponents>, kind: CollectibleKind, sheet: Rc<SpriteSheet>, position: Vec2) -> Entity {
+  world
+    .spawn()
+    .position(position)
+    .sprite(Sprite::animation(
+      sheet,
+      kind.cells(),
+      COIN_FRAME_TICKS,
+      PICKUP_SIZE,
+      PICKUP_SIZE,
+    ))
+    .collider(Rect::new(Vec2::ZERO, PICKUP_SIZE, PICKUP_SIZE))
+    .with(|game, entity| game.pickups.insert(entity, kind))
+    .entity()
 }
 
-pub trait Obstacle {
-  fn check_intersection(&self, boy: &mut RedHatBoy);
-  fn draw(&self, renderer: &Renderer);
-  fn move_horizontally(&mut self, velocity: i16);
-  fn right(&self) -> i16;
+// 石や看板のように、触れたら倒れるだけの障害物。画像の大きさがそのまま当たりの範囲になる
+pub fn spawn_barrier(world: &mut World<WalkComponents>, image: &HtmlImageElement, position: Vec2) -> Entity {
+  let shape = Rect::new(Vec2::ZERO, image.width() as f32, image.height() as f32);
+  world
+    .spawn()
+    .position(position)
+    .collider(shape)
+    .sprite(Sprite::Image(image.clone()))
+    .with(|game, entity| {
+      game.shapes.insert(entity, vec![shape]);
+      game.contacts.insert(entity, Contact::Barrier);
+    })
+    .entity()
 }
 
-impl Obstacle for Platform {
-  fn draw(&self, renderer: &Renderer) {
-    let mut x = 0;
-    self.sprites.iter().for_each(|sprite| {
-      self.sheet.draw(
-        renderer,
-        &Rect::new_from_x_y(
-          sprite.frame.x,
-          sprite.frame.y,
-          sprite.frame.w,
-          sprite.frame.h,
-        ),
-        &Rect::new_from_x_y(
-          self.position.x + x,
-          self.position.y,
-          sprite.frame.w,
-          sprite.frame.h,
+// 足場は今のずれの分だけ動かした位置に置く。collider は動く範囲全体で、動きが無いときの位置から測る
+pub fn spawn_platform(
+  world: &mut World<WalkComponents>,
+  sheet: Rc<SpriteSheet>,
+  anchor: Vec2,
+  cells: &[&str],
+  shapes: &[Rect],
+  motion: PlatformMotion,
+) -> Entity {
+  let reach = motion.reach();
+  let right = shapes.iter().map(|shape| shape.right()).fold(0.0, f32::max);
+  let bottom = shapes
+    .iter()
+    .map(|shape| shape.bottom())
+    .fold(0.0, f32::max);
+  let offset = motion.offset(anchor.x);
+  world
+    .spawn()
+    .position(anchor + offset)
+    .collider(Rect::new_from_x_y(
+      -reach - offset.x,
+      -offset.y,
+      right + reach * 2.0,
+      bottom,
+    ))
+    .sprite(Sprite::Row {
+      sheet,
+      cells: cells.iter().map(|cell| cell.to_string()).collect(),
+    })
+    .with(|game, entity| {
+      game.shapes.insert(entity, shapes.to_vec());
+      game.contacts.insert(entity, Contact::Platform);
+      game.carriers.insert(entity, false);
+      match motion {
+        PlatformMotion::Still => {}
+        PlatformMotion::Crumble => game.footings.insert(entity, Footing::Solid),
+        PlatformMotion::Bob { .. } | PlatformMotion::Patrol { .. } => game.motions.insert(
+          entity,
+          Motion {
+            path: Path::Platform(motion),
+            offset,
+          },
         ),
+      }
+    })
+    .entity()
+}
+
+// 敵も足場と同じく、今のずれの分だけ動かした位置に置く。上下の動きは画面から出たかどうかに関係ないので、collider には含めない
+pub fn spawn_enemy(world: &mut World<WalkComponents>, sheet: Rc<SpriteSheet>, anchor: Vec2, behaviour: EnemyBehaviour) -> Entity {
+  let reach = behaviour.reach();
+  let offset = behaviour.offset(anchor.x);
+  let name = match behaviour {
+    EnemyBehaviour::Patrol { .. } => "Walker",
+    EnemyBehaviour::Hop { .. } => "Hopper",
+    EnemyBehaviour::Fly { .. } => "Flyer",
+  };
+  let cells = (1..=ENEMY_FRAMES)
+    .map(|frame| format!("{} ({}).png", name, frame))
+    .collect();
+  world
+    .spawn()
+    .position(anchor + offset)
+    .collider(Rect::new_from_x_y(
+      -reach - offset.x,
+      -offset.y,
+      ENEMY_WIDTH + reach * 2.0,
+      ENEMY_HEIGHT,
+    ))
+    .sprite(Sprite::animation(
+      sheet,
+      cells,
+      ENEMY_FRAME_TICKS,
+      ENEMY_WIDTH,
+      ENEMY_HEIGHT,
+    ))
+    .with(|game, entity| {
+      game.shapes.insert(
+        entity,
+        vec![Rect::new(Vec2::ZERO, ENEMY_WIDTH, ENEMY_HEIGHT)],
       );
-      x += sprite.frame.w;
-    });
+      game.contacts.insert(entity, Contact::Enemy);
+      game.points.insert(entity, STOMP_POINTS);
+      game.motions.insert(
+        entity,
+        Motion {
+          path: Path::Enemy(behaviour),
+          offset,
+        },
+      );
+    })
+    .entity()
+}
+
+// 地面に空いた穴。当たりの範囲は穴の幅で、厚みは無い。穴そのものは Ground が描く
+pub fn spawn_pit(world: &mut World<WalkComponents>, position: Vec2, width: f32) -> Entity {
+  let shape = Rect::new(Vec2::ZERO, width, 0.0);
+  world
+    .spawn()
+    .position(position)
+    .collider(shape)
+    .with(|game, entity| {
+      game.shapes.insert(entity, vec![shape]);
+      game.contacts.insert(entity, Contact::Pit);
+    })
+    .entity()
+}
+
+// 磁石で引き寄せるのはコインだけ。パワーアップまで寄ってくると選んで避けられない
+// 磁石が切れたら速度を外して、その場に止める
+fn attract_coins(world: &mut World<WalkComponents>, target: Option<&Rect>) {
+  let coins: Vec<Entity> = world
+    .game
+    .pickups
+    .iter()
+    .filter(|(_, kind)| **kind == CollectibleKind::Coin)
+    .map(|(entity, _)| entity)
+    .collect();
+  for coin in coins {
+    match (target, world.bounding_box(coin)) {
+      (Some(target), Some(bounding_box)) => world
+        .velocities
+        .insert(coin, magnet_pull(center(&bounding_box), center(target))),
+      _ => {
+        world.velocities.remove(coin);
+      }
+    }
   }
-  fn move_horizontally(&mut self, x: i16) {
-    self.position.x += x;
-    self.bounding_boxes.iter_mut().for_each(|bounding_box| {
-      bounding_box.set_x(bounding_box.position.x + x);
-    });
+}
+
+fn center(rect: &Rect) -> Vec2 {
+  Vec2::new(rect.x() + rect.width / 2.0, rect.y() + rect.height / 2.0)
+}
+
+// 磁石の範囲に入ったものを 1 回の update で動かす量。近ければ行き過ぎないようにちょうど重なるところまで
+fn magnet_pull(position: Vec2, target: Vec2) -> Vec2 {
+  let (dx, dy) = (target.x - position.x, target.y - position.y);
+  let distance = (dx * dx + dy * dy).sqrt();
+  if distance == 0.0 || distance > MAGNET_RADIUS {
+    return Vec2::ZERO;
+  }
+  let step = distance.min(MAGNET_SPEED) / distance;
+  Vec2::new(dx * step, dy * step)
+}
+
+// 時間帯ごとの色を半分の間はそのまま見せて、残りの半分で次の色へ移る
+fn sky_tint(distance: u32) -> Color {
+  let phase_length = DAY_LENGTH as f32 / DAY_PALETTE.len() as f32;
+  let phase = (distance % DAY_LENGTH) as f32 / phase_length;
+  let index = phase as usize % DAY_PALETTE.len();
+  let next = (index + 1) % DAY_PALETTE.len();
+  let t = (phase.fract() - 0.5) * 2.0;
+  DAY_PALETTE[index].lerp(DAY_PALETTE[next], t)
+}
+
+fn lives_text(lives_mode: bool, lives: u8) -> String {
+  if lives_mode {
+    format!("Lives: {}", lives)
+  } else {
+    String::new()
   }
-  fn check_intersection(&self, boy: &mut RedHatBoy) {
-    if let Some(box_to_land_on) = self
-      .bounding_boxes()
+}
+
+// 効いているパワーアップと残り秒数。切り上げて、最後の 1 秒も 1 と表示する
+fn power_up_text(effects: &Effects) -> String {
+  PowerUp::ALL
+    .iter()
+    .filter(|power_up| effects.is_active(**power_up))
+    .map(|power_up| {
+      let seconds = effects.remaining(*power_up).div_ceil(FRAMES_PER_SECOND);
+      format!("{}: {}", power_up.name(), seconds)
+    })
+    .collect::<Vec<_>>()
+    .join("\n")
+}
+
+// 画面の下端に並べる地面のタイル。背景と同じ速さで流れ、穴の部分は暗く塗って両側に縁を付ける
+struct Ground {
+  sheet: Rc<SpriteSheet>,
+  x: f32,
+}
+
+impl Ground {
+  fn new(sheet: Rc<SpriteSheet>) -> Self {
+    Ground { sheet, x: 0.0 }
+  }
+  fn move_horizontally(&mut self, x: f32) {
+    self.x += x;
+  }
+  fn draw(&self, renderer: &Renderer, pits: &[(f32, f32)]) {
+    let Some(cell) = self.sheet.cell(GROUND_TILE) else {
+      error!("Cell {} not found", GROUND_TILE);
+      return;
+    };
+    let frame = Rect::from(&cell.frame);
+    let tile_width = frame.width;
+    let first_tile = self.x.rem_euclid(tile_width) - tile_width * 2.0;
+    // 縁のタイルを描く分だけ、穴の両側の地面を空けておく
+    let gaps: Vec<(f32, f32)> = pits
       .iter()
-      .find(|&bounding_box| boy.bounding_box().intersects(bounding_box))
-    {
-      if boy.velocity_y() > 0 && boy.pos_y() < self.position.y {
-        boy.land_on(box_to_land_on.y());
-      } else {
-        boy.knock_out();
+      .map(|(left, right)| (left - GROUND_EDGE_WIDTH, right + GROUND_EDGE_WIDTH))
+      .collect();
+    for (start, end) in ground_pieces(&gaps, -VIEW_MARGIN, WIDTH + VIEW_MARGIN) {
+      let mut tile_x = first_tile;
+      while tile_x < end {
+        let left = tile_x.max(start);
+        let right = (tile_x + tile_width).min(end);
+        if left < right {
+          self.sheet.draw(
+            renderer,
+            &Rect::new_from_x_y(
+              frame.x() + left - tile_x,
+              frame.y(),
+              right - left,
+              GROUND_DEPTH,
+            ),
+            &Rect::new_from_x_y(left, HEIGHT - GROUND_DEPTH, right - left, GROUND_DEPTH),
+          );
+        }
+        tile_x += tile_width;
       }
     }
+    for (pit_left, pit_right) in pits {
+      renderer.fill_rect(
+        &Rect::new_from_x_y(
+          *pit_left,
+          HEIGHT - GROUND_DEPTH,
+          pit_right - pit_left,
+          GROUND_DEPTH,
+        ),
+        PIT_COLOR,
+      );
+      self.draw_edge(
+        renderer,
+        GROUND_RIGHT_EDGE_TILE,
+        pit_left - GROUND_EDGE_WIDTH,
+        true,
+      );
+      self.draw_edge(renderer, GROUND_LEFT_EDGE_TILE, *pit_right, false);
+    }
   }
-  fn right(&self) -> i16 {
-    self
-      .bounding_boxes()
-      .last()
-      .unwrap_or(&Rect::default())
-      .right()
+  // right_side なら、タイルの右端を切り出して x から描く
+  fn draw_edge(&self, renderer: &Renderer, tile: &str, x: f32, right_side: bool) {
+    let Some(cell) = self.sheet.cell(tile) else {
+      error!("Cell {} not found", tile);
+      return;
+    };
+    let frame = Rect::from(&cell.frame);
+    let source_x = if right_side {
+      frame.right() - GROUND_EDGE_WIDTH
+    } else {
+      frame.x()
+    };
+    self.sheet.draw(
+      renderer,
+      &Rect::new_from_x_y(source_x, frame.y(), GROUND_EDGE_WIDTH, GROUND_DEPTH),
+      &Rect::new_from_x_y(x, HEIGHT - GROUND_DEPTH, GROUND_EDGE_WIDTH, GROUND_DEPTH),
+    );
   }
 }
 
-pub struct Barrier {
-  image: Image,
+// left から right までの地面から穴の範囲を抜いて、残った区間を左から順に返す
+fn ground_pieces(pits: &[(f32, f32)], left: f32, right: f32) -> Vec<(f32, f32)> {
+  let mut pits = pits.to_vec();
+  pits.sort_by(|a, b| a.0.total_cmp(&b.0));
+  let mut pieces = vec![];
+  let mut start = left;
+  for (pit_left, pit_right) in pits {
+    if pit_left > start {
+      pieces.push((start, pit_left.min(right)));
+    }
+    start = start.max(pit_right);
+    if start >= right {
+      return pieces;
+    }
+  }
+  pieces.push((start, right));
+  pieces
 }
-impl Barrier {
-  pub fn new(image: Image) -> Self {
-    Barrier { image }
+
+// 足場は上から落ちてきたときだけ乗れる。それ以外でぶつかったら倒れる
+// 当たり判定はゲームとソルバーで共通にするため、少年を直接書き換えずに起きるイベントを返す
+pub fn platform_collision(boy: &RedHatBoyStateMachine, boy_box: &Rect, top: f32, bounding_boxes: &[Rect]) -> Option<Event> {
+  let box_to_land_on = bounding_boxes
+    .iter()
+    .find(|&bounding_box| boy_box.intersects(bounding_box))?;
+  let context = boy.context();
+  if context.velocity.y > 0.0 && context.position.y < top {
+    Some(Event::Land(box_to_land_on.y()))
+  } else {
+    Some(Event::KnockOut)
   }
 }
-impl Obstacle for Barrier {
-  fn check_intersection(&self, boy: &mut RedHatBoy) {
-    if boy.bounding_box().intersects(&self.image.bounding_box()) {
-      boy.knock_out()
+
+pub fn barrier_collision(boy_box: &Rect, bounding_box: &Rect) -> Option<Event> {
+  boy_box.intersects(bounding_box).then_some(Event::Hit)
+}
+
+// 地面を走っていて、体の真ん中が穴の上に出たら落ちる。跳んでいる間や足場の上では落ちない
+pub fn pit_collision(boy: &RedHatBoyStateMachine, boy_box: &Rect, left: f32, right: f32) -> Option<Event> {
+  let centre = boy_box.x() + boy_box.width / 2.0;
+  let on_ground = boy.context().position.y >= red_hat_boy_states::FLOOR;
+  (on_ground && left < centre && centre < right).then_some(Event::Plunge)
+}
+
+// 跳んで落ちてくる途中で、足が敵の上半分にあれば踏める。それ以外で触れたら石と同じく盾で防げる
+pub fn enemy_collision(boy: &RedHatBoyStateMachine, boy_box: &Rect, bounding_box: &Rect) -> Option<Event> {
+  if !boy_box.intersects(bounding_box) {
+    return None;
+  }
+  let descending = matches!(boy, RedHatBoyStateMachine::Jumping(_)) && boy.context().velocity.y > 0.0;
+  if descending && boy_box.bottom() <= bounding_box.y() + bounding_box.height / 2.0 {
+    Some(Event::Stomp)
+  } else {
+    Some(Event::Hit)
+  }
+}
+
+// 設定の音量は、曲ごとの聞こえ方をそろえた既定の音量に掛ける
+fn apply_volumes(audio: &Audio, settings: &Settings) -> Result<()> {
+  audio.set_volume(Bus::Master, settings.master_volume)?;
+  audio.set_volume(Bus::Music, MUSIC_VOLUME * settings.music_volume)?;
+  audio.set_volume(Bus::Sfx, settings.sfx_volume)
+}
+
+fn rightmost(world: &World<WalkComponents>, entities: &[Entity]) -> f32 {
+  entities
+    .iter()
+    .filter_map(|entity| world.right(*entity))
+    .max_by(|x, y| x.total_cmp(y))
+    .unwrap_or(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    engine::{NoImage, RecordingAudio},
+    segments::ObstacleLayout,
+    solver::{Course, Input, Solver},
+  };
+  use proptest::prelude::*;
+
+  // ブラウザ無しで RedHatBoy を組み立て、イベント列を流して状態を確かめるためのハーネス
+  struct BoyHarness {
+    boy: RedHatBoy,
+    audio: Rc<RecordingAudio>,
+  }
+
+  impl BoyHarness {
+    fn new() -> Self {
+      let audio = Rc::new(RecordingAudio::default());
+      let sounds = RedHatBoySounds {
+        jump: audio.sound("jump"),
+        land: audio.sound("land"),
+        slide: audio.sound("slide"),
+        knock_out: audio.sound("knock_out"),
+      };
+      let sheet: Sheet = serde_json::from_str(include_str!("../static/rhb.json")).expect("rhb.json is a valid sprite sheet");
+      BoyHarness {
+        boy: RedHatBoy::new(sheet, Rc::new(NoImage), audio.clone(), sounds),
+        audio,
+      }
+    }
+
+    fn run(mut self, script: &[Event]) -> Self {
+      for event in script {
+        self.boy.state_machine = self.boy.state_machine.clone().transition(*event);
+      }
+      self
+    }
+
+    fn update_until(mut self, done: impl Fn(&RedHatBoyStateMachine) -> bool) -> Self {
+      const LIMIT: usize = 1000;
+      for _ in 0..LIMIT {
+        if done(&self.boy.state_machine) {
+          return self;
+        }
+        self.boy.update();
+      }
+      panic!("state did not settle within {} updates", LIMIT);
+    }
+
+    fn state(&self) -> &'static str {
+      state_name(&self.boy.state_machine)
+    }
+
+    fn position(&self) -> Vec2 {
+      self.boy.state_machine.context().position
+    }
+
+    fn velocity(&self) -> Vec2 {
+      self.boy.state_machine.context().velocity
     }
   }
-  fn draw(&self, renderer: &Renderer) {
-    self.image.draw(renderer);
+
+  fn state_name(machine: &RedHatBoyStateMachine) -> &'static str {
+    match machine {
+      RedHatBoyStateMachine::Idle(_) => "Idle",
+      RedHatBoyStateMachine::Running(_) => "Running",
+      RedHatBoyStateMachine::Sliding(_) => "Sliding",
+      RedHatBoyStateMachine::Jumping(_) => "Jumping",
+      RedHatBoyStateMachine::Falling(_) => "Falling",
+      RedHatBoyStateMachine::Plunging(_) => "Plunging",
+      RedHatBoyStateMachine::KnockedOut(_) => "KnockedOut",
+    }
   }
-  fn move_horizontally(&mut self, x: i16) {
-    self.image.move_horizontally(x);
+
+  fn boy_in(state: &str) -> BoyHarness {
+    let harness = BoyHarness::new();
+    match state {
+      "Idle" => harness,
+      "Running" => harness.run(&[Event::Run]),
+      "Sliding" => harness.run(&[Event::Run, Event::Slide]),
+      "Jumping" => harness.run(&[Event::Run, Event::Jump, Event::Update]),
+      "Falling" => harness.run(&[Event::Run, Event::KnockOut]),
+      "Plunging" => harness.run(&[Event::Run, Event::Plunge]),
+      "KnockedOut" => harness
+        .run(&[Event::Run, Event::KnockOut])
+        .update_until(|machine| machine.knocked_out()),
+      _ => unreachable!("unknown state {}", state),
+    }
   }
-  fn right(&self) -> i16 {
-    self.image.right()
+
+  #[test]
+  fn idle_boy_stands_still_on_the_floor() {
+    let harness = BoyHarness::new().run(&[Event::Update, Event::Update]);
+
+    assert_eq!(harness.state(), "Idle");
+    assert_eq!(
+      harness.position(),
+      Vec2 {
+        x: STARTING_POINT,
+        y: FLOOR
+      }
+    );
+    // 床の上でも重力で velocity.y は増え続ける(足場への着地判定がこれに頼っている)。横には動かない
+    assert_eq!(harness.velocity().x, 0.0);
   }
-}
 
-fn rightmost(obstacle_list: &Vec<Box<dyn Obstacle>>) -> i16 {
-  obstacle_list
-    .iter()
-    .map(|obstacle| obstacle.right())
-    .max_by(|x, y| x.cmp(&y))
-    .unwrap_or(0)
+  #[test]
+  fn running_moves_at_running_speed() {
+    let harness = BoyHarness::new().run(&[Event::Run, Event::Update]);
+
+    assert_eq!(harness.state(), "Running");
+    assert_eq!(harness.velocity().x, RUNNING_SPEED);
+    assert_eq!(harness.position().y, FLOOR);
+  }
+
+  #[test]
+  fn jump_rises_and_lands_back_on_the_floor() {
+    let harness = BoyHarness::new().run(&[Event::Run, Event::Jump]);
+    assert_eq!(harness.state(), "Jumping");
+    assert_eq!(harness.velocity().y, JUMP_SPEED);
+
+    let harness = harness.run(&[Event::Update]);
+    assert!(harness.position().y < FLOOR);
+
+    let harness = harness.update_until(|machine| !matches!(machine, RedHatBoyStateMachine::Jumping(_)));
+    assert_eq!(harness.state(), "Running");
+    assert_eq!(harness.position().y, FLOOR);
+    assert_eq!(harness.velocity().x, RUNNING_SPEED);
+  }
+
+  #[test]
+  fn landing_on_a_platform_puts_the_feet_on_its_top() {
+    const PLATFORM_TOP: f32 = 400.0;
+    let harness = BoyHarness::new().run(&[
+      Event::Run,
+      Event::Jump,
+      Event::Update,
+      Event::Land(PLATFORM_TOP),
+    ]);
+
+    assert_eq!(harness.state(), "Running");
+    assert_eq!(harness.position().y, PLATFORM_TOP - PLAYER_HEIGHT);
+  }
+
+  // 座標が f32 になったので、足場の高さがピクセルの途中でもそのまま乗れる
+  #[test]
+  fn landing_keeps_sub_pixel_heights() {
+    const PLATFORM_TOP: f32 = 400.5;
+    let harness = BoyHarness::new().run(&[
+      Event::Run,
+      Event::Jump,
+      Event::Update,
+      Event::Land(PLATFORM_TOP),
+    ]);
+
+    assert_eq!(harness.position().y, PLATFORM_TOP - PLAYER_HEIGHT);
+    assert_eq!(harness.position().y.fract(), 0.5);
+  }
+
+  #[test]
+  fn sliding_stands_up_after_the_animation() {
+    let harness = BoyHarness::new().run(&[Event::Run, Event::Slide]);
+    assert_eq!(harness.state(), "Sliding");
+
+    let harness = harness.update_until(|machine| !matches!(machine, RedHatBoyStateMachine::Sliding(_)));
+    assert_eq!(harness.state(), "Running");
+    assert_eq!(harness.velocity().x, RUNNING_SPEED);
+  }
+
+  // 看板の下端は、走っている少年の頭より低く、スライディングしている少年より高い
+  #[test]
+  fn sliding_lowers_the_hitbox_below_hanging_signs() {
+    let running = boy_in("Running").boy.bounding_box();
+    let sliding = boy_in("Sliding").boy.bounding_box();
+    let sign = Segment::SignAndPlatform
+      .layout(0.0)
+      .iter()
+      .find_map(|layout| match layout {
+        ObstacleLayout::Overhead(_) => Some(layout.bounding_boxes()[0]),
+        _ => None,
+      })
+      .expect("the segment has a sign");
+
+    assert!(running.y() < sign.bottom() && sign.bottom() < sliding.y());
+    assert!((running.bottom() - sliding.bottom()).abs() < 10.0);
+  }
+
+  #[test]
+  fn knock_out_stops_the_boy_and_ends_knocked_out() {
+    let harness = BoyHarness::new().run(&[Event::Run, Event::Jump, Event::Update, Event::KnockOut]);
+    assert_eq!(harness.state(), "Falling");
+    assert_eq!(harness.velocity().x, 0.0);
+
+    let harness = harness.update_until(|machine| machine.knocked_out());
+    assert_eq!(harness.state(), "KnockedOut");
+    assert!(harness.boy.knocked_out());
+  }
+
+  #[test]
+  fn every_state_and_event_pair_is_handled() {
+    const STATES: [&str; 7] = [
+      "Idle",
+      "Running",
+      "Sliding",
+      "Jumping",
+      "Falling",
+      "Plunging",
+      "KnockedOut",
+    ];
+    let events = [
+      Event::Run,
+      Event::Slide,
+      Event::Jump,
+      Event::AirJump,
+      Event::Hit,
+      Event::KnockOut,
+      Event::Update,
+      Event::Land(HEIGHT),
+      Event::PowerUp(PowerUp::Shield),
+      Event::Respawn,
+      Event::Stomp,
+      Event::Plunge,
+    ];
+    // 各状態でイベントを受けたときの遷移先。元の状態と同じものは意図的に無視している組み合わせ
+    let expected: [[&str; 12]; 7] = [
+      // Run, Slide, Jump, AirJump, Hit, KnockOut, Update, Land, PowerUp, Respawn, Stomp, Plunge
+      [
+        "Running", "Idle", "Idle", "Idle", "Idle", "Idle", "Idle", "Idle", "Idle", "Idle", "Idle", "Idle",
+      ],
+      [
+        "Running", "Sliding", "Jumping", "Running", "Falling", "Falling", "Running", "Running", "Running", "Running", "Running", "Plunging",
+      ],
+      [
+        "Sliding", "Sliding", "Sliding", "Sliding", "Falling", "Falling", "Sliding", "Sliding", "Sliding", "Sliding", "Sliding", "Plunging",
+      ],
+      [
+        "Jumping", "Jumping", "Jumping", "Jumping", "Falling", "Falling", "Jumping", "Running", "Jumping", "Jumping", "Jumping", "Plunging",
+      ],
+      [
+        "Falling", "Falling", "Falling", "Falling", "Falling", "Falling", "Falling", "Falling", "Falling", "Falling", "Falling", "Falling",
+      ],
+      [
+        "Plunging", "Plunging", "Plunging", "Plunging", "Plunging", "Plunging", "Plunging", "Plunging", "Plunging", "Plunging", "Plunging",
+        "Plunging",
+      ],
+      [
+        "KnockedOut",
+        "KnockedOut",
+        "KnockedOut",
+        "KnockedOut",
+        "KnockedOut",
+        "KnockedOut",
+        "KnockedOut",
+        "KnockedOut",
+        "KnockedOut",
+        "Running",
+        "KnockedOut",
+        "KnockedOut",
+      ],
+    ];
+
+    for (state, row) in STATES.iter().zip(expected) {
+      for (event, next) in events.iter().zip(row) {
+        let harness = boy_in(state).run(&[*event]);
+        assert_eq!(harness.state(), next, "{} + {:?}", state, event);
+      }
+    }
+  }
+
+  #[test]
+  fn plunging_falls_off_the_bottom_of_the_screen_and_ends_the_run() {
+    let harness = boy_in("Plunging");
+    assert_eq!(harness.velocity().x, 0.0);
+
+    let harness = harness.update_until(|machine| machine.knocked_out());
+    assert!(harness.position().y > HEIGHT);
+    // 空中で跳んでも足場に触れても戻ってこない
+    let harness = harness.run(&[Event::Jump, Event::AirJump, Event::Land(FLOOR)]);
+    assert_eq!(harness.state(), "KnockedOut");
+  }
+
+  #[test]
+  fn only_a_boy_on_the_ground_falls_into_a_pit() {
+    let running = boy_in("Running");
+    let boy_box = running.boy.bounding_box();
+    let centre = boy_box.x() + boy_box.width / 2.0;
+    assert!(matches!(
+      pit_collision(
+        &running.boy.state_machine,
+        &boy_box,
+        centre - 10.0,
+        centre + 10.0
+      ),
+      Some(Event::Plunge)
+    ));
+    // 足の先が掛かっているだけなら落ちない
+    assert!(pit_collision(
+      &running.boy.state_machine,
+      &boy_box,
+      boy_box.right() - 10.0,
+      boy_box.right() + 100.0
+    )
+    .is_none());
+
+    let jumping = boy_in("Jumping");
+    let boy_box = jumping.boy.bounding_box();
+    let centre = boy_box.x() + boy_box.width / 2.0;
+    assert!(pit_collision(
+      &jumping.boy.state_machine,
+      &boy_box,
+      centre - 10.0,
+      centre + 10.0
+    )
+    .is_none());
+  }
+
+  #[test]
+  fn ground_pieces_leave_out_the_pits() {
+    assert_eq!(ground_pieces(&[], 0.0, WIDTH), vec![(0.0, WIDTH)]);
+    assert_eq!(
+      ground_pieces(&[(400.0, 450.0), (100.0, 200.0)], 0.0, WIDTH),
+      vec![(0.0, 100.0), (200.0, 400.0), (450.0, WIDTH)]
+    );
+    // 画面の端にかかった穴や画面の外の穴
+    assert_eq!(
+      ground_pieces(
+        &[(-50.0, 50.0), (550.0, 700.0), (900.0, 1000.0)],
+        0.0,
+        WIDTH
+      ),
+      vec![(50.0, 550.0)]
+    );
+  }
+
+  #[test]
+  fn a_shield_absorbs_one_hit_then_wears_off() {
+    let harness = boy_in("Running").run(&[Event::PowerUp(PowerUp::Shield), Event::Hit]);
+    assert_eq!(harness.state(), "Running");
+    assert!(!harness.boy.effects().is_active(PowerUp::Shield));
+
+    // 割れた直後はまだ同じ石に重なっているので、続けて当たっても倒れない
+    let harness = harness.run(&[Event::Update, Event::Hit]);
+    assert_eq!(harness.state(), "Running");
+
+    let harness = harness
+      .run(&[Event::Update; SHIELD_GRACE_FRAMES as usize])
+      .run(&[Event::Hit]);
+    assert_eq!(harness.state(), "Falling");
+  }
+
+  #[test]
+  fn double_jump_works_once_per_airtime() {
+    let harness = boy_in("Jumping").run(&[Event::PowerUp(PowerUp::DoubleJump), Event::AirJump]);
+    assert_eq!(harness.state(), "Jumping");
+    assert_eq!(harness.velocity().y, AIR_JUMP_SPEED);
+    assert_eq!(harness.audio.play_count("jump"), 2);
+
+    let harness = harness.run(&[Event::Update, Event::AirJump]);
+    assert_eq!(harness.audio.play_count("jump"), 2);
+
+    // 着地すればまた二段ジャンプできる
+    let harness = harness
+      .update_until(|machine| !matches!(machine, RedHatBoyStateMachine::Jumping(_)))
+      .run(&[Event::Jump, Event::Update, Event::AirJump]);
+    assert_eq!(harness.audio.play_count("jump"), 4);
+  }
+
+  #[test]
+  fn power_ups_expire_and_need_to_be_collected_again() {
+    let harness = boy_in("Jumping").run(&[Event::AirJump]);
+    assert_eq!(harness.audio.play_count("jump"), 1);
+
+    let harness = boy_in("Running").run(&[Event::PowerUp(PowerUp::Magnet)]);
+    assert_eq!(
+      harness.boy.effects().remaining(PowerUp::Magnet),
+      POWER_UP_FRAMES
+    );
+
+    let harness = harness.run(&[Event::Update; POWER_UP_FRAMES as usize]);
+    assert!(!harness.boy.effects().is_active(PowerUp::Magnet));
+  }
+
+  #[test]
+  fn fallen_boys_do_not_pick_up_power_ups() {
+    let harness = boy_in("Falling").run(&[Event::PowerUp(PowerUp::Shield)]);
+
+    assert_eq!(harness.boy.effects(), Effects::default());
+  }
+
+  #[test]
+  fn the_magnet_pulls_nearby_things_without_overshooting() {
+    let target = Vec2::new(100.0, 100.0);
+
+    assert_eq!(
+      magnet_pull(Vec2::new(100.0, 100.0 + MAGNET_RADIUS + 1.0), target),
+      Vec2::ZERO
+    );
+    assert_eq!(
+      magnet_pull(Vec2::new(100.0 + MAGNET_RADIUS, 100.0), target),
+      Vec2::new(-MAGNET_SPEED, 0.0)
+    );
+    assert_eq!(
+      magnet_pull(Vec2::new(103.0, 96.0), target),
+      Vec2::new(-3.0, 4.0)
+    );
+  }
+
+  #[test]
+  fn only_coins_drift_towards_the_magnet_and_stop_when_it_wears_off() {
+    let mut world = World::new();
+    let pickup = |world: &mut World<WalkComponents>, kind| {
+      world
+        .spawn()
+        .position(Vec2::new(100.0 + MAGNET_RADIUS / 2.0, 100.0))
+        .collider(Rect::new(Vec2::ZERO, PICKUP_SIZE, PICKUP_SIZE))
+        .with(|game, entity| game.pickups.insert(entity, kind))
+        .entity()
+    };
+    let coin = pickup(&mut world, CollectibleKind::Coin);
+    let shield = pickup(&mut world, CollectibleKind::PowerUp(PowerUp::Shield));
+    let boy_box = Rect::new_from_x_y(100.0, 100.0, PICKUP_SIZE, PICKUP_SIZE);
+
+    attract_coins(&mut world, Some(&boy_box));
+    world.integrate();
+    assert_eq!(
+      world.positions.get(coin),
+      Some(&Vec2::new(
+        100.0 + MAGNET_RADIUS / 2.0 - MAGNET_SPEED,
+        100.0
+      ))
+    );
+    assert_eq!(
+      world.positions.get(shield),
+      Some(&Vec2::new(100.0 + MAGNET_RADIUS / 2.0, 100.0))
+    );
+
+    attract_coins(&mut world, None);
+    world.integrate();
+    assert_eq!(
+      world.positions.get(coin),
+      Some(&Vec2::new(
+        100.0 + MAGNET_RADIUS / 2.0 - MAGNET_SPEED,
+        100.0
+      ))
+    );
+  }
+
+  #[test]
+  fn power_up_timers_count_whole_seconds_up() {
+    let harness = boy_in("Running").run(&[
+      Event::PowerUp(PowerUp::Shield),
+      Event::PowerUp(PowerUp::Magnet),
+      Event::Update,
+    ]);
+
+    assert_eq!(
+      power_up_text(&harness.boy.effects()),
+      "Shield: 10\nMagnet: 10"
+    );
+    assert_eq!(power_up_text(&Effects::default()), "");
+  }
+
+  #[test]
+  fn respawning_runs_again_from_the_floor_and_flashes_while_invulnerable() {
+    let harness = boy_in("Jumping")
+      .run(&[Event::PowerUp(PowerUp::Magnet), Event::KnockOut])
+      .update_until(|machine| machine.knocked_out())
+      .run(&[Event::Respawn]);
+
+    assert_eq!(harness.state(), "Running");
+    assert_eq!(harness.position().y, FLOOR);
+    assert_eq!(harness.velocity(), Vec2::new(RUNNING_SPEED, 0.0));
+    assert!(!harness.boy.effects().is_active(PowerUp::Magnet));
+
+    let flashes = (0..RESPAWN_INVULNERABLE_FRAMES)
+      .scan(harness, |harness, _| {
+        harness.boy.update();
+        Some(harness.boy.effects().is_flashing())
+      })
+      .collect::<Vec<_>>();
+    assert!(flashes.iter().any(|flashing| *flashing));
+    assert!(flashes.iter().any(|flashing| !*flashing));
+    assert!(!flashes[flashes.len() - 1]);
+  }
+
+  #[test]
+  fn invulnerable_boys_run_through_stones_until_it_wears_off() {
+    let harness = boy_in("KnockedOut").run(&[Event::Respawn, Event::Hit]);
+    assert_eq!(harness.state(), "Running");
+
+    let harness = harness
+      .run(&[Event::Update; RESPAWN_INVULNERABLE_FRAMES as usize])
+      .run(&[Event::Hit]);
+    assert_eq!(harness.state(), "Falling");
+  }
+
+  #[test]
+  fn invulnerable_boys_run_into_the_side_of_platforms_until_it_wears_off() {
+    let side_hit = |harness: &BoyHarness| {
+      let boy_box = harness.boy.bounding_box();
+      platform_collision(
+        &harness.boy.state_machine,
+        &boy_box,
+        harness.position().y,
+        &[boy_box],
+      )
+      .expect("the boy touches the platform")
+    };
+    let harness = boy_in("KnockedOut").run(&[Event::Respawn]);
+    let hit = side_hit(&harness);
+    let harness = harness.run(&[hit]);
+    assert_eq!(harness.state(), "Running");
+
+    let harness = harness.run(&[Event::Update; RESPAWN_INVULNERABLE_FRAMES as usize]);
+    let hit = side_hit(&harness);
+    let harness = harness.run(&[hit]);
+    assert_eq!(harness.state(), "Falling");
+  }
+
+  #[test]
+  fn power_ups_only_flash_when_about_to_run_out() {
+    let harness = boy_in("Running").run(&[Event::PowerUp(PowerUp::Magnet)]);
+    assert!(!harness.boy.effects().is_flashing());
+
+    let flashes = (0..POWER_UP_FRAMES)
+      .scan(harness, |harness, _| {
+        harness.boy.update();
+        Some(harness.boy.effects().is_flashing())
+      })
+      .collect::<Vec<_>>();
+    let warning = flashes.len() - usize::from(POWER_UP_WARNING_FRAMES);
+    assert!(flashes[..warning].iter().all(|flashing| !*flashing));
+    assert!(flashes[warning..].iter().any(|flashing| *flashing));
+  }
+
+  #[test]
+  fn the_sky_turns_from_day_to_night_and_back() {
+    let phase = DAY_LENGTH / DAY_PALETTE.len() as u32;
+
+    assert_eq!(sky_tint(0), Color::WHITE);
+    assert_eq!(sky_tint(phase / 2), Color::WHITE);
+    assert_eq!(sky_tint(phase), DAY_PALETTE[1]);
+    assert_eq!(sky_tint(phase * 2), DAY_PALETTE[2]);
+    assert_eq!(
+      sky_tint(phase * 2 + phase * 3 / 4),
+      DAY_PALETTE[2].lerp(DAY_PALETTE[3], 0.5)
+    );
+    assert_eq!(sky_tint(DAY_LENGTH), Color::WHITE);
+  }
+
+  #[test]
+  fn lives_are_only_shown_in_lives_mode() {
+    assert_eq!(lives_text(true, 2), "Lives: 2");
+    assert_eq!(lives_text(false, 2), "");
+  }
+
+  #[test]
+  fn jumping_plays_the_jump_sound_once() {
+    let harness = BoyHarness::new().run(&[Event::Run, Event::Jump, Event::Update, Event::Jump]);
+
+    assert_eq!(harness.audio.play_count("jump"), 1);
+    assert_eq!(harness.state(), "Jumping");
+  }
+
+  #[test]
+  fn landing_from_a_jump_plays_the_land_sound() {
+    let harness = BoyHarness::new()
+      .run(&[Event::Run, Event::Jump])
+      .update_until(|machine| !matches!(machine, RedHatBoyStateMachine::Jumping(_)));
+
+    let played: Vec<String> = harness
+      .audio
+      .played()
+      .into_iter()
+      .map(|played| played.name)
+      .collect();
+    assert_eq!(played, vec!["jump", "land"]);
+  }
+
+  // 少年の音は、画面の左端近くにいても左に寄せずに鳴らす
+  #[test]
+  fn the_boys_own_sounds_are_not_panned() {
+    let harness = BoyHarness::new()
+      .run(&[Event::Run, Event::Jump])
+      .update_until(|machine| !matches!(machine, RedHatBoyStateMachine::Jumping(_)));
+
+    let pans: Vec<f32> = harness
+      .audio
+      .played()
+      .into_iter()
+      .map(|played| played.pan)
+      .collect();
+    assert_eq!(pans.len(), 2);
+    assert!(pans.iter().all(|pan| pan.abs() < 0.01), "{:?}", pans);
+  }
+
+  fn solver() -> Solver {
+    let harness = BoyHarness::new();
+    Solver::new(
+      harness.boy.sprite_sheet,
+      harness.boy.state_machine.context().sounds.clone(),
+    )
+  }
+
+  // at フレーム目に input したとき、セグメントを抜ける前に倒れたらそのフレームを返す
+  fn knocked_out_at(solver: &Solver, segment: Segment, offset_x: f32, input: Input, at: Option<usize>) -> Option<usize> {
+    let inputs: Vec<(usize, Input)> = at.map(|frame| (frame, input)).into_iter().collect();
+    solver
+      .replay(&Course::new(&[segment], offset_x, 0.0), &inputs)
+      .err()
+      .map(|unsolvable| unsolvable.frame)
+  }
+
+  // 実行中に間隔を広げずに済むよう、どの難易度でもどの組み合わせもその難易度の間隔で抜けられるようにしておく
+  #[test]
+  fn every_pair_of_segments_is_solvable_at_each_difficulty_gap() {
+    let solver = solver();
+    for difficulty in [Difficulty::Easy, Difficulty::Normal, Difficulty::Hard] {
+      for first in Segment::ALL {
+        for second in Segment::ALL {
+          let course = Course::new(&[first, second], 0.0, obstacle_gap(difficulty));
+          let inputs = solver.solve(&course).unwrap_or_else(|unsolvable| {
+            panic!(
+              "{:?}: {:?} then {:?}: {:?}",
+              difficulty, first, second, unsolvable
+            )
+          });
+          assert_eq!(solver.replay(&course, &inputs), Ok(()));
+        }
+      }
+    }
+  }
+
+  // 落ちてくる途中に上から触れたときだけ踏める。横から当たったり、跳び上がる途中で下から当たったりしたら Hit
+  #[test]
+  fn enemies_are_stomped_only_from_above() {
+    let enemy = Rect::new_from_x_y(100.0, 552.0, ENEMY_WIDTH, ENEMY_HEIGHT);
+    let feet_on_top = Rect::new_from_x_y(90.0, 450.0, 60.0, 110.0);
+    let beside = Rect::new_from_x_y(50.0, 479.0, 60.0, 121.0);
+    let descending = boy_in("Jumping").update_until(|machine| machine.context().velocity.y > 0.0);
+    let rising = boy_in("Jumping");
+
+    assert!(matches!(
+      enemy_collision(&descending.boy.state_machine, &feet_on_top, &enemy),
+      Some(Event::Stomp)
+    ));
+    assert!(matches!(
+      enemy_collision(&rising.boy.state_machine, &feet_on_top, &enemy),
+      Some(Event::Hit)
+    ));
+    assert!(matches!(
+      enemy_collision(&boy_in("Running").boy.state_machine, &beside, &enemy),
+      Some(Event::Hit)
+    ));
+    assert!(enemy_collision(
+      &descending.boy.state_machine,
+      &Rect::new_from_x_y(300.0, 450.0, 60.0, 110.0),
+      &enemy
+    )
+    .is_none());
+  }
+
+  // 往復する足場は、ずれの変化を速度にして動く。動く範囲は動きが無いときの位置に留まり、乗った少年は同じだけ運ばれる
+  #[test]
+  fn patrolling_platforms_move_by_velocity_and_carry_the_boy() {
+    let mut harness = boy_in("Jumping").update_until(|machine| machine.context().velocity.y > 0.0);
+    let boy_box = harness.boy.bounding_box();
+    let motion = PlatformMotion::Patrol { distance: 40.0 };
+    let anchor = Vec2::new(boy_box.x(), boy_box.bottom() - 5.0);
+    let mut world: World<WalkComponents> = World::new();
+    let platform = world
+      .spawn()
+      .position(anchor + motion.offset(anchor.x))
+      .collider(Rect::new_from_x_y(
+        -40.0 - motion.offset(anchor.x).x,
+        0.0,
+        200.0,
+        50.0,
+      ))
+      .with(|game, entity| {
+        game
+          .shapes
+          .insert(entity, vec![Rect::new(Vec2::ZERO, 120.0, 50.0)]);
+        game.contacts.insert(entity, Contact::Platform);
+        game.carriers.insert(entity, false);
+        game.motions.insert(
+          entity,
+          Motion {
+            path: Path::Platform(motion),
+            offset: motion.offset(anchor.x),
+          },
+        );
+      })
+      .entity();
+    let right = world.right(platform).expect("the platform has a collider");
+
+    world.scroll(-RUNNING_SPEED);
+    follow_paths(&mut world);
+    world.integrate();
+    let anchor = anchor - Vec2::new(RUNNING_SPEED, 0.0);
+    let velocity = *world
+      .velocities
+      .get(platform)
+      .expect("moving platforms have a velocity");
+    let position = world
+      .positions
+      .get(platform)
+      .expect("the platform has a position");
+    assert_ne!(velocity.x, 0.0);
+    assert!((position.x - (anchor.x + motion.offset(anchor.x).x)).abs() < 0.001);
+    assert!((world.right(platform).expect("the platform has a collider") - (right - RUNNING_SPEED)).abs() < 0.001);
+    assert_eq!(carry(&world), 0.0);
+
+    assert!(touch(&mut world, &mut harness.boy).is_empty());
+    assert_eq!(harness.state(), "Running");
+    assert_eq!(carry(&world), velocity.x);
+  }
+
+  // 踏まれた敵は点数を残して、もう少年には当たらず、動きも止まる
+  #[test]
+  fn stomped_enemies_keep_their_points_and_stop_moving() {
+    let mut harness = boy_in("Jumping").update_until(|machine| machine.context().velocity.y > 0.0);
+    let boy_box = harness.boy.bounding_box();
+    let behaviour = EnemyBehaviour::Patrol { distance: 60.0 };
+    let mut world: World<WalkComponents> = World::new();
+    let enemy = world
+      .spawn()
+      .position(Vec2::new(boy_box.x(), boy_box.bottom() - 10.0))
+      .collider(Rect::new(Vec2::ZERO, ENEMY_WIDTH, ENEMY_HEIGHT))
+      .with(|game, entity| {
+        game.shapes.insert(
+          entity,
+          vec![Rect::new(Vec2::ZERO, ENEMY_WIDTH, ENEMY_HEIGHT)],
+        );
+        game.contacts.insert(entity, Contact::Enemy);
+        game.points.insert(entity, STOMP_POINTS);
+        game.motions.insert(
+          entity,
+          Motion {
+            path: Path::Enemy(behaviour),
+            offset: Vec2::ZERO,
+          },
+        );
+      })
+      .entity();
+
+    assert_eq!(touch(&mut world, &mut harness.boy), vec![enemy]);
+    squash(&mut world, enemy);
+
+    assert_eq!(world.game.points.get(enemy), Some(&STOMP_POINTS));
+    assert_eq!(world.game.contacts.get(enemy), None);
+    assert_eq!(world.game.motions.get(enemy), None);
+    assert_eq!(world.lifetimes.get(enemy), Some(&u16::from(SQUASH_FRAMES)));
+    assert!(touch(&mut world, &mut harness.boy).is_empty());
+  }
+
+  #[test]
+  fn stomping_bounces_the_boy_back_up() {
+    let harness = boy_in("Jumping")
+      .update_until(|machine| machine.context().velocity.y > 0.0)
+      .run(&[Event::Stomp]);
+
+    assert_eq!(harness.state(), "Jumping");
+    assert_eq!(harness.velocity().y, STOMP_BOUNCE_SPEED);
+    assert_eq!(harness.audio.play_count("jump"), 2);
+  }
+
+  proptest! {
+    // 1 ケースで何百回もシミュレーションするので回数を絞る
+    #![proptest_config(ProptestConfig::with_cases(32))]
+
+    // generate_next_segment が置くどのセグメントも、どこかで 1 回ジャンプすれば抜けられる。看板だけは 1 回のスライディング
+    #[test]
+    fn every_generated_segment_can_be_cleared_with_one_input(
+      segment in prop::sample::select(Segment::ALL.to_vec()),
+      offset_x in OBSTACLE_BUFFER..=TIMELINE_MINIMUM + OBSTACLE_BUFFER,
+    ) {
+      let solver = solver();
+      let input = if segment == Segment::SignAndPlatform { Input::Slide } else { Input::Jump };
+      if let Some(hit) = knocked_out_at(&solver, segment, offset_x, input, None) {
+        // ぶつかる直前から遡って試すと、すぐに成功する入力が見つかる
+        let cleared = (0..=hit).rev().any(|at| knocked_out_at(&solver, segment, offset_x, input, Some(at)).is_none());
+        prop_assert!(cleared, "{:?} at {} cannot be cleared", segment, offset_x);
+      }
+    }
+  }
 }
@@ -0,0 +1,93 @@
+use crate::browser;
+
+const STORAGE_KEY: &str = "walk_the_dog.high_scores";
+const MAX_ENTRIES: usize = 5;
+
+// 上位のスコアだけを高い順に持つ。localStorage にはカンマ区切りで保存する
+#[derive(Debug, Default, PartialEq)]
+pub struct HighScores {
+  scores: Vec<u32>,
+}
+
+impl HighScores {
+  pub fn load() -> Self {
+    browser::load_item(STORAGE_KEY)
+      .unwrap_or_else(|err| {
+        error!("Could not load high scores {:#?}", err);
+        None
+      })
+      .map(|value| HighScores::parse(&value))
+      .unwrap_or_default()
+  }
+  // 表に載ったときだけ true を返して保存する
+  pub fn record(&mut self, score: u32) -> bool {
+    if !self.insert(score) {
+      return false;
+    }
+    if let Err(err) = browser::save_item(STORAGE_KEY, &self.to_storage()) {
+      error!("Could not save high scores {:#?}", err);
+    }
+    true
+  }
+  pub fn scores(&self) -> &[u32] {
+    &self.scores
+  }
+  // 壊れた値が保存されていても、読めたスコアだけを使う
+  fn parse(value: &str) -> Self {
+    let mut high_scores = HighScores::default();
+    value
+      .split(',')
+      .filter_map(|score| score.trim().parse().ok())
+      .for_each(|score| {
+        high_scores.insert(score);
+      });
+    high_scores
+  }
+  fn to_storage(&self) -> String {
+    self
+      .scores
+      .iter()
+      .map(u32::to_string)
+      .collect::<Vec<_>>()
+      .join(",")
+  }
+  fn insert(&mut self, score: u32) -> bool {
+    if score == 0 {
+      return false;
+    }
+    let index = self
+      .scores
+      .partition_point(|high_score| *high_score >= score);
+    if index >= MAX_ENTRIES {
+      return false;
+    }
+    self.scores.insert(index, score);
+    self.scores.truncate(MAX_ENTRIES);
+    true
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn keeps_only_the_best_scores_in_order() {
+    let mut high_scores = HighScores::default();
+    for score in [30, 10, 50, 20, 40, 60] {
+      high_scores.insert(score);
+    }
+
+    assert_eq!(high_scores.scores(), &[60, 50, 40, 30, 20]);
+    assert!(!high_scores.insert(20));
+    assert!(high_scores.insert(21));
+  }
+
+  #[test]
+  fn round_trips_through_storage_and_skips_broken_entries() {
+    let high_scores = HighScores::parse("120, 80,oops,,300");
+
+    assert_eq!(high_scores.scores(), &[300, 120, 80]);
+    assert_eq!(HighScores::parse(&high_scores.to_storage()), high_scores);
+  }
+}
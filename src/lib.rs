@@ -2,7 +2,11 @@
 mod browser;
 mod engine;
 mod game;
+mod high_scores;
 mod segments;
+mod settings;
+#[cfg(test)]
+mod solver;
 mod sound;
 
 use engine::GameLoop;
@@ -1,82 +1,564 @@
-use std::rc::Rc;
+use std::{f32::consts::TAU, rc::Rc};
 
+use rand::Rng;
 use web_sys::HtmlImageElement;
 
 use crate::{
-  engine::{Image, Point, Rect, SpriteSheet},
-  game::{Barrier, Obstacle, Platform},
+  engine::{Entity, Rect, SpriteSheet, Vec2, World},
+  game::{red_hat_boy_states::PowerUp, spawn_barrier, spawn_collectible, spawn_enemy, spawn_pit, spawn_platform, CollectibleKind, WalkComponents},
 };
 
-const LOW_PLATFORM: i16 = 420;
-const HIGH_PLATFORM: i16 = 375;
-const FIRST_PLATFORM: i16 = 370;
-const STONE_ON_GROUND: i16 = 546;
+const LOW_PLATFORM: f32 = 420.0;
+const HIGH_PLATFORM: f32 = 375.0;
+const FIRST_PLATFORM: f32 = 370.0;
+const STONE_ON_GROUND: f32 = 546.0;
+const GROUND: f32 = 600.0;
 const FLOATING_PLATFORM_SPRITES: [&str; 3] = ["13.png", "14.png", "15.png"];
-const PLATFORM_WIDTH: i16 = 384;
-const PLATFORM_HEIGHT: i16 = 93;
-const PLATFORM_EDGE_WIDTH: i16 = 60;
-const PLATFORM_EDGE_HEIGHT: i16 = 54;
+const PLATFORM_WIDTH: f32 = 384.0;
+const PLATFORM_HEIGHT: f32 = 93.0;
+const PLATFORM_EDGE_WIDTH: f32 = 60.0;
+const PLATFORM_EDGE_HEIGHT: f32 = 54.0;
 const FLOATING_PLATFORM_BOUNDING_BOXES: [Rect; 3] = [
-  Rect::new_from_x_y(0, 0, PLATFORM_EDGE_WIDTH, PLATFORM_EDGE_HEIGHT),
+  Rect::new_from_x_y(0.0, 0.0, PLATFORM_EDGE_WIDTH, PLATFORM_EDGE_HEIGHT),
   Rect::new_from_x_y(
     PLATFORM_EDGE_WIDTH,
-    0,
-    PLATFORM_WIDTH - (PLATFORM_EDGE_WIDTH * 2),
+    0.0,
+    PLATFORM_WIDTH - (PLATFORM_EDGE_WIDTH * 2.0),
     PLATFORM_HEIGHT,
   ),
   Rect::new_from_x_y(
     PLATFORM_WIDTH - PLATFORM_EDGE_WIDTH,
-    0,
+    0.0,
     PLATFORM_EDGE_WIDTH,
     PLATFORM_EDGE_HEIGHT,
   ),
 ];
-pub fn stone_and_platform(stone: HtmlImageElement, sprite_sheet: Rc<SpriteSheet>, offset_x: i16) -> Vec<Box<dyn Obstacle>> {
-  const INITIAL_STONE_OFFSET: i16 = 250;
+
+// Stone.png の大きさ。ゲーム中は読み込んだ画像の大きさが当たり判定になり、ソルバーはこちらを使う
+const STONE_WIDTH: f32 = 90.0;
+#[cfg(test)]
+const STONE_HEIGHT: f32 = 54.0;
+// Sign.png の大きさ。画面の上から吊るしてあり、走っている少年の頭より低く、スライディングした少年より高いところまで下がる
+#[cfg(test)]
+const SIGN_WIDTH: f32 = 48.0;
+#[cfg(test)]
+const SIGN_HEIGHT: f32 = 515.0;
+
+// コインもパワーアップも同じ大きさの絵にしている
+pub const PICKUP_SIZE: f32 = 32.0;
+const COINS_ON_PLATFORM: usize = 5;
+const COIN_SPACING: f32 = 48.0;
+const COIN_ABOVE_PLATFORM: f32 = 70.0; // 足場を走る少年の胸の高さ
+
+// 石の中心の真上からの位置(横, 石の上面からの高さ)。手前で跳ぶと弧に沿って取れ、そのまま次の足場に乗れる
+const COIN_ARC: [(f32, f32); 5] = [
+  (-90.0, 60.0),
+  (-45.0, 130.0),
+  (0.0, 170.0),
+  (45.0, 190.0),
+  (90.0, 190.0),
+];
+
+// 往復する足場が 1 往復するまでにスクロールする距離。走っていれば 2 秒
+const MOTION_WAVELENGTH: f32 = 480.0;
+const BOB_AMPLITUDE: f32 = 30.0;
+const PATROL_DISTANCE: f32 = 40.0;
+// 崩れる足場に乗ってから落ち始めるまでのフレーム数
+pub const CRUMBLE_FRAMES: u16 = 40;
+
+// 敵の絵の大きさで、当たり判定もこの大きさにする
+pub const ENEMY_WIDTH: f32 = 64.0;
+pub const ENEMY_HEIGHT: f32 = 48.0;
+const ENEMY_ON_GROUND: f32 = 552.0;
+const ENEMY_PATROL_DISTANCE: f32 = 60.0;
+const HOP_HEIGHT: f32 = 80.0;
+const FLIGHT_ALTITUDE: f32 = 250.0;
+const FLIGHT_AMPLITUDE: f32 = 40.0;
+
+// 足場の動き方。往復する足場は時間ではなく画面上の位置で動きが決まるので、ソルバーと実際の動きが同じになる
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PlatformMotion {
+  Still,
+  Bob { amplitude: f32 },
+  Patrol { distance: f32 },
+  // 乗ってから CRUMBLE_FRAMES 後に落ちる
+  Crumble,
+}
+
+impl PlatformMotion {
+  // 動きが無いときの左端が画面上の x にあるときの、そこからのずれ
+  pub fn offset(self, x: f32) -> Vec2 {
+    let wave = (x / MOTION_WAVELENGTH * TAU).sin();
+    match self {
+      PlatformMotion::Still | PlatformMotion::Crumble => Vec2::ZERO,
+      PlatformMotion::Bob { amplitude } => Vec2::new(0.0, amplitude * wave),
+      PlatformMotion::Patrol { distance } => Vec2::new(distance * wave, 0.0),
+    }
+  }
+  // 動きが無いときの位置から、左右にはみ出す幅
+  pub fn reach(self) -> f32 {
+    match self {
+      PlatformMotion::Patrol { distance } => distance,
+      PlatformMotion::Still | PlatformMotion::Bob { .. } | PlatformMotion::Crumble => 0.0,
+    }
+  }
+}
+
+// 敵の動き方。足場と同じく画面上の位置で決まる
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EnemyBehaviour {
+  Patrol { distance: f32 },
+  Hop { height: f32 },
+  Fly { amplitude: f32 },
+}
+
+impl EnemyBehaviour {
+  pub fn offset(self, x: f32) -> Vec2 {
+    let phase = x / MOTION_WAVELENGTH * TAU;
+    match self {
+      EnemyBehaviour::Patrol { distance } => Vec2::new(distance * phase.sin(), 0.0),
+      // 半周期ごとに 1 回跳ぶ。地面より下には潜らない
+      EnemyBehaviour::Hop { height } => Vec2::new(0.0, -height * (phase / 2.0).sin().abs()),
+      EnemyBehaviour::Fly { amplitude } => Vec2::new(0.0, amplitude * phase.sin()),
+    }
+  }
+  pub fn reach(self) -> f32 {
+    match self {
+      EnemyBehaviour::Patrol { distance } => distance,
+      EnemyBehaviour::Hop { .. } | EnemyBehaviour::Fly { .. } => 0.0,
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Segment {
+  StoneAndPlatform,
+  PlatformAndStone,
+  StoneAndBobbingPlatform,
+  PatrollingPlatformAndStone,
+  StoneAndCrumblingPlatform,
+  WalkerAndPlatform,
+  HopperAndPlatform,
+  FlyerOverPlatform,
+  SignAndPlatform,
+  PitAndPlatform,
+  PlatformOverPit,
+}
+
+impl Segment {
+  pub const ALL: [Segment; 11] = [
+    Segment::StoneAndPlatform,
+    Segment::PlatformAndStone,
+    Segment::StoneAndBobbingPlatform,
+    Segment::PatrollingPlatformAndStone,
+    Segment::StoneAndCrumblingPlatform,
+    Segment::WalkerAndPlatform,
+    Segment::HopperAndPlatform,
+    Segment::FlyerOverPlatform,
+    Segment::SignAndPlatform,
+    Segment::PitAndPlatform,
+    Segment::PlatformOverPit,
+  ];
+
+  pub fn random(rng: &mut impl Rng) -> Self {
+    Segment::ALL[rng.gen_range(0..Segment::ALL.len())]
+  }
+
+  // 画像を読み込まずに配置だけを決める。ネイティブのテストからも使う
+  pub fn layout(self, offset_x: f32) -> Vec<ObstacleLayout> {
+    match self {
+      Segment::StoneAndPlatform => stone_and_platform(offset_x, PlatformMotion::Still),
+      Segment::PlatformAndStone => platform_and_stone(offset_x, PlatformMotion::Still),
+      Segment::StoneAndBobbingPlatform => stone_and_platform(
+        offset_x,
+        PlatformMotion::Bob {
+          amplitude: BOB_AMPLITUDE,
+        },
+      ),
+      Segment::PatrollingPlatformAndStone => stone_and_platform(
+        offset_x,
+        PlatformMotion::Patrol {
+          distance: PATROL_DISTANCE,
+        },
+      ),
+      Segment::StoneAndCrumblingPlatform => stone_and_platform(offset_x, PlatformMotion::Crumble),
+      Segment::WalkerAndPlatform => enemy_and_platform(
+        offset_x,
+        EnemyBehaviour::Patrol {
+          distance: ENEMY_PATROL_DISTANCE,
+        },
+      ),
+      Segment::HopperAndPlatform => enemy_and_platform(offset_x, EnemyBehaviour::Hop { height: HOP_HEIGHT }),
+      Segment::FlyerOverPlatform => flyer_over_platform(offset_x),
+      Segment::SignAndPlatform => sign_and_platform(offset_x),
+      Segment::PitAndPlatform => pit_and_platform(offset_x),
+      Segment::PlatformOverPit => platform_over_pit(offset_x),
+    }
+  }
+
+  // コインの左上の位置。障害物と同じく画像を読み込まずに決める
+  pub fn coins(self, offset_x: f32) -> Vec<Vec2> {
+    self
+      .layout(offset_x)
+      .into_iter()
+      .flat_map(|layout| match (self, layout) {
+        (
+          Segment::StoneAndPlatform | Segment::StoneAndBobbingPlatform | Segment::StoneAndCrumblingPlatform | Segment::FlyerOverPlatform,
+          ObstacleLayout::Stone(position),
+        ) => coin_arc_over(position, STONE_WIDTH),
+        (Segment::WalkerAndPlatform | Segment::HopperAndPlatform, ObstacleLayout::Enemy(position, _)) => coin_arc_over(position, ENEMY_WIDTH),
+        (_, ObstacleLayout::Enemy(..)) => vec![],
+        // 石が足場の下にあるので、石の上には置かない
+        (Segment::PlatformAndStone | Segment::PatrollingPlatformAndStone, ObstacleLayout::Stone(_)) => vec![],
+        // 地面の敵や看板、穴が石の代わりなので、石は出てこない
+        (
+          Segment::WalkerAndPlatform | Segment::HopperAndPlatform | Segment::SignAndPlatform | Segment::PitAndPlatform | Segment::PlatformOverPit,
+          ObstacleLayout::Stone(_),
+        ) => vec![],
+        // 跳び越える穴の上にも石と同じ弧を描く。足場の下の穴は足場を渡るので置かない
+        (Segment::PitAndPlatform, ObstacleLayout::Pit { x, width }) => coin_arc_over(Vec2::new(x, STONE_ON_GROUND), width),
+        (_, ObstacleLayout::Pit { .. }) => vec![],
+        // 看板は跳び越えられないので、上にコインを置いても取れない
+        (_, ObstacleLayout::Overhead(_)) => vec![],
+        (_, ObstacleLayout::FloatingPlatform(position, _)) => coins_above_platform(position),
+      })
+      .collect()
+  }
+
+  // パワーアップは足場の上のコインの列の真ん中と入れ替える。どのセグメントも足場の列が最後に並ぶ
+  pub fn collectibles(self, offset_x: f32, power_up: Option<PowerUp>) -> Vec<(CollectibleKind, Vec2)> {
+    let coins = self.coins(offset_x);
+    let power_up_index = coins.len() - COINS_ON_PLATFORM / 2 - 1;
+    coins
+      .into_iter()
+      .enumerate()
+      .map(|(index, position)| match power_up {
+        Some(power_up) if index == power_up_index => (CollectibleKind::PowerUp(power_up), position),
+        _ => (CollectibleKind::Coin, position),
+      })
+      .collect()
+  }
+}
+
+fn stone_and_platform(offset_x: f32, motion: PlatformMotion) -> Vec<ObstacleLayout> {
+  const INITIAL_STONE_OFFSET: f32 = 250.0;
+  vec![
+    ObstacleLayout::Stone(Vec2 {
+      x: offset_x + INITIAL_STONE_OFFSET,
+      y: STONE_ON_GROUND,
+    }),
+    ObstacleLayout::FloatingPlatform(
+      Vec2 {
+        x: offset_x + FIRST_PLATFORM,
+        y: LOW_PLATFORM,
+      },
+      motion,
+    ),
+  ]
+}
+
+fn platform_and_stone(offset_x: f32, motion: PlatformMotion) -> Vec<ObstacleLayout> {
+  const INITIAL_STONE_OFFSET: f32 = 400.0;
+  const INITIAL_PLATFORM_OFFSET: f32 = 200.0;
   vec![
-    Box::new(Barrier::new(Image::new(
-      stone,
-      Point {
-        x: offset_x + INITIAL_STONE_OFFSET,
-        y: STONE_ON_GROUND,
+    ObstacleLayout::Stone(Vec2 {
+      x: offset_x + INITIAL_STONE_OFFSET,
+      y: STONE_ON_GROUND,
+    }),
+    ObstacleLayout::FloatingPlatform(
+      Vec2 {
+        x: offset_x + INITIAL_PLATFORM_OFFSET,
+        y: HIGH_PLATFORM,
       },
-    ))),
-    Box::new(create_floating_platform(
-      sprite_sheet,
-      Point {
+      motion,
+    ),
+  ]
+}
+
+// 石や地面の敵の上に弧を描いて並べる
+fn coin_arc_over(obstacle: Vec2, width: f32) -> Vec<Vec2> {
+  let center_x = obstacle.x + width / 2.0;
+  COIN_ARC
+    .iter()
+    .map(|(x, height)| {
+      Vec2::new(
+        center_x + x - PICKUP_SIZE / 2.0,
+        obstacle.y - height - PICKUP_SIZE / 2.0,
+      )
+    })
+    .collect()
+}
+
+// 石の代わりに地面を動く敵を置く
+fn enemy_and_platform(offset_x: f32, behaviour: EnemyBehaviour) -> Vec<ObstacleLayout> {
+  const INITIAL_ENEMY_OFFSET: f32 = 250.0;
+  vec![
+    ObstacleLayout::Enemy(
+      Vec2 {
+        x: offset_x + INITIAL_ENEMY_OFFSET,
+        y: ENEMY_ON_GROUND,
+      },
+      behaviour,
+    ),
+    ObstacleLayout::FloatingPlatform(
+      Vec2 {
         x: offset_x + FIRST_PLATFORM,
         y: LOW_PLATFORM,
       },
-    )),
+      PlatformMotion::Still,
+    ),
   ]
 }
 
-fn create_floating_platform(sprite_sheet: Rc<SpriteSheet>, position: Point) -> Platform {
-  Platform::new(
-    sprite_sheet,
-    position,
-    &FLOATING_PLATFORM_SPRITES,
-    &FLOATING_PLATFORM_BOUNDING_BOXES,
-  )
+// 石と足場の上を敵が飛んでいる。足場から跳んで踏むか、下をくぐる
+fn flyer_over_platform(offset_x: f32) -> Vec<ObstacleLayout> {
+  const INITIAL_FLYER_OFFSET: f32 = 500.0;
+  let mut layout = vec![ObstacleLayout::Enemy(
+    Vec2 {
+      x: offset_x + INITIAL_FLYER_OFFSET,
+      y: FLIGHT_ALTITUDE,
+    },
+    EnemyBehaviour::Fly {
+      amplitude: FLIGHT_AMPLITUDE,
+    },
+  )];
+  layout.append(&mut stone_and_platform(offset_x, PlatformMotion::Still));
+  layout
+}
+
+// 看板をスライディングでくぐる。足場は高いので下を走り抜けられ、跳び乗ればコインが取れる
+fn sign_and_platform(offset_x: f32) -> Vec<ObstacleLayout> {
+  const INITIAL_SIGN_OFFSET: f32 = 250.0;
+  const INITIAL_PLATFORM_OFFSET: f32 = 500.0;
+  vec![
+    ObstacleLayout::Overhead(Vec2 {
+      x: offset_x + INITIAL_SIGN_OFFSET,
+      y: 0.0,
+    }),
+    ObstacleLayout::FloatingPlatform(
+      Vec2 {
+        x: offset_x + INITIAL_PLATFORM_OFFSET,
+        y: HIGH_PLATFORM,
+      },
+      PlatformMotion::Still,
+    ),
+  ]
 }
 
-pub fn platform_and_stone(stone: HtmlImageElement, sprite_sheet: Rc<SpriteSheet>, offset_x: i16) -> Vec<Box<dyn Obstacle>> {
-  const INITIAL_STONE_OFFSET: i16 = 400;
-  const INITIAL_PLATFORM_OFFSET: i16 = 200;
+// 跳び越えられる幅の穴。足場は高いので下を走り抜けられる
+fn pit_and_platform(offset_x: f32) -> Vec<ObstacleLayout> {
+  const INITIAL_PIT_OFFSET: f32 = 250.0;
+  const PIT_WIDTH: f32 = 150.0;
+  const INITIAL_PLATFORM_OFFSET: f32 = 500.0;
   vec![
-    Box::new(Barrier::new(Image::new(
-      stone,
-      Point {
-        x: offset_x + INITIAL_STONE_OFFSET,
-        y: STONE_ON_GROUND,
+    ObstacleLayout::Pit {
+      x: offset_x + INITIAL_PIT_OFFSET,
+      width: PIT_WIDTH,
+    },
+    ObstacleLayout::FloatingPlatform(
+      Vec2 {
+        x: offset_x + INITIAL_PLATFORM_OFFSET,
+        y: HIGH_PLATFORM,
       },
-    ))),
-    Box::new(create_floating_platform(
-      sprite_sheet,
-      Point {
+      PlatformMotion::Still,
+    ),
+  ]
+}
+
+// 跳び越えられない幅の穴。上の足場に跳び乗って渡る
+fn platform_over_pit(offset_x: f32) -> Vec<ObstacleLayout> {
+  const INITIAL_PIT_OFFSET: f32 = 300.0;
+  const PIT_WIDTH: f32 = 240.0;
+  const INITIAL_PLATFORM_OFFSET: f32 = 200.0;
+  vec![
+    ObstacleLayout::Pit {
+      x: offset_x + INITIAL_PIT_OFFSET,
+      width: PIT_WIDTH,
+    },
+    ObstacleLayout::FloatingPlatform(
+      Vec2 {
         x: offset_x + INITIAL_PLATFORM_OFFSET,
         y: HIGH_PLATFORM,
       },
-    )),
+      PlatformMotion::Still,
+    ),
   ]
 }
+
+fn coins_above_platform(platform: Vec2) -> Vec<Vec2> {
+  let row_width = COIN_SPACING * (COINS_ON_PLATFORM - 1) as f32 + PICKUP_SIZE;
+  let left = platform.x + (PLATFORM_WIDTH - row_width) / 2.0;
+  (0..COINS_ON_PLATFORM)
+    .map(|index| {
+      Vec2::new(
+        left + COIN_SPACING * index as f32,
+        platform.y - COIN_ABOVE_PLATFORM - PICKUP_SIZE / 2.0,
+      )
+    })
+    .collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ObstacleLayout {
+  Stone(Vec2),
+  // 位置は動きが無いときのもの
+  FloatingPlatform(Vec2, PlatformMotion),
+  Enemy(Vec2, EnemyBehaviour),
+  // 上から吊るされていて、スライディングでしかくぐれない
+  Overhead(Vec2),
+  // 地面に空いた穴。左端と幅
+  Pit { x: f32, width: f32 },
+}
+
+impl ObstacleLayout {
+  #[cfg(test)]
+  pub fn bounding_boxes(&self) -> Vec<Rect> {
+    match self {
+      ObstacleLayout::Stone(position) => vec![Rect::new(*position, STONE_WIDTH, STONE_HEIGHT)],
+      ObstacleLayout::FloatingPlatform(position, _) => FLOATING_PLATFORM_BOUNDING_BOXES
+        .iter()
+        .map(|bounding_box| {
+          Rect::new_from_x_y(
+            bounding_box.x() + position.x,
+            bounding_box.y() + position.y,
+            bounding_box.width,
+            bounding_box.height,
+          )
+        })
+        .collect(),
+      ObstacleLayout::Enemy(position, _) => vec![Rect::new(*position, ENEMY_WIDTH, ENEMY_HEIGHT)],
+      ObstacleLayout::Overhead(position) => vec![Rect::new(*position, SIGN_WIDTH, SIGN_HEIGHT)],
+      // 穴は地面の高さにある厚みの無い箱として扱う
+      ObstacleLayout::Pit { x, width } => vec![Rect::new_from_x_y(*x, GROUND, *width, 0.0)],
+    }
+  }
+
+  fn spawn(
+    self,
+    world: &mut World<WalkComponents>,
+    stone: &HtmlImageElement,
+    sign: &HtmlImageElement,
+    sprite_sheet: &Rc<SpriteSheet>,
+    enemy_sheet: &Rc<SpriteSheet>,
+  ) -> Entity {
+    match self {
+      ObstacleLayout::Stone(position) => spawn_barrier(world, stone, position),
+      ObstacleLayout::Overhead(position) => spawn_barrier(world, sign, position),
+      ObstacleLayout::FloatingPlatform(position, motion) => spawn_platform(
+        world,
+        sprite_sheet.clone(),
+        position,
+        &FLOATING_PLATFORM_SPRITES,
+        &FLOATING_PLATFORM_BOUNDING_BOXES,
+        motion,
+      ),
+      ObstacleLayout::Enemy(position, behaviour) => spawn_enemy(world, enemy_sheet.clone(), position, behaviour),
+      ObstacleLayout::Pit { x, width } => spawn_pit(world, Vec2::new(x, GROUND), width),
+    }
+  }
+}
+
+pub fn create_segment(
+  world: &mut World<WalkComponents>,
+  segment: Segment,
+  stone: HtmlImageElement,
+  sign: HtmlImageElement,
+  sprite_sheet: Rc<SpriteSheet>,
+  enemy_sheet: Rc<SpriteSheet>,
+  offset_x: f32,
+) -> Vec<Entity> {
+  segment
+    .layout(offset_x)
+    .into_iter()
+    .map(|layout| layout.spawn(world, &stone, &sign, &sprite_sheet, &enemy_sheet))
+    .collect()
+}
+
+pub fn create_collectibles(world: &mut World<WalkComponents>, segment: Segment, sheet: Rc<SpriteSheet>, offset_x: f32, power_up: Option<PowerUp>) {
+  for (kind, position) in segment.collectibles(offset_x, power_up) {
+    spawn_collectible(world, kind, sheet.clone(), position);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use proptest::prelude::*;
+
+  // コインは障害物に埋まらず、セグメントの中に収まる
+  #[test]
+  fn coins_stay_clear_of_obstacles() {
+    for segment in Segment::ALL {
+      let obstacle_boxes: Vec<Rect> = segment
+        .layout(0.0)
+        .iter()
+        .flat_map(|layout| layout.bounding_boxes())
+        .collect();
+      let right = obstacle_boxes
+        .iter()
+        .map(Rect::right)
+        .reduce(f32::max)
+        .unwrap();
+      let coins = segment.coins(0.0);
+
+      assert!(!coins.is_empty(), "{:?} has no coins", segment);
+      for coin in coins {
+        let coin_box = Rect::new(coin, PICKUP_SIZE, PICKUP_SIZE);
+        assert!(
+          obstacle_boxes
+            .iter()
+            .all(|obstacle| !coin_box.intersects(obstacle)),
+          "{:?}: coin at {:?} overlaps an obstacle",
+          segment,
+          coin
+        );
+        assert!(coin_box.x() >= 0.0 && coin_box.right() <= right && coin_box.y() >= 0.0);
+      }
+    }
+  }
+
+  // パワーアップは足場の上の列の真ん中のコインと入れ替わり、数は変わらない
+  #[test]
+  fn a_power_up_takes_the_place_of_one_coin() {
+    for segment in Segment::ALL {
+      let coins = segment.collectibles(0.0, None);
+      let with_power_up = segment.collectibles(0.0, Some(PowerUp::Magnet));
+
+      assert_eq!(coins.len(), with_power_up.len());
+      let replaced: Vec<_> = coins
+        .iter()
+        .zip(&with_power_up)
+        .filter(|(coin, other)| coin != other)
+        .collect();
+      assert_eq!(replaced.len(), 1, "{:?}", segment);
+      assert_eq!(replaced[0].1 .0, CollectibleKind::PowerUp(PowerUp::Magnet));
+      assert_eq!(replaced[0].0 .1, replaced[0].1 .1);
+    }
+  }
+
+  // 動く足場は振れ幅の中に収まり、動かない足場はずれない
+  proptest! {
+    #[test]
+    fn platform_motion_stays_within_its_reach(x in -2000.0f32..2000.0) {
+      let bob = PlatformMotion::Bob { amplitude: 30.0 }.offset(x);
+      let patrol = PlatformMotion::Patrol { distance: 40.0 };
+
+      prop_assert!(bob.x == 0.0 && bob.y.abs() <= 30.0);
+      prop_assert!(patrol.offset(x).y == 0.0 && patrol.offset(x).x.abs() <= patrol.reach());
+      prop_assert_eq!(PlatformMotion::Still.offset(x), Vec2::ZERO);
+      prop_assert_eq!(PlatformMotion::Crumble.offset(x), Vec2::ZERO);
+    }
+
+    // 跳ねる敵は地面より下に潜らず、飛ぶ敵は振れ幅の中で上下する
+    #[test]
+    fn enemy_behaviour_stays_within_its_range(x in -2000.0f32..2000.0) {
+      let hop = EnemyBehaviour::Hop { height: 80.0 }.offset(x);
+      let fly = EnemyBehaviour::Fly { amplitude: 40.0 }.offset(x);
+      let patrol = EnemyBehaviour::Patrol { distance: 60.0 };
+
+      prop_assert!(hop.x == 0.0 && hop.y <= 0.0 && hop.y >= -80.0);
+      prop_assert!(fly.x == 0.0 && fly.y.abs() <= 40.0);
+      prop_assert!(patrol.offset(x).y == 0.0 && patrol.offset(x).x.abs() <= patrol.reach());
+    }
+  }
+}
@@ -0,0 +1,312 @@
+use crate::browser;
+use crate::engine::{KeyState, GAMEPAD_A, GAMEPAD_DOWN, GAMEPAD_RIGHT};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+const STORAGE_KEY: &str = "walk_the_dog.settings";
+// 設定をまとめて保存するようになる前に、ミュートだけを保存していたキー
+const LEGACY_MUTED_KEY: &str = "walk_the_dog.muted";
+// 項目を足すだけなら serde(default) で読めるので上げなくてよい。
+// 既存の項目の意味や形を変えたときに上げて、migrate に古い形からの変換を足す
+const VERSION: u32 = 1;
+const VOLUME_STEP: f32 = 0.2;
+// ゲーム全体で使うキー。どの動作にも割り当てられない
+pub const MUTE_KEY: &str = "KeyM";
+pub const FULLSCREEN_KEY: &str = "KeyF";
+pub const BACK_KEY: &str = "Escape";
+const RESERVED_KEYS: [&str; 3] = [MUTE_KEY, FULLSCREEN_KEY, BACK_KEY];
+
+#[derive(Serialize, Deserialize)]
+struct Stored {
+  version: u32,
+  settings: Value,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Difficulty {
+  Easy,
+  #[default]
+  Normal,
+  Hard,
+}
+
+impl Difficulty {
+  pub fn next(self) -> Self {
+    match self {
+      Difficulty::Easy => Difficulty::Normal,
+      Difficulty::Normal => Difficulty::Hard,
+      Difficulty::Hard => Difficulty::Easy,
+    }
+  }
+  pub fn name(self) -> &'static str {
+    match self {
+      Difficulty::Easy => "Easy",
+      Difficulty::Normal => "Normal",
+      Difficulty::Hard => "Hard",
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+  Run,
+  Jump,
+  Slide,
+}
+
+impl Action {
+  // ゲームパッドのボタンは割り当てを変えられない
+  fn gamepad_button(self) -> &'static str {
+    match self {
+      Action::Run => GAMEPAD_RIGHT,
+      Action::Jump => GAMEPAD_A,
+      Action::Slide => GAMEPAD_DOWN,
+    }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeyBindings {
+  pub run: String,
+  pub jump: String,
+  pub slide: String,
+}
+
+impl Default for KeyBindings {
+  fn default() -> Self {
+    KeyBindings {
+      run: "ArrowRight".into(),
+      jump: "Space".into(),
+      slide: "ArrowDown".into(),
+    }
+  }
+}
+
+impl KeyBindings {
+  pub fn key(&self, action: Action) -> &str {
+    match action {
+      Action::Run => &self.run,
+      Action::Jump => &self.jump,
+      Action::Slide => &self.slide,
+    }
+  }
+  // 他の動作に使われているキーなら、その動作に今のキーを渡して入れ替える
+  pub fn bind(&mut self, action: Action, code: &str) -> Result<()> {
+    if RESERVED_KEYS.contains(&code) {
+      return Err(anyhow!("{} is reserved and cannot be bound", code));
+    }
+    let previous = self.key(action).to_string();
+    if let Some(other) = [Action::Run, Action::Jump, Action::Slide]
+      .into_iter()
+      .find(|other| *other != action && self.key(*other) == code)
+    {
+      *self.key_mut(other) = previous;
+    }
+    *self.key_mut(action) = code.into();
+    Ok(())
+  }
+  fn key_mut(&mut self, action: Action) -> &mut String {
+    match action {
+      Action::Run => &mut self.run,
+      Action::Jump => &mut self.jump,
+      Action::Slide => &mut self.slide,
+    }
+  }
+  pub fn is_pressed(&self, keystate: &KeyState, action: Action) -> bool {
+    keystate.is_pressed(self.key(action)) || keystate.is_pressed(action.gamepad_button())
+  }
+  pub fn is_just_pressed(&self, keystate: &KeyState, action: Action) -> bool {
+    keystate.is_just_pressed(self.key(action)) || keystate.is_just_pressed(action.gamepad_button())
+  }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+  pub muted: bool,
+  pub master_volume: f32,
+  pub music_volume: f32,
+  pub sfx_volume: f32,
+  pub key_bindings: KeyBindings,
+  pub show_fps: bool,
+  pub reduced_motion: bool,
+  pub difficulty: Difficulty,
+  // 倒れても残機があるうちは走り直せる
+  pub lives_mode: bool,
+}
+
+impl Default for Settings {
+  fn default() -> Self {
+    Settings {
+      muted: false,
+      master_volume: 1.0,
+      music_volume: 1.0,
+      sfx_volume: 1.0,
+      key_bindings: KeyBindings::default(),
+      show_fps: false,
+      reduced_motion: false,
+      difficulty: Difficulty::default(),
+      lives_mode: false,
+    }
+  }
+}
+
+impl Settings {
+  pub fn load() -> Self {
+    let stored = browser::load_item(STORAGE_KEY).unwrap_or_else(|err| {
+      error!("Could not load settings {:#?}", err);
+      None
+    });
+    match stored {
+      Some(blob) => Settings::from_storage(&blob).unwrap_or_else(|err| {
+        error!("Could not read settings, using the defaults {:#?}", err);
+        Settings::default()
+      }),
+      None => {
+        let settings = Settings::first_run();
+        settings.save();
+        if let Err(err) = browser::remove_item(LEGACY_MUTED_KEY) {
+          error!("Could not remove the old mute setting {:#?}", err);
+        }
+        settings
+      }
+    }
+  }
+  pub fn save(&self) {
+    let result = self
+      .to_storage()
+      .and_then(|blob| browser::save_item(STORAGE_KEY, &blob));
+    if let Err(err) = result {
+      error!("Could not save settings {:#?}", err);
+    }
+  }
+  // 保存された設定がなければ、以前のミュート設定と OS の「視差効果を減らす」設定を引き継ぐ
+  fn first_run() -> Self {
+    let legacy_muted = browser::load_item(LEGACY_MUTED_KEY).unwrap_or_else(|err| {
+      error!("Could not load the old mute setting {:#?}", err);
+      None
+    });
+    let reduced_motion = browser::prefers_reduced_motion().unwrap_or_else(|err| {
+      error!("Could not read the reduced motion preference {:#?}", err);
+      false
+    });
+    Settings::from_legacy(legacy_muted.as_deref(), reduced_motion)
+  }
+  fn from_legacy(muted: Option<&str>, reduced_motion: bool) -> Self {
+    Settings {
+      muted: muted == Some("true"),
+      reduced_motion,
+      ..Settings::default()
+    }
+  }
+  fn to_storage(&self) -> Result<String> {
+    let stored = Stored {
+      version: VERSION,
+      settings: serde_json::to_value(self)?,
+    };
+    Ok(serde_json::to_string(&stored)?)
+  }
+  fn from_storage(blob: &str) -> Result<Self> {
+    let stored: Stored = serde_json::from_str(blob)?;
+    migrate(stored.version, stored.settings)
+  }
+}
+
+// 保存されていた版から今の形に変換する。新しい版で保存されたものは読めないので既定値に戻す
+fn migrate(version: u32, settings: Value) -> Result<Settings> {
+  match version {
+    VERSION => Ok(serde_json::from_value(settings)?),
+    _ => Err(anyhow!("Unsupported settings version {}", version)),
+  }
+}
+
+// 音量は 20% 刻みで上げていき、最大の次は 0 に戻る
+pub fn next_volume(volume: f32) -> f32 {
+  let step = (volume / VOLUME_STEP).round() + 1.0;
+  if step * VOLUME_STEP > 1.0 {
+    0.0
+  } else {
+    step * VOLUME_STEP
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn settings_round_trip_through_storage() {
+    let mut settings = Settings {
+      master_volume: 0.6,
+      music_volume: 0.4,
+      show_fps: true,
+      difficulty: Difficulty::Hard,
+      ..Settings::default()
+    };
+    settings.key_bindings.bind(Action::Jump, "KeyW").unwrap();
+
+    let restored = Settings::from_storage(&settings.to_storage().unwrap()).unwrap();
+
+    assert_eq!(restored, settings);
+    assert_eq!(restored.key_bindings.key(Action::Jump), "KeyW");
+  }
+
+  #[test]
+  fn binding_a_key_in_use_swaps_it_with_the_other_action() {
+    let mut key_bindings = KeyBindings::default();
+
+    key_bindings.bind(Action::Jump, "ArrowDown").unwrap();
+
+    assert_eq!(key_bindings.key(Action::Jump), "ArrowDown");
+    assert_eq!(key_bindings.key(Action::Slide), "Space");
+    assert_eq!(key_bindings.key(Action::Run), "ArrowRight");
+  }
+
+  #[test]
+  fn reserved_keys_cannot_be_bound() {
+    let mut key_bindings = KeyBindings::default();
+
+    for code in [MUTE_KEY, FULLSCREEN_KEY, BACK_KEY] {
+      assert!(key_bindings.bind(Action::Jump, code).is_err());
+    }
+    assert_eq!(key_bindings, KeyBindings::default());
+  }
+
+  #[test]
+  fn missing_fields_fall_back_to_defaults() {
+    let restored = Settings::from_storage(r#"{"version":1,"settings":{"muted":true,"key_bindings":{"slide":"KeyS"}}}"#).unwrap();
+
+    assert!(restored.muted);
+    assert_eq!(restored.master_volume, 1.0);
+    assert_eq!(restored.sfx_volume, 1.0);
+    assert_eq!(restored.key_bindings.key(Action::Slide), "KeyS");
+    assert_eq!(restored.key_bindings.key(Action::Run), "ArrowRight");
+  }
+
+  #[test]
+  fn settings_from_a_newer_version_are_rejected() {
+    assert!(Settings::from_storage(r#"{"version":99,"settings":{}}"#).is_err());
+    assert!(Settings::from_storage("not json").is_err());
+  }
+
+  #[test]
+  fn the_old_mute_flag_is_carried_over() {
+    assert!(Settings::from_legacy(Some("true"), false).muted);
+    assert!(!Settings::from_legacy(Some("false"), false).muted);
+    assert!(Settings::from_legacy(None, true).reduced_motion);
+  }
+
+  #[test]
+  fn volume_steps_wrap_back_to_silence() {
+    let volumes: Vec<f32> = std::iter::successors(Some(0.0), |volume| Some(next_volume(*volume)))
+      .take(7)
+      .collect();
+
+    assert_eq!(volumes.len(), 7);
+    assert_eq!(volumes[5], 1.0);
+    assert_eq!(volumes[6], 0.0);
+    assert!((volumes[2] - 0.4).abs() < f32::EPSILON);
+  }
+}
@@ -0,0 +1,611 @@
+use std::{
+  collections::HashSet,
+  mem::{discriminant, Discriminant},
+  rc::Rc,
+};
+
+use anyhow::Result;
+
+use crate::{
+  engine::{AudioBackend, Rect, Sheet, Sound},
+  game::{barrier_collision, enemy_collision, pit_collision, platform_collision, red_hat_boy_states::RedHatBoySounds, Event, RedHatBoyStateMachine},
+  segments::{EnemyBehaviour, ObstacleLayout, PlatformMotion, Segment, CRUMBLE_FRAMES},
+};
+
+// ゴールまでに掛かるフレーム数にこれ以上の余裕は要らない。無限ループ避け
+const EXTRA_FRAMES: usize = 1000;
+const DISTANCE_BUCKET: f32 = 32.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Input {
+  Jump,
+  Slide,
+}
+
+impl From<Input> for Event {
+  fn from(input: Input) -> Self {
+    match input {
+      Input::Jump => Event::Jump,
+      Input::Slide => Event::Slide,
+    }
+  }
+}
+
+// 倒れるきっかけになったセグメントと、そのフレーム
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Unsolvable {
+  pub segment: usize,
+  pub frame: usize,
+}
+
+enum Collider {
+  Barrier(Rect),
+  // 位置は動きが無いときのもの
+  Platform {
+    top: f32,
+    bounding_boxes: Vec<Rect>,
+    motion: PlatformMotion,
+    anchor_x: f32,
+  },
+  Enemy {
+    bounding_box: Rect,
+    behaviour: EnemyBehaviour,
+  },
+  Pit {
+    left: f32,
+    right: f32,
+  },
+}
+
+impl Collider {
+  fn new(layout: ObstacleLayout) -> Self {
+    match layout {
+      ObstacleLayout::Stone(_) | ObstacleLayout::Overhead(_) => Collider::Barrier(layout.bounding_boxes()[0]),
+      ObstacleLayout::FloatingPlatform(position, motion) => Collider::Platform {
+        top: position.y,
+        bounding_boxes: layout.bounding_boxes(),
+        motion,
+        anchor_x: position.x,
+      },
+      ObstacleLayout::Enemy(_, behaviour) => Collider::Enemy {
+        bounding_box: layout.bounding_boxes()[0],
+        behaviour,
+      },
+      ObstacleLayout::Pit { x, width } => Collider::Pit {
+        left: x,
+        right: x + width,
+      },
+    }
+  }
+
+  // 足場の動きは画面上の位置で決まる。少年が distance 進んだとき、ワールド座標の anchor_x は画面上では anchor_x - distance にある
+  fn collision(&self, boy: &RedHatBoyStateMachine, boy_box: &Rect, distance: f32) -> Option<Event> {
+    match self {
+      Collider::Barrier(bounding_box) => barrier_collision(boy_box, bounding_box),
+      Collider::Platform {
+        top,
+        bounding_boxes,
+        motion,
+        anchor_x,
+      } => {
+        let offset = motion.offset(anchor_x - distance);
+        let moved: Vec<Rect> = bounding_boxes
+          .iter()
+          .map(|bounding_box| {
+            Rect::new(
+              bounding_box.position + offset,
+              bounding_box.width,
+              bounding_box.height,
+            )
+          })
+          .collect();
+        platform_collision(boy, boy_box, top + offset.y, &moved)
+      }
+      Collider::Enemy {
+        bounding_box,
+        behaviour,
+      } => {
+        let offset = behaviour.offset(bounding_box.x() - distance);
+        let moved = Rect::new(
+          bounding_box.position + offset,
+          bounding_box.width,
+          bounding_box.height,
+        );
+        enemy_collision(boy, boy_box, &moved)
+      }
+      Collider::Pit { left, right } => pit_collision(boy, boy_box, *left, *right),
+    }
+  }
+
+  // Walk と同じく、前のフレームからこのフレームまでに足場が横に動いた量だけ少年を運ぶ
+  fn carry(&self, previous: f32, distance: f32) -> f32 {
+    match self {
+      Collider::Barrier(_) | Collider::Enemy { .. } | Collider::Pit { .. } => 0.0,
+      Collider::Platform {
+        motion, anchor_x, ..
+      } => motion.offset(anchor_x - distance).x - motion.offset(anchor_x - previous).x,
+    }
+  }
+
+  fn crumbles(&self) -> bool {
+    matches!(
+      self,
+      Collider::Platform {
+        motion: PlatformMotion::Crumble,
+        ..
+      }
+    )
+  }
+
+  fn right(&self) -> f32 {
+    match self {
+      Collider::Barrier(bounding_box) => bounding_box.right(),
+      Collider::Platform {
+        bounding_boxes,
+        motion,
+        ..
+      } => {
+        bounding_boxes
+          .iter()
+          .map(|bounding_box| bounding_box.right())
+          .reduce(f32::max)
+          .unwrap_or(0.0)
+          + motion.reach()
+      }
+      Collider::Enemy {
+        bounding_box,
+        behaviour,
+      } => bounding_box.right() + behaviour.reach(),
+      Collider::Pit { right, .. } => *right,
+    }
+  }
+}
+
+// セグメントを Walk と同じように並べたもの。座標はスクロールする前のワールド座標
+pub struct Course {
+  colliders: Vec<(usize, Collider)>,
+}
+
+impl Course {
+  // 最初のセグメントを offset_x に置き、以降は一つ前の右端から gap 空けて並べる
+  pub fn new(segments: &[Segment], offset_x: f32, gap: f32) -> Self {
+    let mut colliders = vec![];
+    let mut next_x = offset_x;
+    for (index, segment) in segments.iter().enumerate() {
+      let layout: Vec<Collider> = segment
+        .layout(next_x)
+        .into_iter()
+        .map(Collider::new)
+        .collect();
+      next_x = layout
+        .iter()
+        .map(Collider::right)
+        .reduce(f32::max)
+        .unwrap_or(next_x)
+        + gap;
+      colliders.extend(layout.into_iter().map(|collider| (index, collider)));
+    }
+    Course { colliders }
+  }
+
+  #[cfg(test)]
+  fn from_layouts(layouts: &[ObstacleLayout]) -> Self {
+    Course {
+      colliders: layouts
+        .iter()
+        .map(|layout| (0, Collider::new(*layout)))
+        .collect(),
+    }
+  }
+
+  fn right(&self) -> f32 {
+    self
+      .colliders
+      .iter()
+      .map(|(_, collider)| collider.right())
+      .reduce(f32::max)
+      .unwrap_or(0.0)
+  }
+}
+
+// シミュレーション中は音を鳴らさない
+struct Silent;
+
+impl AudioBackend for Silent {
+  fn play_sound(&self, _sound: &Sound, _pan: f32) -> Result<()> {
+    Ok(())
+  }
+}
+
+#[derive(Clone)]
+struct Runner {
+  boy: RedHatBoyStateMachine,
+  distance: f32,
+  carry: f32,
+  // 動く足場に一度でも運ばれたか
+  carried: bool,
+  // 乗った崩れる足場の番号と、乗ったフレーム
+  crumbling: Vec<(usize, usize)>,
+  // 踏んだ敵の番号。もう当たらない
+  stomped: Vec<usize>,
+}
+
+// 足場に運ばれていなければ距離は走った分だけで決まるので、そのまま比べる。
+// 一度でも動く足場に運ばれると距離がばらばらになって探す数が膨らむので、DISTANCE_BUCKET 単位でまとめる
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum DistanceKey {
+  Exact(u32),
+  Carried(i32),
+}
+
+// 同じフレームでこれが同じ Runner は、この先も同じように動く
+type RunnerKey = (
+  Discriminant<RedHatBoyStateMachine>,
+  u8,
+  u32,
+  u32,
+  DistanceKey,
+  Vec<(usize, usize)>,
+  Vec<usize>,
+);
+
+impl Runner {
+  // f32 はそのままでは HashSet に入れられないので、ビット列で比べる。
+  // 既に落ちた足場は、いつ乗ったかで先の動きが変わらないので区別しない
+  fn key(&self, next_frame: usize) -> RunnerKey {
+    let context = self.boy.context();
+    let crumbling = self
+      .crumbling
+      .iter()
+      .map(|(index, landed)| {
+        if self.has_crumbled(*index, next_frame) {
+          (*index, usize::MAX)
+        } else {
+          (*index, *landed)
+        }
+      })
+      .collect();
+    (
+      discriminant(&self.boy),
+      context.frame,
+      context.position.y.to_bits(),
+      context.velocity.y.to_bits(),
+      self.distance_key(),
+      crumbling,
+      self.stomped.clone(),
+    )
+  }
+  fn distance_key(&self) -> DistanceKey {
+    if self.carried {
+      DistanceKey::Carried((self.distance / DISTANCE_BUCKET).round() as i32)
+    } else {
+      DistanceKey::Exact(self.distance.to_bits())
+    }
+  }
+  fn has_crumbled(&self, collider: usize, frame: usize) -> bool {
+    self
+      .crumbling
+      .iter()
+      .any(|(index, landed)| *index == collider && frame >= landed + usize::from(CRUMBLE_FRAMES))
+  }
+}
+
+enum Step {
+  Alive(Box<Runner>),
+  Cleared,
+  Down(Unsolvable),
+}
+
+// RedHatBoy の物理と当たり判定だけを、描画も音も無しで Walking と同じ順に進める
+pub struct Solver {
+  sheet: Sheet,
+  sounds: RedHatBoySounds,
+}
+
+impl Solver {
+  pub fn new(sheet: Sheet, sounds: RedHatBoySounds) -> Self {
+    Solver { sheet, sounds }
+  }
+
+  fn start(&self) -> Runner {
+    Runner {
+      boy: RedHatBoyStateMachine::new(Rc::new(Silent), self.sounds.clone()).transition(Event::Run),
+      distance: 0.0,
+      carry: 0.0,
+      carried: false,
+      crumbling: vec![],
+      stomped: vec![],
+    }
+  }
+
+  // Walking::update と同じく、入力、少年の更新、障害物との当たり判定の順に 1 フレーム進める
+  fn step(&self, course: &Course, runner: &Runner, input: Option<Input>, frame: usize) -> Step {
+    let mut boy = runner.boy.clone();
+    if let Some(input) = input {
+      boy = boy.transition(input.into());
+    }
+    boy = boy.update();
+    let distance = runner.distance + boy.context().velocity.x + runner.carry;
+    let mut carry = 0.0;
+    let mut crumbling = runner.crumbling.clone();
+    let mut stomped = runner.stomped.clone();
+    let boy_box = |boy: &RedHatBoyStateMachine| {
+      let mut boy_box = boy.bounding_box(&self.sheet);
+      boy_box.set_x(boy_box.x() + distance);
+      boy_box
+    };
+    // 当たり判定の箱はスプライトを引いて求めるので、少年の状態が変わったときだけ計算し直す
+    let mut current_box = boy_box(&boy);
+    for (index, (segment, collider)) in course.colliders.iter().enumerate() {
+      if runner.has_crumbled(index, frame) || stomped.contains(&index) {
+        continue;
+      }
+      if let Some(event) = collider.collision(&boy, &current_box, distance) {
+        match event {
+          Event::Land(_) => {
+            carry += collider.carry(runner.distance, distance);
+            if collider.crumbles() && !crumbling.iter().any(|(crumbled, _)| *crumbled == index) {
+              crumbling.push((index, frame));
+            }
+          }
+          Event::Stomp => stomped.push(index),
+          _ => {}
+        }
+        boy = boy.transition(event);
+        current_box = boy_box(&boy);
+      }
+      if boy.is_down() {
+        return Step::Down(Unsolvable {
+          segment: *segment,
+          frame,
+        });
+      }
+    }
+    if distance > course.right() {
+      Step::Cleared
+    } else {
+      Step::Alive(Box::new(Runner {
+        boy,
+        distance,
+        carry,
+        carried: runner.carried || carry != 0.0,
+        crumbling,
+        stomped,
+      }))
+    }
+  }
+
+  fn frame_limit(&self, course: &Course) -> usize {
+    course.right().max(0.0) as usize + EXTRA_FRAMES
+  }
+
+  // 決まった入力でコースを走らせる。inputs は (フレーム, 入力) の組
+  #[cfg(test)]
+  pub fn replay(&self, course: &Course, inputs: &[(usize, Input)]) -> Result<(), Unsolvable> {
+    let mut runner = self.start();
+    for frame in 0..self.frame_limit(course) {
+      let input = inputs
+        .iter()
+        .find(|(input_frame, _)| *input_frame == frame)
+        .map(|(_, input)| *input);
+      match self.step(course, &runner, input, frame) {
+        Step::Alive(next) => runner = *next,
+        Step::Cleared => return Ok(()),
+        Step::Down(unsolvable) => return Err(unsolvable),
+      }
+    }
+    unreachable!("course was not finished within the frame limit");
+  }
+
+  // 倒れずにコースを抜けられる入力を幅優先で探す。見つからなければ一番先まで進めたときに倒れたセグメントを返す
+  pub fn solve(&self, course: &Course) -> Result<Vec<(usize, Input)>, Unsolvable> {
+    // 入力した時点ごとの (親の番号, フレーム, 入力)。見つかった経路を辿り直すのに使う
+    let mut history: Vec<Option<(usize, usize, Input)>> = vec![None];
+    let mut frontier = vec![(0, self.start())];
+    let mut furthest = Unsolvable {
+      segment: 0,
+      frame: 0,
+    };
+    for frame in 0..self.frame_limit(course) {
+      let mut seen: HashSet<RunnerKey> = HashSet::new();
+      let mut next_frontier = vec![];
+      for (node, runner) in &frontier {
+        // 走っているときだけ操作が効く。それ以外の状態で入力しても何もしないのと同じ
+        let inputs: &[Option<Input>] = match runner.boy {
+          RedHatBoyStateMachine::Running(_) => &[None, Some(Input::Jump), Some(Input::Slide)],
+          _ => &[None],
+        };
+        for input in inputs {
+          match self.step(course, runner, *input, frame) {
+            Step::Alive(next) => {
+              if seen.insert(next.key(frame + 1)) {
+                let id = if let Some(input) = input {
+                  history.push(Some((*node, frame, *input)));
+                  history.len() - 1
+                } else {
+                  *node
+                };
+                next_frontier.push((id, *next));
+              }
+            }
+            Step::Cleared => {
+              let mut inputs = vec![];
+              if let Some(input) = input {
+                inputs.push((frame, *input));
+              }
+              let mut id = *node;
+              while let Some((parent, input_frame, input)) = history[id] {
+                inputs.push((input_frame, input));
+                id = parent;
+              }
+              inputs.reverse();
+              return Ok(inputs);
+            }
+            Step::Down(unsolvable) => furthest = unsolvable,
+          }
+        }
+      }
+      if next_frontier.is_empty() {
+        return Err(furthest);
+      }
+      frontier = next_frontier;
+    }
+    Err(furthest)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::engine::{RecordingAudio, Vec2};
+
+  fn solver() -> Solver {
+    let audio = RecordingAudio::default();
+    let sounds = RedHatBoySounds {
+      jump: audio.sound("jump"),
+      land: audio.sound("land"),
+      slide: audio.sound("slide"),
+      knock_out: audio.sound("knock_out"),
+    };
+    let sheet: Sheet = serde_json::from_str(include_str!("../static/rhb.json")).expect("rhb.json is a valid sprite sheet");
+    Solver::new(sheet, sounds)
+  }
+
+  // StoneAndPlatform の石は offset_x + 250 にある。少年の立ち位置に重ねるとどうやっても避けられない
+  const ON_THE_BOY: f32 = -250.0;
+
+  #[test]
+  fn solution_replays_to_the_end_of_the_course() {
+    let solver = solver();
+    let course = Course::new(
+      &[Segment::StoneAndPlatform, Segment::PlatformAndStone],
+      0.0,
+      20.0,
+    );
+
+    let inputs = solver.solve(&course).expect("course is solvable");
+
+    assert!(inputs.iter().any(|(_, input)| *input == Input::Jump));
+    assert_eq!(solver.replay(&course, &inputs), Ok(()));
+  }
+
+  // 崩れ落ちた後は、いつ乗ったかに関わらず同じ状態として扱う
+  #[test]
+  fn crumbled_platforms_share_a_key_however_long_ago_they_fell() {
+    let solver = solver();
+    let landed_at = |frame| Runner {
+      crumbling: vec![(1, frame)],
+      ..solver.start()
+    };
+    let later = 100 + usize::from(CRUMBLE_FRAMES);
+
+    assert!(!landed_at(100).has_crumbled(1, later - 1));
+    assert!(landed_at(100).has_crumbled(1, later));
+    assert!(!landed_at(100).has_crumbled(0, later));
+    assert_ne!(landed_at(100).key(later - 1), landed_at(99).key(later - 1));
+    assert_eq!(landed_at(100).key(later), landed_at(99).key(later));
+  }
+
+  // 歩く敵は、ちょうどよく跳べば踏んでそのまま走り抜けられる
+  #[test]
+  fn a_walker_can_be_stomped() {
+    let solver = solver();
+    let course = Course::new(&[Segment::WalkerAndPlatform], 0.0, 0.0);
+    let stomps = |jump_at: usize| {
+      let mut runner = solver.start();
+      for frame in 0..solver.frame_limit(&course) {
+        let input = (frame == jump_at).then_some(Input::Jump);
+        match solver.step(&course, &runner, input, frame) {
+          Step::Alive(next) => runner = *next,
+          Step::Cleared | Step::Down(_) => return false,
+        }
+        if !runner.stomped.is_empty() {
+          return runner.boy.context().velocity.y < 0.0;
+        }
+      }
+      false
+    };
+
+    assert!((0..100).any(stomps));
+  }
+
+  // 動く足場に乗らずに走った少年は、乗った少年と距離が近くても同じ状態にまとめない。
+  // まとめると、乗らなければ届く跳び越えぎりぎりの穴を越えられない扱いになる
+  #[test]
+  fn runners_left_behind_by_a_moving_platform_keep_their_own_distance() {
+    let solver = solver();
+    let course = Course::from_layouts(&[
+      ObstacleLayout::FloatingPlatform(
+        Vec2 { x: 200.0, y: 375.0 },
+        PlatformMotion::Patrol { distance: 40.0 },
+      ),
+      ObstacleLayout::Pit {
+        x: 758.0,
+        width: 200.0,
+      },
+    ]);
+
+    let inputs = solver.solve(&course).expect("course is solvable");
+
+    assert_eq!(solver.replay(&course, &inputs), Ok(()));
+  }
+
+  #[test]
+  fn hanging_signs_are_passed_by_sliding() {
+    let solver = solver();
+    let course = Course::new(&[Segment::SignAndPlatform], 0.0, 0.0);
+
+    let inputs = solver.solve(&course).expect("course is solvable");
+
+    assert!(inputs.iter().any(|(_, input)| *input == Input::Slide));
+    assert_eq!(solver.replay(&course, &inputs), Ok(()));
+  }
+
+  #[test]
+  fn pits_are_cleared_by_jumping_and_fallen_into_otherwise() {
+    let solver = solver();
+    for segment in [Segment::PitAndPlatform, Segment::PlatformOverPit] {
+      let course = Course::new(&[segment], 0.0, 0.0);
+
+      let inputs = solver.solve(&course).expect("course is solvable");
+
+      assert!(inputs.iter().any(|(_, input)| *input == Input::Jump));
+      assert_eq!(solver.replay(&course, &inputs), Ok(()));
+      assert!(
+        solver.replay(&course, &[]).is_err(),
+        "{:?} without input",
+        segment
+      );
+    }
+  }
+
+  #[test]
+  fn running_without_input_hits_the_first_stone() {
+    let solver = solver();
+    let course = Course::new(&[Segment::StoneAndPlatform], 0.0, 0.0);
+
+    let unsolvable = solver.replay(&course, &[]).unwrap_err();
+
+    assert_eq!(unsolvable.segment, 0);
+    assert!(unsolvable.frame > 0);
+  }
+
+  #[test]
+  fn unsolvable_course_reports_the_segment_that_knocks_the_boy_out() {
+    let solver = solver();
+    let first_right = Course::new(&[Segment::StoneAndPlatform], 0.0, 0.0).right();
+    let course = Course::new(
+      &[Segment::StoneAndPlatform, Segment::StoneAndPlatform],
+      0.0,
+      ON_THE_BOY - first_right,
+    );
+
+    assert_eq!(
+      solver.solve(&course),
+      Err(Unsolvable {
+        segment: 1,
+        frame: 0
+      })
+    );
+  }
+}
@@ -2,41 +2,103 @@ use anyhow::{anyhow, Result};
 use js_sys::ArrayBuffer;
 use wasm_bindgen::JsCast;
 use wasm_bindgen_futures::JsFuture;
-use web_sys::{AudioBuffer, AudioBufferSourceNode, AudioContext, AudioDestinationNode, AudioNode};
+use web_sys::{AudioBuffer, AudioBufferSourceNode, AudioContext, AudioContextState, AudioNode, GainNode, StereoPannerNode};
 
 pub fn create_audo_context() -> Result<AudioContext> {
   web_sys::AudioContext::new().map_err(|err| anyhow!("Failed to create audio context: {:#?}", err))
 }
 
+pub fn is_suspended(context: &AudioContext) -> bool {
+  context.state() == AudioContextState::Suspended
+}
+
+// ブラウザはユーザー操作のイベントの中で呼んだ resume しか受け付けないので、呼び出しまでは同期で行い、完了は返す future で待つ
+pub fn resume(context: &AudioContext) -> Result<JsFuture> {
+  Ok(JsFuture::from(context.resume().map_err(|err| {
+    anyhow!("Failed to resume audio context {:#?}", err)
+  })?))
+}
+
 fn craete_buffer_source(context: &AudioContext) -> Result<AudioBufferSourceNode> {
   context
     .create_buffer_source()
     .map_err(|err| anyhow!("Failed to create buffer source: {:#?}", err))
 }
 
-fn connect_with_audio_node(source: &AudioBufferSourceNode, destination: &AudioDestinationNode) -> Result<AudioNode> {
+pub fn create_gain(context: &AudioContext) -> Result<GainNode> {
+  context
+    .create_gain()
+    .map_err(|err| anyhow!("Failed to create gain node: {:#?}", err))
+}
+
+pub fn create_stereo_panner(context: &AudioContext) -> Result<StereoPannerNode> {
+  context
+    .create_stereo_panner()
+    .map_err(|err| anyhow!("Failed to create stereo panner: {:#?}", err))
+}
+
+pub fn connect_with_audio_node(source: &AudioNode, destination: &AudioNode) -> Result<AudioNode> {
   source
-    .connect_with_audio_node(&destination)
+    .connect_with_audio_node(destination)
     .map_err(|err| anyhow!("Failed to connect source with destination: {:#?}", err))
 }
-fn create_track_source(context: &AudioContext, buffer: &AudioBuffer) -> Result<AudioBufferSourceNode> {
-  let source = craete_buffer_source(&context)?;
-  source.set_buffer(Some(&buffer));
-  connect_with_audio_node(&source, &context.destination())?;
+fn create_track_source(context: &AudioContext, buffer: &AudioBuffer, destination: &AudioNode) -> Result<AudioBufferSourceNode> {
+  let source = craete_buffer_source(context)?;
+  source.set_buffer(Some(buffer));
+  connect_with_audio_node(&source, destination)?;
   Ok(source)
 }
-pub enum LOOPING {
-  NO,
-  YES,
+// offset は再生を開始する曲中の位置(秒)。一時停止からの再開に使う
+pub fn play_looping_sound(context: &AudioContext, buffer: &AudioBuffer, destination: &AudioNode, offset: f64) -> Result<AudioBufferSourceNode> {
+  let source = create_track_source(context, buffer, destination)?;
+  source.set_loop(true);
+  source
+    .start_with_when_and_grain_offset(0.0, offset)
+    .map_err(|err| anyhow!("Failed to start source: {:#?}", err))?;
+  Ok(source)
 }
-pub fn play_sound(context: &AudioContext, buffer: &AudioBuffer, looping: LOOPING) -> Result<()> {
-  let source = create_track_source(context, buffer)?;
-  if matches!(looping, LOOPING::YES) {
-    source.set_loop(true);
-  }
+
+// detune はセント単位。100 で半音上がる
+pub fn play_sound_detuned(context: &AudioContext, buffer: &AudioBuffer, destination: &AudioNode, detune: f32) -> Result<AudioBufferSourceNode> {
+  let source = create_track_source(context, buffer, destination)?;
+  source.detune().set_value(detune);
   source
     .start()
-    .map_err(|err| anyhow!("Failed to start source: {:#?}", err))
+    .map_err(|err| anyhow!("Failed to start source: {:#?}", err))?;
+  Ok(source)
+}
+
+pub fn stop_sound(source: &AudioBufferSourceNode, when: f64) -> Result<()> {
+  source
+    .stop_with_when(when)
+    .map_err(|err| anyhow!("Failed to stop source: {:#?}", err))
+}
+
+pub fn set_gain(context: &AudioContext, gain: &GainNode, value: f32) -> Result<()> {
+  let param = gain.gain();
+  param
+    .cancel_scheduled_values(context.current_time())
+    .map_err(|err| anyhow!("Failed to cancel gain ramp: {:#?}", err))?;
+  param
+    .set_value_at_time(value, context.current_time())
+    .map_err(|err| anyhow!("Failed to set gain: {:#?}", err))?;
+  Ok(())
+}
+
+// 現在の音量から value まで seconds 秒かけて直線的に変化させる
+pub fn ramp_gain(context: &AudioContext, gain: &GainNode, value: f32, seconds: f64) -> Result<()> {
+  let param = gain.gain();
+  let now = context.current_time();
+  param
+    .cancel_scheduled_values(now)
+    .map_err(|err| anyhow!("Failed to cancel gain ramp: {:#?}", err))?;
+  param
+    .set_value_at_time(param.value(), now)
+    .map_err(|err| anyhow!("Failed to anchor gain ramp: {:#?}", err))?;
+  param
+    .linear_ramp_to_value_at_time(value, now + seconds)
+    .map_err(|err| anyhow!("Failed to ramp gain: {:#?}", err))?;
+  Ok(())
 }
 
 pub async fn decode_audio_data(ctx: &AudioContext, array_buffer: &ArrayBuffer) -> Result<AudioBuffer> {